@@ -0,0 +1,6 @@
+CREATE TABLE scan_warnings (
+    id VARCHAR(36) PRIMARY KEY,
+    audiobook_id VARCHAR(36) REFERENCES audiobooks (id) NOT NULL,
+    message TEXT NOT NULL,
+    created_at TIMESTAMP DEFAULT current_timestamp NOT NULL
+);
@@ -0,0 +1,9 @@
+CREATE TABLE libraries_old (
+    id VARCHAR(36) PRIMARY KEY,
+    location TEXT NOT NULL,
+    is_audiobook_regex TEXT NOT NULL,
+    last_scan TIMESTAMP
+);
+INSERT INTO libraries_old SELECT id, location, is_audiobook_regex, last_scan FROM libraries;
+DROP TABLE libraries;
+ALTER TABLE libraries_old RENAME TO libraries;
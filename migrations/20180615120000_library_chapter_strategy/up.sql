@@ -0,0 +1,2 @@
+ALTER TABLE libraries ADD COLUMN chapter_strategy TEXT NOT NULL DEFAULT 'merge_titles';
+ALTER TABLE libraries ADD COLUMN chapter_regex TEXT;
@@ -0,0 +1,10 @@
+CREATE TABLE chapters_old (
+    id VARCHAR(36) PRIMARY KEY,
+    title VARCHAR(1024),
+    audiobook_id UUID REFERENCES audiobooks (id) NOT NULL,
+    start_time DOUBLE PRECISION NOT NULL,
+    number BIGINT NOT NULL
+);
+INSERT INTO chapters_old SELECT id, title, audiobook_id, start_time, number FROM chapters;
+DROP TABLE chapters;
+ALTER TABLE chapters_old RENAME TO chapters;
@@ -0,0 +1 @@
+ALTER TABLE chapters ADD COLUMN generated BOOLEAN NOT NULL DEFAULT 0;
@@ -0,0 +1,15 @@
+CREATE TABLE audiobooks_old (
+    id VARCHAR(36) PRIMARY KEY,
+    location TEXT NOT NULL,
+    title VARCHAR(1024) NOT NULL,
+    artist VARCHAR(1024),
+    length DOUBLE PRECISION NOT NULL,
+    library_id UUID REFERENCES libraries (id) NOT NULL,
+    hash BYTEA NOT NULL,
+    file_extension VARCHAR(255) NOT NULL,
+    deleted BOOLEAN NOT NULL DEFAULT FALSE
+);
+INSERT INTO audiobooks_old
+    SELECT id, location, title, artist, length, library_id, hash, file_extension, deleted FROM audiobooks;
+DROP TABLE audiobooks;
+ALTER TABLE audiobooks_old RENAME TO audiobooks;
@@ -0,0 +1,10 @@
+ALTER TABLE audiobooks ADD COLUMN narrator VARCHAR(1024);
+ALTER TABLE audiobooks ADD COLUMN series VARCHAR(1024);
+ALTER TABLE audiobooks ADD COLUMN series_index DOUBLE PRECISION;
+ALTER TABLE audiobooks ADD COLUMN description TEXT;
+ALTER TABLE audiobooks ADD COLUMN publisher VARCHAR(1024);
+ALTER TABLE audiobooks ADD COLUMN year INTEGER;
+ALTER TABLE audiobooks ADD COLUMN genres TEXT NOT NULL DEFAULT '';
+ALTER TABLE audiobooks ADD COLUMN language VARCHAR(255);
+ALTER TABLE audiobooks ADD COLUMN isbn VARCHAR(255);
+ALTER TABLE audiobooks ADD COLUMN asin VARCHAR(255);
@@ -0,0 +1,10 @@
+CREATE TABLE users_old (
+    id VARCHAR(36) PRIMARY KEY,
+    created_at TIMESTAMP DEFAULT current_timestamp NOT NULL,
+    updated_at TIMESTAMP DEFAULT current_timestamp NOT NULL,
+    email VARCHAR(120) UNIQUE NOT NULL,
+    password_hash VARCHAR(240) NOT NULL
+);
+INSERT INTO users_old SELECT id, created_at, updated_at, email, password_hash FROM users;
+DROP TABLE users;
+ALTER TABLE users_old RENAME TO users;
@@ -0,0 +1 @@
+DROP TABLE audiobook_overrides;
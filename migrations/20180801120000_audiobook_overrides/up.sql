@@ -0,0 +1,12 @@
+-- Values set by an admin, these win over whatever the scanner finds
+CREATE TABLE audiobook_overrides (
+    audiobook_id VARCHAR(36) PRIMARY KEY REFERENCES audiobooks (id) NOT NULL,
+    title VARCHAR(1024),
+    artist VARCHAR(1024),
+    narrator VARCHAR(1024),
+    series VARCHAR(1024),
+    series_index DOUBLE PRECISION,
+    description TEXT,
+    -- JSON list of chapters replacing the scanned ones
+    chapters TEXT
+);
@@ -0,0 +1,40 @@
+CREATE TABLE libraries_old (
+    id VARCHAR(36) PRIMARY KEY,
+    location TEXT NOT NULL,
+    is_audiobook_regex TEXT NOT NULL,
+    last_scan TIMESTAMP,
+    chapter_strategy TEXT NOT NULL DEFAULT 'merge_titles',
+    chapter_regex TEXT
+);
+INSERT INTO libraries_old
+    SELECT id, location, is_audiobook_regex, last_scan, chapter_strategy, chapter_regex FROM libraries;
+DROP TABLE libraries;
+ALTER TABLE libraries_old RENAME TO libraries;
+
+CREATE TABLE audiobooks_old (
+    id VARCHAR(36) PRIMARY KEY,
+    location TEXT NOT NULL,
+    title VARCHAR(1024) NOT NULL,
+    artist VARCHAR(1024),
+    length DOUBLE PRECISION NOT NULL,
+    library_id UUID REFERENCES libraries (id) NOT NULL,
+    hash BYTEA NOT NULL,
+    file_extension VARCHAR(255) NOT NULL,
+    deleted BOOLEAN NOT NULL DEFAULT FALSE,
+    narrator VARCHAR(1024),
+    series VARCHAR(1024),
+    series_index DOUBLE PRECISION,
+    description TEXT,
+    publisher VARCHAR(1024),
+    year INTEGER,
+    genres TEXT NOT NULL DEFAULT '',
+    language VARCHAR(255),
+    isbn VARCHAR(255),
+    asin VARCHAR(255)
+);
+INSERT INTO audiobooks_old
+    SELECT id, location, title, artist, length, library_id, hash, file_extension, deleted, narrator,
+        series, series_index, description, publisher, year, genres, language, isbn, asin
+    FROM audiobooks;
+DROP TABLE audiobooks;
+ALTER TABLE audiobooks_old RENAME TO audiobooks;
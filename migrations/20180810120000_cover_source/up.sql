@@ -0,0 +1,2 @@
+ALTER TABLE libraries ADD COLUMN cover_preference TEXT NOT NULL DEFAULT 'embedded';
+ALTER TABLE audiobooks ADD COLUMN cover_source TEXT;
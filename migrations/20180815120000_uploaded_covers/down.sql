@@ -0,0 +1,15 @@
+CREATE TABLE audiobook_overrides_old (
+    audiobook_id VARCHAR(36) PRIMARY KEY REFERENCES audiobooks (id) NOT NULL,
+    title VARCHAR(1024),
+    artist VARCHAR(1024),
+    narrator VARCHAR(1024),
+    series VARCHAR(1024),
+    series_index DOUBLE PRECISION,
+    description TEXT,
+    chapters TEXT
+);
+INSERT INTO audiobook_overrides_old
+    SELECT audiobook_id, title, artist, narrator, series, series_index, description, chapters
+    FROM audiobook_overrides;
+DROP TABLE audiobook_overrides;
+ALTER TABLE audiobook_overrides_old RENAME TO audiobook_overrides;
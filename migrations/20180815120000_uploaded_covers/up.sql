@@ -0,0 +1,2 @@
+-- 'uploaded' when an admin replaced the cover of a book
+ALTER TABLE audiobook_overrides ADD COLUMN cover_source VARCHAR(1024);
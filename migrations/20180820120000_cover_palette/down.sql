@@ -0,0 +1,46 @@
+CREATE TABLE audiobooks_old (
+    id VARCHAR(36) PRIMARY KEY,
+    location TEXT NOT NULL,
+    title VARCHAR(1024) NOT NULL,
+    artist VARCHAR(1024),
+    length DOUBLE PRECISION NOT NULL,
+    library_id UUID REFERENCES libraries (id) NOT NULL,
+    hash BYTEA NOT NULL,
+    file_extension VARCHAR(255) NOT NULL,
+    deleted BOOLEAN NOT NULL DEFAULT FALSE,
+    narrator VARCHAR(1024),
+    series VARCHAR(1024),
+    series_index DOUBLE PRECISION,
+    description TEXT,
+    publisher VARCHAR(1024),
+    year INTEGER,
+    genres TEXT NOT NULL DEFAULT '',
+    language VARCHAR(255),
+    isbn VARCHAR(255),
+    asin VARCHAR(255),
+    cover_source TEXT
+);
+INSERT INTO audiobooks_old
+    SELECT id, location, title, artist, length, library_id, hash, file_extension, deleted, narrator,
+        series, series_index, description, publisher, year, genres, language, isbn, asin, cover_source
+    FROM audiobooks;
+DROP TABLE audiobooks;
+ALTER TABLE audiobooks_old RENAME TO audiobooks;
+
+CREATE TABLE audiobook_overrides_old (
+    audiobook_id VARCHAR(36) PRIMARY KEY REFERENCES audiobooks (id) NOT NULL,
+    title VARCHAR(1024),
+    artist VARCHAR(1024),
+    narrator VARCHAR(1024),
+    series VARCHAR(1024),
+    series_index DOUBLE PRECISION,
+    description TEXT,
+    chapters TEXT,
+    cover_source VARCHAR(1024)
+);
+INSERT INTO audiobook_overrides_old
+    SELECT audiobook_id, title, artist, narrator, series, series_index, description, chapters,
+        cover_source
+    FROM audiobook_overrides;
+DROP TABLE audiobook_overrides;
+ALTER TABLE audiobook_overrides_old RENAME TO audiobook_overrides;
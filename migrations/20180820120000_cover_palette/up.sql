@@ -0,0 +1,3 @@
+-- JSON object with the colours and blurhash of the cover
+ALTER TABLE audiobooks ADD COLUMN palette TEXT;
+ALTER TABLE audiobook_overrides ADD COLUMN palette TEXT;
@@ -0,0 +1,14 @@
+CREATE TABLE libraries_old (
+    id VARCHAR(36) PRIMARY KEY,
+    location TEXT NOT NULL,
+    is_audiobook_regex TEXT NOT NULL,
+    last_scan TIMESTAMP,
+    chapter_strategy TEXT NOT NULL DEFAULT 'merge_titles',
+    chapter_regex TEXT,
+    cover_preference TEXT NOT NULL DEFAULT 'embedded'
+);
+INSERT INTO libraries_old
+    SELECT id, location, is_audiobook_regex, last_scan, chapter_strategy, chapter_regex, cover_preference
+    FROM libraries;
+DROP TABLE libraries;
+ALTER TABLE libraries_old RENAME TO libraries;
@@ -0,0 +1 @@
+ALTER TABLE libraries ADD COLUMN multifile_storage TEXT NOT NULL DEFAULT 'remux';
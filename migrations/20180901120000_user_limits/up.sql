@@ -0,0 +1,7 @@
+-- Limits set by an admin for single users, these win over the configured ones
+CREATE TABLE user_limits (
+    user_id VARCHAR(36) PRIMARY KEY REFERENCES users (id) NOT NULL,
+    max_streams INTEGER,
+    -- kbit/s, 0 lifts the limit
+    bandwidth INTEGER
+);
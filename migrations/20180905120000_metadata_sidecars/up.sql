@@ -0,0 +1,6 @@
+-- The metadata sidecars a book was last read with, so removing one brings back the tags
+CREATE TABLE metadata_sidecars (
+    audiobook_id VARCHAR(36) REFERENCES audiobooks (id) NOT NULL,
+    file_name TEXT NOT NULL,
+    PRIMARY KEY (audiobook_id, file_name)
+);
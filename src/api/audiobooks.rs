@@ -1,4 +1,4 @@
-use crate::models::user::User;
+use crate::models::user::{User, Admin};
 use rocket_contrib::json::Json;
 use diesel::prelude::*;
 use serde_json;
@@ -6,29 +6,47 @@ use crate::helpers::db::DB;
 use crate::helpers::uuid::Uuid;
 use crate::models::library::Library;
 use crate::models::playstate::Playstate;
-use crate::models::audiobook::Audiobook;
+use crate::models::audiobook::{Audiobook, AudiobookFilter, COVER_UPLOADED};
+use crate::models::audiobook_override::{AudiobookOverride, AudiobookPatch, ChapterList, ChapterPatch, ChapterEdit};
+use crate::models::chapter::Chapter;
 use diesel::prelude;
 use std::path::{Path, PathBuf};
 use crate::api::ranged_file::RangedFile;
+use crate::api::cover_image::CoverImage;
+use crate::api::zip_download::{ZipDownload, ZipStream};
+use crate::api::streaming::{MediaServer, Stream, StreamSlot};
+use crate::worker::{chapter_audio, cover, palette, transcode_cache, virtual_file};
+use crate::worker::transcode_cache::{StreamFormat, Transcode};
+use crate::worker::mediafile::ImageType;
 use std::fs;
-use std::io;
+use std::io::Read;
 use crate::schema::audiobooks::dsl::{audiobooks, self};
-use crate::responses::{APIResponse, APIError, self, ok, internal_server_error};
-use rocket::response::NamedFile;
+use crate::responses::{APIResponse, APIError, self, ok, internal_server_error, unprocessable_entity};
+use rocket::request::LenientForm;
+use rocket::http::{ContentType, Status};
+use rocket::Data;
 use crate::config::Config;
 
-#[get("/data/<book_id>")]
-pub fn get_data_file(current_user: User, db: DB, book_id: Uuid, config: Config) -> Result<RangedFile, APIError> {
-    match current_user.get_book_if_accessible(&book_id, &*db)? {
-        Some(_) => (),
+/// The audio file of a book. With `format` (`opus`, `aac` or `mp3`) and optionally a `bitrate` in
+/// kbit/s a transcode is sent instead, e.g. `/data/<id>?format=opus&bitrate=32`. Transcodes are
+/// made in the background when first requested and answered with a 202 until they are done.
+#[get("/data/<book_id>?<format>&<bitrate>")]
+pub fn get_data_file(current_user: User, db: DB, book_id: Uuid, format: Option<String>, bitrate: Option<u32>,
+                     config: Config, server: MediaServer) -> Result<Stream<RangedFile>, APIError> {
+    if let MediaServer::Elsewhere(url) = server {
+        return Ok(Stream::Elsewhere(url));
+    }
+    let book = match current_user.get_book_if_accessible(&book_id, &*db)? {
+        Some(b) => b,
         None => return Err(responses::not_found())
     };
-    let book = audiobooks.filter(dsl::id.eq(book_id)).first::<Audiobook>(&*db)?;
-    let mut path = PathBuf::from(config.data_directory);
-    path.push(book.id.hyphenated().to_string());
-    path.set_extension(book.file_extension);
+    let path = match format {
+        Some(name) => transcoded_file(book, &name, bitrate, &config, &*db)?,
+        None => book.data_file(&config.data_directory),
+    };
+    let slot = StreamSlot::acquire(&current_user, &config.web.streaming, &*db)?;
     match RangedFile::open(path.clone()) {
-        Ok(f) => Ok(f),
+        Ok(f) => Ok(Stream::Body(f, slot)),
         Err(_) => {
             println!("Audiobook file not found in data directory: {:?}", path);
             Err(internal_server_error())
@@ -36,29 +54,207 @@ pub fn get_data_file(current_user: User, db: DB, book_id: Uuid, config: Config)
     }
 }
 
-#[get("/coverart/<book_id>")]
-pub fn get_coverart(current_user: User, db: DB, book_id: Uuid, config: Config) -> Result<NamedFile, APIError> {
-    use crate::schema::libraries::dsl::*;
+fn transcoded_file(book: Audiobook, format: &str, bitrate: Option<u32>, config: &Config,
+                   conn: &diesel::SqliteConnection) -> Result<PathBuf, APIError> {
+    let format = match StreamFormat::from_name(format) {
+        Some(f) => f,
+        None => return Err(responses::bad_request().message("Unknown format, use opus, aac or mp3."))
+    };
+    let allowed = &config.transcoding.bitrates;
+    let bitrate = match bitrate {
+        Some(b) => b,
+        None if allowed.contains(&format.default_bitrate()) => format.default_bitrate(),
+        None => match allowed.first() {
+            Some(&b) => b,
+            None => return Err(responses::bad_request().message("Transcoding is turned off."))
+        }
+    };
+    if !allowed.contains(&bitrate) {
+        let allowed: Vec<String> = allowed.iter().map(|b| b.to_string()).collect();
+        return Err(responses::bad_request().message(&format!(
+            "The bitrate has to be one of {} kbit/s.", allowed.join(", ")
+        )));
+    }
+    let chapters = AudiobookOverride::chapters_of(&book, conn)?;
+    let cover = cover::read_image(&cover_path(&config.data_directory, &book.id)).ok();
+    let profile = format.profile(bitrate);
+    match transcode_cache::transcode(&config.transcoding, &config.data_directory, book, chapters, cover, profile) {
+        Transcode::Ready(path) => Ok(path),
+        Transcode::Running => Err(APIError::new(Status::Accepted)
+            .message("The book is being transcoded, try again shortly.")),
+        Transcode::Failed(e) => Err(internal_server_error().message(&format!("Transcoding failed: {}", e))),
+        Transcode::Busy => Err(APIError::new(Status::ServiceUnavailable)
+            .message("Too many books are being transcoded, try again later.")),
+    }
+}
+
+/// The audio of a single chapter, in the format of the book. Clients syncing books for offline
+/// listening can fetch a few chapters at a time instead of the whole book.
+#[get("/audiobooks/<book_id>/chapters/<number>/audio")]
+pub fn get_chapter_audio(current_user: User, db: DB, book_id: Uuid, number: i64, config: Config,
+                         server: MediaServer) -> Result<Stream<RangedFile>, APIError> {
+    if let MediaServer::Elsewhere(url) = server {
+        return Ok(Stream::Elsewhere(url));
+    }
     let book = match current_user.get_book_if_accessible(&book_id, &*db)? {
-        Some(a) => a,
-        None => return Err(responses::not_found().message("No book found or not accessible."))
+        Some(b) => b,
+        None => return Err(responses::not_found())
     };
-    let mut path = PathBuf::from(config.data_directory);
-    path.push("img");
-    path.push(book_id.hyphenated().to_string());
-    match NamedFile::open(path) {
-        Ok(f) => Ok(f),
-        Err(e) => match e.kind() {
-            io::ErrorKind::NotFound => Err(responses::not_found().message("No cover art found.")),
-            _ => Err(responses::internal_server_error())
+    let chapters = AudiobookOverride::chapters_of(&book, &*db)?;
+    let position = match chapters.iter().position(|c| c.number == number) {
+        Some(p) => p,
+        None => return Err(responses::not_found().message("No such chapter."))
+    };
+    let slot = StreamSlot::acquire(&current_user, &config.web.streaming, &*db)?;
+    let end = chapters.get(position + 1).map(|c| c.start_time).unwrap_or(book.length);
+    let cover = cover::read_image(&cover_path(&config.data_directory, &book_id)).ok();
+    let path = chapter_audio::chapter_file(
+        &book.data_file(&config.data_directory),
+        &chapter_audio::cache_directory(&config.data_directory, &book_id),
+        &book, &chapters[position], end, cover.as_ref()
+    ).map_err(|e| {
+        warn!("Can't extract chapter {} of {}: {}", number, book_id.hyphenated(), e);
+        internal_server_error()
+    })?;
+    let file = RangedFile::open(path).map_err(|_| internal_server_error())?;
+    Ok(Stream::Body(file, slot))
+}
+
+/// Everything needed to listen to a book offline as a single ZIP: the audio file, the cover,
+/// `audiobook.json` like `/audiobooks/<id>` and `chapters.json` like `/audiobooks/<id>/chapters`.
+/// The archive is put together while it is sent.
+#[get("/audiobooks/<book_id>/download")]
+pub fn download_audiobook(current_user: User, db: DB, book_id: Uuid, config: Config,
+                          server: MediaServer) -> Result<Stream<ZipDownload>, APIError> {
+    if let MediaServer::Elsewhere(url) = server {
+        return Ok(Stream::Elsewhere(url));
+    }
+    let book = match current_user.get_book_if_accessible(&book_id, &*db)? {
+        Some(b) => b,
+        None => return Err(responses::not_found())
+    };
+    let slot = StreamSlot::acquire(&current_user, &config.web.streaming, &*db)?;
+    let chapters = AudiobookOverride::chapters_of(&book, &*db)?;
+    let mut folder: String = book.title.trim().chars()
+        .map(|c| if c == '/' || c == '\\' || c.is_control() { '_' } else { c })
+        .collect();
+    if folder.is_empty() || folder.starts_with('.') {
+        folder = book_id.hyphenated().to_string();
+    }
+
+    let mut zip = ZipStream::new(chrono::Local::now());
+    let (audio, size) = virtual_file::open_stream(&book.data_file(&config.data_directory))
+        .map_err(|e| {
+            warn!("Can't read data file of {}: {}", book_id.hyphenated(), e);
+            internal_server_error()
+        })?;
+    zip.add(&format!("{}/{}.{}", folder, folder, book.file_extension), size, audio);
+    let cover = cover::read_image(&cover_path(&config.data_directory, &book_id)).ok();
+    if let Some(image) = cover {
+        let extension = match image.image_type {
+            ImageType::PNG => "png",
+            ImageType::JPG => "jpg",
+        };
+        zip.add_bytes(&format!("{}/cover.{}", folder, extension), image.data);
+    }
+    zip.add_bytes(&format!("{}/chapters.json", folder), serde_json::to_vec_pretty(&chapters)?);
+    zip.add_bytes(&format!("{}/audiobook.json", folder), serde_json::to_vec_pretty(&book)?);
+    Ok(Stream::Body(ZipDownload { stream: zip, file_name: format!("{}.zip", folder) }, slot))
+}
+
+/// The cover of a book, the one uploaded by an admin if there is one.
+fn cover_path(data_directory: &str, book_id: &Uuid) -> PathBuf {
+    let uploaded = cover::uploaded_path(data_directory, book_id);
+    if uploaded.is_file() {
+        uploaded
+    } else {
+        cover::saved_path(data_directory, book_id)
+    }
+}
+
+/// The cover of a book. With `size` the smallest thumbnail at least that large is sent as JPEG,
+/// requests larger than all thumbnails get the original image.
+#[get("/coverart/<book_id>?<size>")]
+pub fn get_coverart(current_user: User, db: DB, book_id: Uuid, size: Option<u32>, config: Config) -> Result<CoverImage, APIError> {
+    if current_user.get_book_if_accessible(&book_id, &*db)?.is_none() {
+        return Err(responses::not_found().message("No book found or not accessible."));
+    }
+    let path = cover_path(&config.data_directory, &book_id);
+    if !path.is_file() {
+        return Err(responses::not_found().message("No cover art found."));
+    }
+    let image = match size.and_then(cover::thumbnail_size) {
+        Some(s) => cover::thumbnail(&path, s)
+            .map_err(|e| warn!("Can't create thumbnail of {:?}: {}", path, e))
+            .ok()
+            .and_then(|thumbnail| CoverImage::open(thumbnail, ContentType::JPEG).ok()),
+        None => {
+            let mut start = [0; 8];
+            let sniffed = fs::File::open(&path)
+                .and_then(|mut f| f.read(&mut start))
+                .ok()
+                .and_then(|n| ImageType::sniff(&start[..n]));
+            let content_type = match sniffed {
+                Some(ImageType::PNG) => ContentType::PNG,
+                Some(ImageType::JPG) => ContentType::JPEG,
+                None => ContentType::Binary,
+            };
+            CoverImage::open(path, content_type).ok()
         }
+    };
+    image.ok_or_else(internal_server_error)
+}
+
+/// Uploaded covers may be this many bytes at most.
+const MAX_COVER_UPLOAD: u64 = 20 * 1024 * 1024;
+
+/// Replace the cover of a book with an uploaded image, the body being the image itself. It is
+/// stored as an override, rescans don't change it.
+#[put("/audiobooks/<book_id>/cover", data = "<data>")]
+pub fn upload_cover(admin: Admin, db: DB, book_id: Uuid, data: Data, config: Config) -> Result<APIResponse, APIError> {
+    if admin.0.get_book_if_accessible(&book_id, &*db)?.is_none() {
+        return Err(responses::not_found());
     }
+    let mut image = Vec::new();
+    data.open().take(MAX_COVER_UPLOAD + 1).read_to_end(&mut image)
+        .map_err(|_| responses::bad_request().message("Can't read the uploaded image."))?;
+    if image.len() as u64 > MAX_COVER_UPLOAD {
+        return Err(APIError::new(Status::PayloadTooLarge).message("The image is too large."));
+    }
+    if let Err(e) = cover::save_upload(&image, &cover::uploaded_path(&config.data_directory, &book_id)) {
+        return Err(unprocessable_entity().message(&format!("Not a valid image: {}", e)));
+    }
+    let mut overrides = AudiobookOverride::find(&book_id, &*db)?
+        .unwrap_or_else(|| AudiobookOverride::new(book_id));
+    overrides.cover_source = Some(COVER_UPLOADED.to_owned());
+    overrides.palette = palette::extract(&image).ok();
+    overrides.save(&*db)?;
+    Ok(ok().data(json!(admin.0.get_book_if_accessible(&book_id, &*db)?)))
 }
 
-#[get("/audiobooks")]
-pub fn get_audiobooks(current_user: User, db: DB) -> Result<APIResponse, APIError> {
-    use crate::schema::libraries::dsl::*;
-    let user_books = current_user.accessible_audiobooks(&*db)?;
+/// Go back to the cover found by the scanner.
+#[delete("/audiobooks/<book_id>/cover")]
+pub fn revert_cover(admin: Admin, db: DB, book_id: Uuid, config: Config) -> Result<APIResponse, APIError> {
+    if admin.0.get_book_if_accessible(&book_id, &*db)?.is_none() {
+        return Err(responses::not_found());
+    }
+    if let Some(mut overrides) = AudiobookOverride::find(&book_id, &*db)? {
+        overrides.cover_source = None;
+        overrides.palette = None;
+        overrides.save(&*db)?;
+    }
+    if let Err(e) = cover::remove_with_thumbnails(&cover::uploaded_path(&config.data_directory, &book_id)) {
+        warn!("Can't remove uploaded cover of {}: {}", book_id.hyphenated(), e);
+        return Err(internal_server_error());
+    }
+    Ok(ok().data(json!(admin.0.get_book_if_accessible(&book_id, &*db)?)))
+}
+
+/// All books of the user, narrowed down by the query parameters of `AudiobookFilter`, e.g.
+/// `/audiobooks?narrator=westphal&genre=classics`.
+#[get("/audiobooks?<filter..>")]
+pub fn get_audiobooks(current_user: User, db: DB, filter: LenientForm<AudiobookFilter>) -> Result<APIResponse, APIError> {
+    let user_books = current_user.filter_accessible_audiobooks(&filter, &*db)?;
     Ok(ok().data(json!(user_books)))
 }
 
@@ -71,3 +267,90 @@ pub fn get_audiobook(current_user: User, db: DB, book_id: Uuid) -> Result<APIRes
     };
     Ok(ok().data(json!(book)))
 }
+
+/// Override metadata of a book, see `AudiobookPatch`. The scanner never changes overridden values,
+/// setting a field to `null` brings back the scanned one.
+#[patch("/audiobooks/<book_id>", data = "<patch>", format = "application/json")]
+pub fn patch_audiobook(admin: Admin, db: DB, book_id: Uuid, patch: Json<AudiobookPatch>) -> Result<APIResponse, APIError> {
+    let mut book = match admin.0.get_book_if_accessible(&book_id, &*db)? {
+        Some(a) => a,
+        None => return Err(responses::not_found())
+    };
+    let mut overrides = AudiobookOverride::find(&book_id, &*db)?
+        .unwrap_or_else(|| AudiobookOverride::new(book_id));
+    if let Err(e) = patch.into_inner().apply_to(&mut overrides, book.length) {
+        return Err(unprocessable_entity().message(&e));
+    }
+    overrides.save(&*db)?;
+    book = audiobooks.filter(dsl::id.eq(book_id)).first::<Audiobook>(&*db)?;
+    overrides.apply_to(&mut book);
+    Ok(ok().data(json!(book)))
+}
+
+#[get("/audiobooks/<book_id>/chapters")]
+pub fn get_chapters(current_user: User, db: DB, book_id: Uuid) -> Result<APIResponse, APIError> {
+    let book = match current_user.get_book_if_accessible(&book_id, &*db)? {
+        Some(a) => a,
+        None => return Err(responses::not_found())
+    };
+    Ok(ok().data(json!(AudiobookOverride::chapters_of(&book, &*db)?)))
+}
+
+/// Add a chapter, it is placed according to its `start_time`.
+#[post("/audiobooks/<book_id>/chapters", data = "<chapter>", format = "application/json")]
+pub fn add_chapter(admin: Admin, db: DB, book_id: Uuid, chapter: Json<ChapterPatch>) -> Result<APIResponse, APIError> {
+    let chapter = chapter.into_inner();
+    edit_chapters(admin, db, book_id, |list| {
+        list.add(chapter.title, chapter.start_time);
+        Ok(())
+    })
+}
+
+/// Rename or move a chapter, see `ChapterEdit`.
+#[patch("/audiobooks/<book_id>/chapters/<chapter_id>", data = "<edit>", format = "application/json")]
+pub fn edit_chapter(admin: Admin, db: DB, book_id: Uuid, chapter_id: Uuid, edit: Json<ChapterEdit>) -> Result<APIResponse, APIError> {
+    edit_chapters(admin, db, book_id, |list| match list.get_mut(&chapter_id) {
+        Some(chapter) => {
+            edit.into_inner().apply_to(chapter);
+            Ok(())
+        },
+        None => Err(responses::not_found().message("No such chapter."))
+    })
+}
+
+#[delete("/audiobooks/<book_id>/chapters/<chapter_id>")]
+pub fn delete_chapter(admin: Admin, db: DB, book_id: Uuid, chapter_id: Uuid) -> Result<APIResponse, APIError> {
+    edit_chapters(admin, db, book_id, |list| if list.remove(&chapter_id) {
+        Ok(())
+    } else {
+        Err(responses::not_found().message("No such chapter."))
+    })
+}
+
+/// Change the chapter overrides of a book and respond with the resulting chapters. The first edit
+/// copies the scanned chapters, from then on the scanner's chapters are ignored until the
+/// overrides are reset with `PATCH /audiobooks/<id>` and `"chapters": null`.
+fn edit_chapters<F>(admin: Admin, db: DB, book_id: Uuid, edit: F) -> Result<APIResponse, APIError>
+    where F: FnOnce(&mut ChapterList) -> Result<(), APIError> {
+    use crate::schema::chapters::dsl::number;
+    let book = match admin.0.get_book_if_accessible(&book_id, &*db)? {
+        Some(a) => a,
+        None => return Err(responses::not_found())
+    };
+    let mut overrides = AudiobookOverride::find(&book_id, &*db)?
+        .unwrap_or_else(|| AudiobookOverride::new(book_id));
+    let mut list = match AudiobookOverride::chapter_list_of(&book, &*db)? {
+        Some(list) => list,
+        None => ChapterList::from_chapters(
+            &Chapter::belonging_to(&book).order(number.asc()).load::<Chapter>(&*db)?
+        )
+    };
+    edit(&mut list)?;
+    if let Err(e) = list.validate(book.length) {
+        return Err(unprocessable_entity().message(&e));
+    }
+    let chapters = list.to_chapters(book_id);
+    overrides.chapters = Some(list);
+    overrides.save(&*db)?;
+    Ok(ok().data(json!(chapters)))
+}
@@ -0,0 +1,52 @@
+use std::fs::{self, File};
+use std::io;
+use std::path::PathBuf;
+
+use rocket::request::Request;
+use rocket::response::{Response, Responder};
+use rocket::http::{Status, ContentType};
+
+use crate::api::ranged_file::file_etag;
+
+/// Covers only change when a book is scanned again, clients may keep them for a week and
+/// revalidate them using the `ETag` afterwards.
+const CACHE_CONTROL: &str = "private, max-age=604800";
+
+/// A cover image or thumbnail. Responds with its content type, an `ETag` and cache headers, a
+/// request whose `If-None-Match` matches the `ETag` gets a 304 without body.
+#[derive(Debug)]
+pub struct CoverImage {
+    path: PathBuf,
+    content_type: ContentType,
+    etag: String,
+}
+
+impl CoverImage {
+    pub fn open(path: PathBuf, content_type: ContentType) -> io::Result<CoverImage> {
+        let etag = file_etag(&fs::metadata(&path)?);
+        Ok(CoverImage { path, content_type, etag })
+    }
+}
+
+impl Responder<'static> for CoverImage {
+    fn respond_to(self, req: &Request) -> Result<Response<'static>, Status> {
+        let mut response = Response::build();
+        response.raw_header("ETag", self.etag.clone())
+            .raw_header("Cache-Control", CACHE_CONTROL);
+
+        let not_modified = req.headers().get("If-None-Match")
+            .flat_map(|value| value.split(','))
+            .any(|tag| {
+                let tag = tag.trim();
+                tag == "*" || tag.trim_start_matches("W/") == self.etag
+            });
+        if not_modified {
+            return response.status(Status::NotModified).ok();
+        }
+
+        let file = File::open(&self.path).map_err(|_| Status::NotFound)?;
+        response.header(self.content_type)
+            .sized_body(file)
+            .ok()
+    }
+}
@@ -0,0 +1,63 @@
+use rocket::http::ContentType;
+use rocket::response::content::Content;
+
+use crate::api::ranged_file::RangedFile;
+use crate::api::streaming::{MediaServer, Stream, StreamSlot};
+use crate::config::Config;
+use crate::helpers::db::DB;
+use crate::helpers::uuid::Uuid;
+use crate::models::audiobook::Audiobook;
+use crate::models::audiobook_override::AudiobookOverride;
+use crate::models::user::{User, ApiToken};
+use crate::responses::{self, APIError, internal_server_error};
+use crate::worker::hls::{self, Segment};
+use diesel::SqliteConnection;
+
+fn book_segments(user: &User, book_id: &Uuid, conn: &SqliteConnection) -> Result<(Audiobook, Vec<Segment>), APIError> {
+    let book = match user.get_book_if_accessible(book_id, conn)? {
+        Some(b) => b,
+        None => return Err(responses::not_found())
+    };
+    let chapters = AudiobookOverride::chapters_of(&book, conn)?;
+    let segments = hls::segments(&chapters, book.length);
+    Ok((book, segments))
+}
+
+/// HLS media playlist of a book with a segment for every ten seconds, chapters start new segments.
+/// Players can't send the `Authorization` header along with segment requests, so the segment URLs
+/// carry the token of this request as `auth` query parameter.
+#[get("/hls/<book_id>/playlist.m3u8")]
+pub fn get_playlist(current_user: User, token: ApiToken, db: DB, book_id: Uuid) -> Result<Content<String>, APIError> {
+    let (_, segments) = book_segments(&current_user, &book_id, &*db)?;
+    let query = format!("?auth={}", token.id.hyphenated());
+    Ok(Content(ContentType::new("application", "vnd.apple.mpegurl"), hls::playlist(&segments, &query)))
+}
+
+/// A segment of the playlist as MPEG-TS, named `<index>.ts`. Segments are remuxed from the data
+/// file when first requested and cached afterwards. Like other audio they are sent by the
+/// streaming server if there is one.
+#[get("/hls/<book_id>/<segment>", rank = 2)]
+pub fn get_segment(current_user: User, db: DB, book_id: Uuid, segment: String, config: Config,
+                   server: MediaServer) -> Result<Stream<RangedFile>, APIError> {
+    if let MediaServer::Elsewhere(url) = server {
+        return Ok(Stream::Elsewhere(url));
+    }
+    let index = match segment.trim_end_matches(".ts").parse::<usize>() {
+        Ok(i) if segment.ends_with(".ts") => i,
+        _ => return Err(responses::not_found())
+    };
+    let (book, segments) = book_segments(&current_user, &book_id, &*db)?;
+    let segment = match segments.get(index) {
+        Some(s) => s,
+        None => return Err(responses::not_found().message("No such segment."))
+    };
+    let slot = StreamSlot::acquire(&current_user, &config.web.streaming, &*db)?;
+    let cache = hls::cache_directory(&config.data_directory, &book_id);
+    let path = hls::segment_file(&book.data_file(&config.data_directory), &cache, segment)
+        .map_err(|e| {
+            warn!("Can't create segment {} of {}: {}", index, book_id.hyphenated(), e);
+            internal_server_error()
+        })?;
+    let file = RangedFile::open(path).map_err(|_| internal_server_error())?;
+    Ok(Stream::Body(file, slot))
+}
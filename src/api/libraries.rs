@@ -7,6 +7,7 @@ use serde_json;
 use crate::helpers::db::DB;
 use crate::models::library::Library;
 use crate::models::audiobook::Audiobook;
+use crate::models::audiobook_override::AudiobookOverride;
 use crate::models::chapter::Chapter;
 use crate::models::playstate::{Playstate, ApiPlaystate};
 
@@ -22,7 +23,7 @@ pub fn all_the_things(current_user: User, db: DB) -> APIResponse {
     use crate::schema;
     let libs = current_user.accessible_libraries(&*db).unwrap();
     let books = current_user.accessible_audiobooks(&*db).unwrap();
-    let chapters: Vec<Chapter> = books.clone().into_iter().flat_map(|b| Chapter::belonging_to(&b).load::<Chapter>(&*db).unwrap()).collect();
+    let chapters: Vec<Chapter> = books.iter().flat_map(|b| AudiobookOverride::chapters_of(b, &*db).unwrap()).collect();
     let playstates: Vec<_> = Playstate::belonging_to(&current_user).load::<Playstate>(&*db)
                                 .unwrap().into_iter().map(|p| p.to_api_playstate()).collect();
     ok().data(json!({
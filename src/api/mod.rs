@@ -1,4 +1,9 @@
 pub mod libraries;
 pub mod audiobooks;
 pub mod auth;
+pub mod users;
 pub mod ranged_file;
+pub mod cover_image;
+pub mod hls;
+pub mod zip_download;
+pub mod streaming;
@@ -1,7 +1,9 @@
-use std::fs::File;
+use std::fs::{File, Metadata};
 use std::path::{Path, PathBuf};
-use std::io;
-use std::ops::{Deref, DerefMut};
+use std::io::{self, Cursor};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use chrono::{DateTime, Utc};
 
 use rocket::request::Request;
 use rocket::response::{Response, Responder, Body};
@@ -9,13 +11,17 @@ use rocket::http::{Status, ContentType};
 use rocket::http::hyper::header::{Range, ByteRangeSpec, AcceptRanges, RangeUnit, ContentLength, ContentRange, ContentRangeSpec};
 use rocket::http::hyper::header::Range::Bytes;
 use rocket::http::hyper::header::ByteRangeSpec::*;
-use std::fs::metadata;
 use std::io::{Seek, SeekFrom, Read};
 
+use crate::helpers::uuid::Uuid;
+use crate::worker::virtual_file::{self, VirtualFile};
+
 /// A file with an associated name; responds with the Content-Type based on the
-/// file extension.
+/// file extension and answers range requests.
+///
+/// Manifests of virtual files (`<id>.mp3.parts`) are sent as the stream made of their parts.
 #[derive(Debug)]
-pub struct RangedFile(PathBuf, File);
+pub struct RangedFile(PathBuf, File, Option<VirtualFile>);
 
 impl RangedFile {
     /// Attempts to open a file in read-only mode.
@@ -37,10 +43,17 @@ impl RangedFile {
     /// ```
     pub fn open<P: AsRef<Path>>(path: P) -> io::Result<RangedFile> {
         let file = File::open(path.as_ref())?;
-        Ok(RangedFile(path.as_ref().to_path_buf(), file))
+        let parts = if virtual_file::is_manifest(path.as_ref()) {
+            let parts = VirtualFile::read(path.as_ref())
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+            Some(parts)
+        } else {
+            None
+        };
+        Ok(RangedFile(path.as_ref().to_path_buf(), file, parts))
     }
 
-    /// Retrieve the underlying `File`.
+    /// Retrieve the underlying `File`, for virtual files this is the manifest.
     #[inline(always)]
     pub fn file(&self) -> &File {
         &self.1
@@ -77,77 +90,174 @@ impl RangedFile {
     pub fn path(&self) -> &Path {
         self.0.as_path()
     }
+
+    /// The parts of a virtual file.
+    #[inline(always)]
+    pub fn parts(&self) -> Option<&VirtualFile> {
+        self.2.as_ref()
+    }
+
+    /// Size of the content that is sent.
+    fn len(&self) -> io::Result<u64> {
+        match self.2 {
+            Some(ref parts) => Ok(parts.len()),
+            None => Ok(self.file().metadata()?.len()),
+        }
+    }
+
+    /// Read the content starting at byte `from`.
+    fn read_from(&self, from: u64) -> io::Result<Box<dyn Read>> {
+        match self.2 {
+            Some(ref parts) => Ok(parts.reader(from)),
+            None => {
+                let mut file = File::open(self.path())?;
+                file.seek(SeekFrom::Start(from))?;
+                Ok(Box::new(file))
+            }
+        }
+    }
+}
+
+/// Longest list of ranges answered, requests for more ranges get the whole file.
+const MAX_RANGES: usize = 32;
+
+/// Content type of an audio file by its extension, which is that of its container. Rocket doesn't
+/// know most audio formats.
+pub fn audio_content_type(extension: &str) -> ContentType {
+    match extension.to_lowercase().as_str() {
+        "mp3" => ContentType::new("audio", "mpeg"),
+        "m4a" | "m4b" | "mp4" => ContentType::new("audio", "mp4"),
+        "aac" => ContentType::new("audio", "aac"),
+        "ogg" | "oga" | "opus" => ContentType::new("audio", "ogg"),
+        "flac" => ContentType::new("audio", "flac"),
+        "wav" => ContentType::new("audio", "wav"),
+        "webm" => ContentType::new("audio", "webm"),
+        "mka" => ContentType::new("audio", "x-matroska"),
+        // HLS segments
+        "ts" => ContentType::new("video", "mp2t"),
+        _ => ContentType::Binary,
+    }
 }
 
-/// Streams the named file to the client. Sets or overrides the Content-Type in
-/// the response according to the file's extension if the extension is
-/// recognized. See
-/// [ContentType::from_extension](/rocket/http/struct.ContentType.html#method.from_extension)
-/// for more information. If you would like to stream a file with a different
-/// Content-Type than that implied by its extension, use a `File` directly.
+/// A strong `ETag` made of size and modification time, which change whenever a file is written.
+pub fn file_etag(meta: &Metadata) -> String {
+    let modified = meta.modified()
+        .ok()
+        .and_then(|m| m.duration_since(UNIX_EPOCH).ok())
+        .unwrap_or_default();
+    format!("\"{:x}-{:x}.{:x}\"", meta.len(), modified.as_secs(), modified.subsec_nanos())
+}
+
+fn http_date(time: SystemTime) -> String {
+    DateTime::<Utc>::from(time).format("%a, %d %b %Y %H:%M:%S GMT").to_string()
+}
+
+/// The ranges to send, `None` if the whole file should be sent. That is the case without a
+/// `Range` header, with one that can't be parsed, and if `If-Range` names another version of the
+/// file.
+fn requested_ranges(req: &Request, etag: &str, last_modified: Option<&str>) -> Option<Vec<ByteRangeSpec>> {
+    let range = req.headers().get_one("Range")?;
+    if let Some(validator) = req.headers().get_one("If-Range") {
+        let validator = validator.trim();
+        if validator != etag && Some(validator) != last_modified {
+            return None;
+        }
+    }
+    match range.parse::<Range>() {
+        Ok(Bytes(specs)) if specs.len() <= MAX_RANGES => Some(specs),
+        _ => None
+    }
+}
+
+/// First and last byte of a range within a file of `size` bytes, `None` if it lies outside.
+fn satisfiable(spec: &ByteRangeSpec, size: u64) -> Option<(u64, u64)> {
+    match *spec {
+        FromTo(from, to) if from < size && from <= to => Some((from, to.min(size - 1))),
+        AllFrom(from) if from < size => Some((from, size - 1)),
+        Last(n) if n > 0 && size > 0 => Some((size - n.min(size), size - 1)),
+        _ => None
+    }
+}
+
+/// Streams the file to the client, honouring `Range` and `If-Range` headers. The Content-Type is
+/// derived from the file extension, see `audio_content_type`. Several ranges are sent as
+/// `multipart/byteranges`, ranges outside of the file get a 416.
+///
+/// HEAD requests are answered by Rocket using this responder as well, it sends the headers and
+/// drops the body without reading it.
 impl Responder<'static> for RangedFile {
     fn respond_to(self, req: &Request) -> Result<Response<'static>, Status> {
+        let meta = self.file().metadata().map_err(|_| Status::InternalServerError)?;
+        let size = self.len().map_err(|_| Status::InternalServerError)?;
+        // a virtual file changes along with its manifest
+        let etag = file_etag(&meta);
+        let last_modified = meta.modified().ok().map(http_date);
+        let audio_path = match self.parts() {
+            Some(_) => self.path().with_extension(""),
+            None => self.path().to_path_buf(),
+        };
+        let content_type = audio_path.extension()
+            .map(|ext| audio_content_type(&ext.to_string_lossy()))
+            .unwrap_or(ContentType::Binary);
+
         let mut response = Response::new();
-        // if let Some(ext) = self.path().extension() {
-        //     // TODO: Use Cow for lowercase.
-        //     let ext_string = ext.to_string_lossy().to_lowercase();
-        //     if let Some(content_type) = ContentType::from_extension(&ext_string) {
-        //         response.set_header(content_type);
-        //     }
-        // }
-        // hard-coded for testing, rocket doesn't have an mp3 content-type
-        response.set_header(ContentType::new("audio", "mpeg"));
-
-        let meta = metadata(self.path()).unwrap();
-        let size = meta.len();
         response.set_header(AcceptRanges(vec![RangeUnit::Bytes]));
+        response.set_raw_header("ETag", etag.clone());
+        if let Some(ref date) = last_modified {
+            response.set_raw_header("Last-Modified", date.clone());
+        }
 
-        if let Some(range) = req.headers().get_one("Range") {
-            let r: Range = range.parse().unwrap();
-            println!("{:?}", r);
-            match r {
-                Bytes(vec) => {
-                    let spec = &vec[0];
-                    let mut f = self.take_file();
-                    match *spec {
-                        FromTo(from, to) => {
-                            let first_byte_not_sent = to + 1;
-                            f.seek(SeekFrom::Start(from));
-                            let body = Body::Sized(f.take(first_byte_not_sent - from), first_byte_not_sent - from);
-                            let result_spec = ContentRangeSpec::Bytes{
-                                range: Some((from, to)),
-                                instance_length: Some(size)
-                            };
-                            response.set_header(ContentRange(result_spec));
-                            response.set_raw_body(body);
-                        }
-                        AllFrom(from) => {
-                            f.seek(SeekFrom::Start(from));
-                            let body = Body::Sized(f, meta.len() - from);
-                            let result_spec = ContentRangeSpec::Bytes{
-                                range: Some((from, size - 1)),
-                                instance_length: Some(size)
-                            };
-                            response.set_header(ContentRange(result_spec));
-                            response.set_raw_body(body);
-                        }
-                        Last(n) => {
-                            f.seek(SeekFrom::End(-(n as i64)));
-                            let body = Body::Sized(f, n);
-                            let result_spec = ContentRangeSpec::Bytes{
-                                range: Some((size - n, size - 1)),
-                                instance_length: Some(size)
-                            };
-                            response.set_header(ContentRange(result_spec));
-                            response.set_raw_body(body);
-                        }
-                    };
-                    response.set_status(Status::PartialContent);
-                }
-                _ => unreachable!("can't deal with non-byte ranges")
+        let specs = match requested_ranges(req, &etag, last_modified.as_ref().map(String::as_str)) {
+            Some(specs) => specs,
+            None => {
+                response.set_header(content_type);
+                response.set_header(ContentLength(size));
+                let body: Box<dyn Read> = match self {
+                    RangedFile(_, _, Some(parts)) => parts.reader(0),
+                    RangedFile(_, file, None) => Box::new(file),
+                };
+                response.set_raw_body(Body::Sized(body, size));
+                return Ok(response);
             }
+        };
+        let ranges: Vec<(u64, u64)> = specs.iter().filter_map(|s| satisfiable(s, size)).collect();
+        if ranges.is_empty() {
+            response.set_status(Status::RangeNotSatisfiable);
+            response.set_header(ContentRange(ContentRangeSpec::Bytes {
+                range: None,
+                instance_length: Some(size)
+            }));
+            return Ok(response);
+        }
+
+        response.set_status(Status::PartialContent);
+        if ranges.len() == 1 {
+            let (from, to) = ranges[0];
+            let body = self.read_from(from).map_err(|_| Status::InternalServerError)?;
+            response.set_header(content_type);
+            response.set_header(ContentRange(ContentRangeSpec::Bytes {
+                range: Some((from, to)),
+                instance_length: Some(size)
+            }));
+            response.set_header(ContentLength(to - from + 1));
+            response.set_raw_body(Body::Sized(body.take(to - from + 1), to - from + 1));
         } else {
-            response.set_sized_body(self.take_file());
+            let boundary = Uuid::new_v4().hyphenated().to_string();
+            let mut body: Box<dyn Read> = Box::new(io::empty());
+            let mut length = 0;
+            for &(from, to) in &ranges {
+                let head = format!("\r\n--{}\r\nContent-Type: {}\r\nContent-Range: bytes {}-{}/{}\r\n\r\n",
+                                   boundary, content_type, from, to, size);
+                let part = self.read_from(from).map_err(|_| Status::InternalServerError)?;
+                length += head.len() as u64 + to - from + 1;
+                body = Box::new(body.chain(Cursor::new(head.into_bytes())).chain(part.take(to - from + 1)));
+            }
+            let tail = format!("\r\n--{}--\r\n", boundary);
+            length += tail.len() as u64;
+            body = Box::new(body.chain(Cursor::new(tail.into_bytes())));
+            response.set_raw_header("Content-Type", format!("multipart/byteranges; boundary={}", boundary));
+            response.set_header(ContentLength(length));
+            response.set_raw_body(Body::Sized(body, length));
         }
 
         Ok(response)
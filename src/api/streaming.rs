@@ -0,0 +1,258 @@
+use std::collections::HashMap;
+use std::io::{self, Read};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use diesel::sqlite::SqliteConnection;
+
+use rocket::Outcome;
+use rocket::State;
+use rocket::request::{self, Request, FromRequest};
+use rocket::response::{Response, Responder, Body};
+use rocket::http::Status;
+
+use crate::config::{Config, StreamingConfig};
+use crate::helpers::uuid::Uuid;
+use crate::models::user::User;
+use crate::models::user_limits::UserLimits;
+use crate::responses::APIError;
+
+/// Which of the two web servers a Rocket instance is, see `StreamingConfig`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ServerRole {
+    Api,
+    Streaming,
+}
+
+lazy_static! {
+    /// The streams being sent, to all users and to each of them.
+    static ref OPEN_STREAMS: Mutex<OpenStreams> = Mutex::new(OpenStreams::default());
+}
+
+#[derive(Default)]
+struct OpenStreams {
+    total: usize,
+    pacer: Pacer,
+    users: HashMap<Uuid, UserStreams>,
+}
+
+#[derive(Default)]
+struct UserStreams {
+    open: usize,
+    pacer: Pacer,
+}
+
+/// Spreads the bytes of several streams over time so together they don't exceed a bandwidth.
+struct Pacer {
+    /// When the bytes sent so far have been paid for.
+    next: Instant,
+}
+
+impl Default for Pacer {
+    fn default() -> Self {
+        Pacer { next: Instant::now() }
+    }
+}
+
+impl Pacer {
+    /// Account for `bytes` sent at `rate` bytes per second, returns how long to wait before
+    /// sending them. Time not used by the streams doesn't build up to allow a burst later.
+    fn reserve(&mut self, bytes: usize, rate: u64) -> Duration {
+        if rate == 0 {
+            return Duration::from_secs(0);
+        }
+        let now = Instant::now();
+        if self.next < now {
+            self.next = now;
+        }
+        let wait = self.next - now;
+        self.next += Duration::from_nanos(bytes as u64 * 1_000_000_000 / rate);
+        wait
+    }
+}
+
+/// A stream a user has open. It is closed when the body holding it is dropped, that is once it
+/// is sent or the client went away.
+#[derive(Debug)]
+pub struct StreamSlot {
+    user_id: Uuid,
+    /// Bandwidths of the user and of all streams in bytes per second, 0 for no limit.
+    user_rate: u64,
+    total_rate: u64,
+}
+
+impl StreamSlot {
+    /// Open a stream for a user, unless they or all users together already have as many open as
+    /// they may.
+    pub fn acquire(user: &User, config: &StreamingConfig, conn: &SqliteConnection) -> Result<Self, APIError> {
+        let limits = UserLimits::of(user, config, conn)?;
+        let mut streams = OPEN_STREAMS.lock().unwrap();
+        let max_streams = config.stream_limit();
+        if max_streams > 0 && streams.total >= max_streams {
+            return Err(APIError::new(Status::ServiceUnavailable)
+                .message("Too many streams are open, try again later."));
+        }
+        let user_streams = streams.users.entry(user.id).or_insert_with(UserStreams::default);
+        if limits.max_streams > 0 && user_streams.open >= limits.max_streams {
+            return Err(APIError::new(Status::TooManyRequests)
+                .message(&format!("You can't have more than {} streams open at once.", limits.max_streams)));
+        }
+        user_streams.open += 1;
+        streams.total += 1;
+        Ok(StreamSlot {
+            user_id: user.id,
+            user_rate: limits.bandwidth * 1000 / 8,
+            total_rate: config.total_bandwidth * 1000 / 8,
+        })
+    }
+
+    /// Number of streams a user has open.
+    pub fn open_streams(user_id: &Uuid) -> usize {
+        OPEN_STREAMS.lock().unwrap().users.get(user_id).map(|u| u.open).unwrap_or(0)
+    }
+
+    /// Lift the bandwidth limits, for streams sent by the workers of the API server.
+    fn unthrottle(&mut self) {
+        self.user_rate = 0;
+        self.total_rate = 0;
+    }
+
+    /// How many bytes to send at once, so a throttled stream is sent in chunks of about 100ms.
+    fn chunk_size(&self) -> Option<usize> {
+        [self.user_rate, self.total_rate].iter()
+            .filter(|rate| **rate > 0)
+            .min()
+            .map(|rate| (rate / 10).max(1024) as usize)
+    }
+
+    /// Account for bytes of the stream, returns how long to wait before sending them.
+    fn pace(&self, bytes: usize) -> Duration {
+        let mut streams = OPEN_STREAMS.lock().unwrap();
+        let total_wait = streams.pacer.reserve(bytes, self.total_rate);
+        let user_wait = match streams.users.get_mut(&self.user_id) {
+            Some(user_streams) => user_streams.pacer.reserve(bytes, self.user_rate),
+            None => Duration::from_secs(0)
+        };
+        total_wait.max(user_wait)
+    }
+}
+
+impl Drop for StreamSlot {
+    fn drop(&mut self) {
+        let mut streams = OPEN_STREAMS.lock().unwrap();
+        streams.total -= 1;
+        let remaining = match streams.users.get_mut(&self.user_id) {
+            Some(user_streams) => {
+                user_streams.open -= 1;
+                user_streams.open
+            },
+            None => return
+        };
+        if remaining == 0 {
+            streams.users.remove(&self.user_id);
+        }
+    }
+}
+
+/// Where a request for media is answered: here, or at the streaming server if it reached the
+/// API server while there is a streaming server.
+pub enum MediaServer {
+    Here,
+    Elsewhere(String),
+}
+
+impl<'a, 'r> FromRequest<'a, 'r> for MediaServer {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<MediaServer, ()> {
+        let role = request.guard::<State<ServerRole>>().map(|role| *role);
+        let config = match request.guard::<Config>() {
+            Outcome::Success(config) => config,
+            _ => return Outcome::Success(MediaServer::Here)
+        };
+        // `Config::check` makes sure there is a `public_url` along with the port
+        let base = match (role, &config.web.streaming.port, &config.web.streaming.public_url) {
+            (Outcome::Success(ServerRole::Api), Some(_), Some(url)) => url.trim_end_matches('/').to_owned(),
+            _ => return Outcome::Success(MediaServer::Here)
+        };
+
+        // clients don't send the `Authorization` header to another host, the token has to be in
+        // the URL like for HLS segments
+        let mut query: Vec<String> = request.uri().query()
+            .map(|q| q.split('&').map(str::to_owned).collect())
+            .unwrap_or_default();
+        if !query.iter().any(|q| q.starts_with("auth=")) {
+            if let Some(token) = request.headers().get_one("Authorization") {
+                query.push(format!("auth={}", percent_encode(token)));
+            }
+        }
+        let mut url = format!("{}{}", base, request.uri().path());
+        if !query.is_empty() {
+            url.push('?');
+            url.push_str(&query.join("&"));
+        }
+        Outcome::Success(MediaServer::Elsewhere(url))
+    }
+}
+
+/// Escape everything but unreserved characters so a value can't change the query it is put in.
+fn percent_encode(value: &str) -> String {
+    value.bytes().map(|b| match b {
+        b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => (b as char).to_string(),
+        _ => format!("%{:02X}", b)
+    }).collect()
+}
+
+/// A response holding large amounts of media. Its body keeps a `StreamSlot` until it is sent, or
+/// it redirects to the streaming server.
+pub enum Stream<R> {
+    Body(R, StreamSlot),
+    Elsewhere(String),
+}
+
+impl<R: Responder<'static>> Responder<'static> for Stream<R> {
+    fn respond_to(self, req: &Request) -> Result<Response<'static>, Status> {
+        let (inner, mut slot) = match self {
+            Stream::Body(inner, slot) => (inner, slot),
+            Stream::Elsewhere(url) => {
+                return Response::build()
+                    .status(Status::TemporaryRedirect)
+                    .raw_header("Location", url)
+                    .ok();
+            }
+        };
+        // waiting between chunks would keep the API from answering, only the streaming server
+        // throttles
+        if req.guard::<State<ServerRole>>().succeeded().map(|role| *role) != Some(ServerRole::Streaming) {
+            slot.unthrottle();
+        }
+        let mut response = inner.respond_to(req)?;
+        match response.take_body() {
+            Some(Body::Sized(body, size)) => response.set_raw_body(Body::Sized(SlotReader { body, slot }, size)),
+            Some(Body::Chunked(body, chunk_size)) => {
+                response.set_raw_body(Body::Chunked(SlotReader { body, slot }, chunk_size))
+            },
+            None => {}
+        }
+        Ok(response)
+    }
+}
+
+/// Reads a body within the bandwidth of its stream and closes the stream when dropped.
+struct SlotReader<R> {
+    body: R,
+    slot: StreamSlot,
+}
+
+impl<R: Read> Read for SlotReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = match self.slot.chunk_size() {
+            Some(size) => buf.len().min(size),
+            None => return self.body.read(buf)
+        };
+        let read = self.body.read(&mut buf[..len])?;
+        thread::sleep(self.slot.pace(read));
+        Ok(read)
+    }
+}
@@ -0,0 +1,46 @@
+use diesel::prelude::*;
+use rocket_contrib::json::Json;
+
+use crate::config::Config;
+use crate::helpers::db::DB;
+use crate::helpers::uuid::Uuid;
+use crate::models::user::{User, Admin};
+use crate::models::user_limits::{UserLimits, LimitsPatch};
+use crate::responses::{APIResponse, APIError, self, ok, unprocessable_entity};
+use crate::schema::users;
+
+/// The streaming limits an admin set for a user and the limits the user streams with.
+#[get("/users/<user_id>/limits")]
+pub fn get_limits(_admin: Admin, db: DB, user_id: Uuid, config: Config) -> Result<APIResponse, APIError> {
+    let user = find_user(&user_id, &*db)?;
+    let limits = UserLimits::find(&user.id, &*db)?.unwrap_or_else(|| UserLimits::new(user.id));
+    Ok(limits_response(&limits, &config))
+}
+
+/// Set streaming limits of a user, see `LimitsPatch`. 0 lifts a limit for the user.
+#[patch("/users/<user_id>/limits", data = "<patch>", format = "application/json")]
+pub fn patch_limits(_admin: Admin, db: DB, user_id: Uuid, patch: Json<LimitsPatch>,
+                    config: Config) -> Result<APIResponse, APIError> {
+    let user = find_user(&user_id, &*db)?;
+    let mut limits = UserLimits::find(&user.id, &*db)?.unwrap_or_else(|| UserLimits::new(user.id));
+    if let Err(e) = patch.into_inner().apply_to(&mut limits) {
+        return Err(unprocessable_entity().message(&e));
+    }
+    limits.save(&*db)?;
+    Ok(limits_response(&limits, &config))
+}
+
+fn find_user(user_id: &Uuid, conn: &SqliteConnection) -> Result<User, APIError> {
+    match users::table.find(user_id).first::<User>(conn).optional()? {
+        Some(user) => Ok(user),
+        None => Err(responses::not_found())
+    }
+}
+
+fn limits_response(limits: &UserLimits, config: &Config) -> APIResponse {
+    ok().data(json!({
+        "max_streams": limits.max_streams,
+        "bandwidth": limits.bandwidth,
+        "effective": limits.apply_to(&config.web.streaming),
+    }))
+}
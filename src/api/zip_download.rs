@@ -0,0 +1,341 @@
+use std::fs::File;
+use std::io::{self, Cursor, Read};
+use std::mem;
+use std::path::Path;
+
+use chrono::{DateTime, Datelike, Local, Timelike};
+use crc32fast::Hasher;
+
+use rocket::request::Request;
+use rocket::response::{Response, Responder, Body};
+use rocket::http::{Status, ContentType};
+use rocket::http::hyper::header::ContentLength;
+
+/// Sizes and offsets from here on need the ZIP64 extensions.
+const ZIP64_LIMIT: u64 = 0xFFFF_FFFF;
+/// Names are UTF-8, sizes and CRC follow the data in a data descriptor.
+const FLAGS: u16 = 0x0808;
+
+/// A file inside the archive.
+struct Entry {
+    name: String,
+    size: u64,
+    offset: u64,
+    crc: u32,
+    source: Option<Box<dyn Read>>,
+}
+
+impl Entry {
+    fn zip64(&self) -> bool {
+        self.size >= ZIP64_LIMIT || self.offset >= ZIP64_LIMIT
+    }
+
+    fn version(&self) -> u16 {
+        if self.zip64() { 45 } else { 20 }
+    }
+
+    fn header_len(&self) -> u64 {
+        30 + self.name.len() as u64 + if self.zip64() { 20 } else { 0 }
+    }
+
+    fn descriptor_len(&self) -> u64 {
+        if self.zip64() { 24 } else { 16 }
+    }
+
+    fn central_len(&self) -> u64 {
+        46 + self.name.len() as u64 + if self.zip64() { 28 } else { 0 }
+    }
+}
+
+/// What `ZipStream` is reading from at the moment.
+enum Part {
+    Header(Cursor<Vec<u8>>),
+    Data(Box<dyn Read>, Hasher, u64),
+    Descriptor(Cursor<Vec<u8>>),
+    Tail(Cursor<Vec<u8>>),
+}
+
+/// A ZIP archive read while it is written. Entries are stored without compression, audio and
+/// images don't get any smaller anyway, which makes the size of the archive known up front. The
+/// CRC of an entry is calculated while it is read and written after it in a data descriptor.
+pub struct ZipStream {
+    entries: Vec<Entry>,
+    current: usize,
+    part: Part,
+    time: u16,
+    date: u16,
+}
+
+impl ZipStream {
+    pub fn new(modified: DateTime<Local>) -> Self {
+        ZipStream {
+            entries: Vec::new(),
+            current: 0,
+            part: Part::Tail(Cursor::new(Vec::new())),
+            time: ((modified.hour() << 11) | (modified.minute() << 5) | (modified.second() / 2)) as u16,
+            date: (((modified.year().max(1980) - 1980) << 9) as u32 | (modified.month() << 5) | modified.day()) as u16,
+        }
+    }
+
+    /// Add an entry read from `source`, which has to yield exactly `size` bytes.
+    pub fn add<R: Read + 'static>(&mut self, name: &str, size: u64, source: R) {
+        let offset = self.entries.last()
+            .map(|e| e.offset + e.header_len() + e.size + e.descriptor_len())
+            .unwrap_or(0);
+        self.entries.push(Entry {
+            name: name.to_owned(),
+            size,
+            offset,
+            crc: 0,
+            source: Some(Box::new(source)),
+        });
+    }
+
+    pub fn add_bytes(&mut self, name: &str, data: Vec<u8>) {
+        let size = data.len() as u64;
+        self.add(name, size, Cursor::new(data));
+    }
+
+    pub fn add_file(&mut self, name: &str, path: &Path) -> io::Result<()> {
+        let file = File::open(path)?;
+        let size = file.metadata()?.len();
+        self.add(name, size, file);
+        Ok(())
+    }
+
+    fn central_directory_offset(&self) -> u64 {
+        self.entries.last()
+            .map(|e| e.offset + e.header_len() + e.size + e.descriptor_len())
+            .unwrap_or(0)
+    }
+
+    fn central_directory_len(&self) -> u64 {
+        self.entries.iter().map(Entry::central_len).sum()
+    }
+
+    fn zip64_end(&self) -> bool {
+        self.entries.len() >= 0xFFFF
+            || self.central_directory_offset() >= ZIP64_LIMIT
+            || self.central_directory_len() >= ZIP64_LIMIT
+            || self.entries.iter().any(Entry::zip64)
+    }
+
+    /// Size of the whole archive.
+    pub fn len(&self) -> u64 {
+        let end = if self.zip64_end() { 56 + 20 + 22 } else { 22 };
+        self.central_directory_offset() + self.central_directory_len() + end
+    }
+
+    /// Start reading the archive.
+    fn start(&mut self) {
+        self.part = match self.entries.first() {
+            Some(entry) => Part::Header(Cursor::new(self.local_header(entry))),
+            None => Part::Tail(Cursor::new(self.tail())),
+        };
+    }
+
+    fn local_header(&self, entry: &Entry) -> Vec<u8> {
+        let mut header = Vec::with_capacity(entry.header_len() as usize);
+        put_u32(&mut header, 0x0403_4b50);
+        put_u16(&mut header, entry.version());
+        put_u16(&mut header, FLAGS);
+        put_u16(&mut header, 0);
+        put_u16(&mut header, self.time);
+        put_u16(&mut header, self.date);
+        put_u32(&mut header, 0);
+        let size = if entry.zip64() { 0xFFFF_FFFF } else { 0 };
+        put_u32(&mut header, size);
+        put_u32(&mut header, size);
+        put_u16(&mut header, entry.name.len() as u16);
+        put_u16(&mut header, if entry.zip64() { 20 } else { 0 });
+        header.extend_from_slice(entry.name.as_bytes());
+        if entry.zip64() {
+            // the actual sizes are in the data descriptor
+            put_u16(&mut header, 0x0001);
+            put_u16(&mut header, 16);
+            put_u64(&mut header, 0);
+            put_u64(&mut header, 0);
+        }
+        header
+    }
+
+    fn descriptor(entry: &Entry) -> Vec<u8> {
+        let mut descriptor = Vec::with_capacity(entry.descriptor_len() as usize);
+        put_u32(&mut descriptor, 0x0807_4b50);
+        put_u32(&mut descriptor, entry.crc);
+        if entry.zip64() {
+            put_u64(&mut descriptor, entry.size);
+            put_u64(&mut descriptor, entry.size);
+        } else {
+            put_u32(&mut descriptor, entry.size as u32);
+            put_u32(&mut descriptor, entry.size as u32);
+        }
+        descriptor
+    }
+
+    /// The central directory and the end records.
+    fn tail(&self) -> Vec<u8> {
+        let mut tail = Vec::new();
+        for entry in &self.entries {
+            put_u32(&mut tail, 0x0201_4b50);
+            put_u16(&mut tail, entry.version());
+            put_u16(&mut tail, entry.version());
+            put_u16(&mut tail, FLAGS);
+            put_u16(&mut tail, 0);
+            put_u16(&mut tail, self.time);
+            put_u16(&mut tail, self.date);
+            put_u32(&mut tail, entry.crc);
+            let (size, offset) = if entry.zip64() {
+                (0xFFFF_FFFF, 0xFFFF_FFFF)
+            } else {
+                (entry.size as u32, entry.offset as u32)
+            };
+            put_u32(&mut tail, size);
+            put_u32(&mut tail, size);
+            put_u16(&mut tail, entry.name.len() as u16);
+            put_u16(&mut tail, if entry.zip64() { 28 } else { 0 });
+            // comment, disk, internal and external attributes
+            put_u16(&mut tail, 0);
+            put_u16(&mut tail, 0);
+            put_u16(&mut tail, 0);
+            put_u32(&mut tail, 0);
+            put_u32(&mut tail, offset);
+            tail.extend_from_slice(entry.name.as_bytes());
+            if entry.zip64() {
+                put_u16(&mut tail, 0x0001);
+                put_u16(&mut tail, 24);
+                put_u64(&mut tail, entry.size);
+                put_u64(&mut tail, entry.size);
+                put_u64(&mut tail, entry.offset);
+            }
+        }
+
+        let entries = self.entries.len() as u64;
+        let offset = self.central_directory_offset();
+        let length = self.central_directory_len();
+        if self.zip64_end() {
+            let zip64_end_offset = offset + length;
+            put_u32(&mut tail, 0x0606_4b50);
+            put_u64(&mut tail, 44);
+            put_u16(&mut tail, 45);
+            put_u16(&mut tail, 45);
+            put_u32(&mut tail, 0);
+            put_u32(&mut tail, 0);
+            put_u64(&mut tail, entries);
+            put_u64(&mut tail, entries);
+            put_u64(&mut tail, length);
+            put_u64(&mut tail, offset);
+
+            put_u32(&mut tail, 0x0706_4b50);
+            put_u32(&mut tail, 0);
+            put_u64(&mut tail, zip64_end_offset);
+            put_u32(&mut tail, 1);
+        }
+        put_u32(&mut tail, 0x0605_4b50);
+        put_u16(&mut tail, 0);
+        put_u16(&mut tail, 0);
+        let (entries, length, offset) = if self.zip64_end() {
+            (0xFFFF, 0xFFFF_FFFF, 0xFFFF_FFFF)
+        } else {
+            (entries as u16, length as u32, offset as u32)
+        };
+        put_u16(&mut tail, entries);
+        put_u16(&mut tail, entries);
+        put_u32(&mut tail, length);
+        put_u32(&mut tail, offset);
+        put_u16(&mut tail, 0);
+        tail
+    }
+}
+
+impl Read for ZipStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let next = match self.part {
+                Part::Header(ref mut header) => {
+                    let n = header.read(buf)?;
+                    if n > 0 {
+                        return Ok(n);
+                    }
+                    let entry = &mut self.entries[self.current];
+                    let source = entry.source.take().expect("entry is read twice");
+                    Part::Data(source, Hasher::new(), entry.size)
+                },
+                Part::Data(ref mut source, ref mut hasher, ref mut remaining) => {
+                    if *remaining > 0 {
+                        let max = buf.len().min(*remaining as usize);
+                        let n = source.read(&mut buf[..max])?;
+                        if n == 0 {
+                            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "file shrank while zipping it"));
+                        }
+                        hasher.update(&buf[..n]);
+                        *remaining -= n as u64;
+                        return Ok(n);
+                    }
+                    let entry = &mut self.entries[self.current];
+                    entry.crc = mem::replace(hasher, Hasher::new()).finalize();
+                    Part::Descriptor(Cursor::new(Self::descriptor(entry)))
+                },
+                Part::Descriptor(ref mut descriptor) => {
+                    let n = descriptor.read(buf)?;
+                    if n > 0 {
+                        return Ok(n);
+                    }
+                    self.current += 1;
+                    match self.entries.get(self.current) {
+                        Some(entry) => Part::Header(Cursor::new(self.local_header(entry))),
+                        None => Part::Tail(Cursor::new(self.tail())),
+                    }
+                },
+                Part::Tail(ref mut tail) => return tail.read(buf),
+            };
+            self.part = next;
+        }
+    }
+}
+
+fn put_u16(buf: &mut Vec<u8>, value: u16) {
+    buf.extend_from_slice(&value.to_le_bytes());
+}
+
+fn put_u32(buf: &mut Vec<u8>, value: u32) {
+    buf.extend_from_slice(&value.to_le_bytes());
+}
+
+fn put_u64(buf: &mut Vec<u8>, value: u64) {
+    buf.extend_from_slice(&value.to_le_bytes());
+}
+
+/// Sends a `ZipStream` as attachment named `file_name`.
+pub struct ZipDownload {
+    pub stream: ZipStream,
+    pub file_name: String,
+}
+
+impl Responder<'static> for ZipDownload {
+    fn respond_to(self, _: &Request) -> Result<Response<'static>, Status> {
+        let mut stream = self.stream;
+        let length = stream.len();
+        stream.start();
+        // the plain file name has to be ASCII, clients that know `filename*` get the real one
+        let ascii_name: String = self.file_name.chars()
+            .map(|c| if c.is_ascii_alphanumeric() || " .-_()".contains(c) { c } else { '_' })
+            .collect();
+        let encoded_name: String = self.file_name.bytes()
+            .map(|b| if b.is_ascii_alphanumeric() || b".-_".contains(&b) {
+                (b as char).to_string()
+            } else {
+                format!("%{:02X}", b)
+            })
+            .collect();
+        let mut response = Response::new();
+        response.set_header(ContentType::new("application", "zip"));
+        response.set_raw_header(
+            "Content-Disposition",
+            format!("attachment; filename=\"{}\"; filename*=UTF-8''{}", ascii_name, encoded_name)
+        );
+        response.set_header(ContentLength(length));
+        response.set_raw_body(Body::Sized(stream, length));
+        Ok(response)
+    }
+}
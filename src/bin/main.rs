@@ -18,6 +18,7 @@ use std::error::Error;
 use std::path::PathBuf;
 use std::panic;
 use std::panic::AssertUnwindSafe;
+use std::thread;
 use std::time::Duration;
 use std::fs::OpenOptions;
 
@@ -34,7 +35,7 @@ use scheduled_thread_pool::ScheduledThreadPool;
 use vorleser_server::worker::scanner::{Scanner, LockingBehavior};
 use vorleser_server::schema::libraries;
 use vorleser_server::schema::libraries::dsl::*;
-use vorleser_server::models::library::Library;
+use vorleser_server::models::library::{Library, ChapterStrategy, CoverPreference, MultifileStorage};
 use vorleser_server::models::user::{User, NewUser};
 use vorleser_server::schema::users;
 use vorleser_server::config::{self, Config, WebConfig, LoggingConfig};
@@ -84,6 +85,9 @@ fn main() {
         let email = create_user.value_of("email").expect("a man has no name");
         let pass = create_user.value_of("password").expect("a man has no password");
         let user = User::create(&email, &pass, db).expect("Error saving user");
+        if create_user.is_present("admin") {
+            user.set_admin(true, db).expect("Error making user an admin");
+        }
     }
 
 
@@ -110,6 +114,19 @@ fn main() {
                 .. conf
             };
         }
+        if conf.web.streaming.port.is_some() {
+            let streaming_pool = pool.clone();
+            let streaming_config = conf.clone();
+            thread::spawn(move || {
+                match helpers::rocket::streaming_factory(streaming_pool, streaming_config) {
+                    Ok(r) => error_log!("{}", r.launch()),
+                    Err(e) => error_log!("Invalid streaming server configuration: {}", e)
+                };
+            });
+        } else {
+            warn!("No [web.streaming] port is set, audio is sent by the workers of the API so a few \
+                   listeners can keep it from answering.");
+        }
         match helpers::rocket::factory(pool, conf) {
             Ok(r) => error_log!("{}", r.launch()),
             Err(e) => error_log!("Invalid web-server configuration: {}", e)
@@ -151,6 +168,10 @@ fn build_command_parser<'a, 'b>() -> App<'a, 'b> {
                 .takes_value(true)
                 .required(true)
             )
+            .arg(Arg::with_name("admin")
+                .long("admin")
+                .help("Allow the user to edit the metadata of books")
+            )
         )
         .subcommand(SubCommand::with_name("create-library")
             .about("Create a new Library")
@@ -162,6 +183,29 @@ fn build_command_parser<'a, 'b>() -> App<'a, 'b> {
                 .takes_value(true)
                 .default_value(PATH_REGEX)
             )
+            .arg(Arg::with_name("chapter-strategy")
+                .long("chapter-strategy")
+                .help("How multi-file books are split into chapters: per_file, merge_titles, merge_regex or filename.")
+                .takes_value(true)
+                .default_value("merge_titles")
+            )
+            .arg(Arg::with_name("chapter-regex")
+                .long("chapter-regex")
+                .help("Regex on file names, used to group files for merge_regex and to clean up titles for filename.")
+                .takes_value(true)
+            )
+            .arg(Arg::with_name("cover-preference")
+                .long("cover-preference")
+                .help("Which cover to use for books with both an embedded picture and an image file: embedded or folder.")
+                .takes_value(true)
+                .default_value("embedded")
+            )
+            .arg(Arg::with_name("multifile-storage")
+                .long("multifile-storage")
+                .help("How to store multi-file books: remux them into one file, or virtual to serve mp3 and aac books from their files.")
+                .takes_value(true)
+                .default_value("remux")
+            )
         ).arg(Arg::with_name("config")
                 .short("c")
                 .long("config")
@@ -208,6 +252,32 @@ fn create_library(command: &ArgMatches, conn: &SqliteConnection) {
         command.value_of("path").expect("Please provide a valid utf-8 path.")
     );
     let regex = command.value_of("regex").expect("Regex needs to be valid utf-8.");
+    let chapter_strategy = match command.value_of("chapter-strategy").unwrap_or("merge_titles").parse::<ChapterStrategy>() {
+        Ok(s) => s,
+        Err(e) => {
+            error_log!("{}", e);
+            return;
+        }
+    };
+    let cover_preference = match command.value_of("cover-preference").unwrap_or("embedded").parse::<CoverPreference>() {
+        Ok(p) => p,
+        Err(e) => {
+            error_log!("{}", e);
+            return;
+        }
+    };
+    let multifile_storage = match command.value_of("multifile-storage").unwrap_or("remux").parse::<MultifileStorage>() {
+        Ok(s) => s,
+        Err(e) => {
+            error_log!("{}", e);
+            return;
+        }
+    };
+    let chapter_regex = command.value_of("chapter-regex").map(|r| r.to_owned());
+    if let Some(Err(e)) = chapter_regex.as_ref().map(|r| Regex::new(r)) {
+        error_log!("Invalid chapter regex: {:?}", e);
+        return;
+    }
     let path = if input_path.is_absolute() {
         input_path
     } else {
@@ -215,9 +285,17 @@ fn create_library(command: &ArgMatches, conn: &SqliteConnection) {
     };
     match Regex::new(regex) {
         Ok(_) => {
-            match Library::create(path.to_string_lossy().into_owned(), regex.to_owned(), &*conn)
+            let created = Library::create(path.to_string_lossy().into_owned(), regex.to_owned(), &*conn)
+                .and_then(|mut lib| {
+                    lib.chapter_strategy = chapter_strategy;
+                    lib.chapter_regex = chapter_regex;
+                    lib.cover_preference = cover_preference;
+                    lib.multifile_storage = multifile_storage;
+                    lib.save(&*conn)
+                });
+            match created
             {
-                Ok(lib) => info!("Successfully created library."),
+                Ok(_) => info!("Successfully created library."),
                 Err(error) => error_log!("Library creation failed: {}", error)
             }
         },
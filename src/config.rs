@@ -20,7 +20,11 @@ static CONFIG_LOCATIONS: &'static [&'static str] = &["vorleser-dev.toml"];
 #[derive(Debug, Fail)]
 enum ConfigError {
     #[fail(display = "Could not read any config files")]
-    NoReadableConfig
+    NoReadableConfig,
+    #[fail(display = "web.streaming.public_url has to be set when web.streaming.port is")]
+    NoStreamingUrl,
+    #[fail(display = "Streams can only be throttled by a streaming server, set web.streaming.port")]
+    ThrottlingWithoutStreamingServer,
 }
 
 /// Load a configuration, this checks xdg config paths.
@@ -40,7 +44,8 @@ pub fn load_config_from_path(config_path: &dyn AsRef<Path>) -> Result<Config, Er
     let mut file = File::open(config_path)?;
     let mut content: Vec<u8> = Vec::new();
     file.read_to_end(&mut content)?;
-    let conf = toml::from_slice(&content)?;
+    let conf: Config = toml::from_slice(&content)?;
+    conf.check()?;
     Ok(conf)
 }
 
@@ -53,10 +58,27 @@ pub struct Config {
     pub database: String,
     pub web: WebConfig,
     pub scan: ScanConfig,
+    #[serde(default)]
+    pub transcoding: TranscodingConfig,
     pub sentry_dsn: Option<String>,
     pub logging: LoggingConfig,
 }
 
+impl Config {
+    /// Reject settings that can be parsed but not used together.
+    pub fn check(&self) -> Result<(), Error> {
+        let streaming = &self.web.streaming;
+        if streaming.port.is_some() && streaming.public_url.is_none() {
+            return Err(ConfigError::NoStreamingUrl.into());
+        }
+        // throttled streams wait between chunks, that mustn't happen on the workers of the API
+        if streaming.port.is_none() && (streaming.user_bandwidth > 0 || streaming.total_bandwidth > 0) {
+            return Err(ConfigError::ThrottlingWithoutStreamingServer.into());
+        }
+        Ok(())
+    }
+}
+
 #[derive(Deserialize, Clone, Debug)]
 pub struct LoggingConfig {
     pub level: String,
@@ -70,6 +92,63 @@ pub struct ScanConfig {
     pub enabled: bool,
     #[serde(default= "default_scan_interval")]
     pub interval: u64,
+    /// Multifile books consisting of different formats are transcoded using this profile.
+    #[serde(default)]
+    pub mixed_format_target: TranscodeProfile,
+    /// Prefix chapter titles with the disc number for books split into disc folders.
+    #[serde(default)] // default to false
+    pub prefix_disc_chapters: bool,
+    /// Use chapters from CUE sheets even when the container has chapters of its own.
+    #[serde(default)] // default to false
+    pub prefer_cue_sheets: bool,
+    /// Make up chapters for books that have none by looking for silences.
+    #[serde(default)]
+    pub generated_chapters: GeneratedChaptersConfig,
+}
+
+/// Books with at most one chapter get a chapter every `interval` minutes, moved to the closest
+/// silence of at least `min_silence` seconds that is quieter than `threshold` dB.
+/// This decodes the whole book, so it is off by default.
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+pub struct GeneratedChaptersConfig {
+    #[serde(default)] // default to false
+    pub enabled: bool,
+    #[serde(default = "default_chapter_interval")]
+    pub interval: f64,
+    #[serde(default = "default_silence_threshold")]
+    pub threshold: f64,
+    #[serde(default = "default_min_silence")]
+    pub min_silence: f64,
+}
+
+impl Default for GeneratedChaptersConfig {
+    fn default() -> Self {
+        GeneratedChaptersConfig {
+            enabled: false,
+            interval: default_chapter_interval(),
+            threshold: default_silence_threshold(),
+            min_silence: default_min_silence(),
+        }
+    }
+}
+
+/// Describes the output of a transcode: the file extension determines the container,
+/// `codec` is the name of an ffmpeg encoder and `bitrate` is in bits per second.
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+pub struct TranscodeProfile {
+    pub extension: String,
+    pub codec: String,
+    pub bitrate: i64,
+}
+
+impl Default for TranscodeProfile {
+    fn default() -> Self {
+        TranscodeProfile {
+            extension: "m4b".to_owned(),
+            codec: "aac".to_owned(),
+            bitrate: 64_000,
+        }
+    }
 }
 
 #[derive(Deserialize, Clone)]
@@ -79,6 +158,87 @@ pub struct WebConfig {
     pub port: u16,
     #[serde(default)] // default to false
     pub debug: bool,
+    #[serde(default)]
+    pub streaming: StreamingConfig,
+}
+
+/// Sending a book takes as long as listening to it, and every stream keeps a worker of the web
+/// server busy meanwhile. With a `port` set, audio files, chapters and downloads are sent by a
+/// second server with `workers` of its own, requests for them at the API are redirected there.
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+pub struct StreamingConfig {
+    #[serde(default)]
+    pub port: Option<u16>,
+    #[serde(default = "default_streaming_workers")]
+    pub workers: u16,
+    /// Where clients reach the streaming server, e.g. `https://media.example.com` behind a proxy.
+    /// Required along with `port`, the API redirects there with the token of the client.
+    #[serde(default)]
+    pub public_url: Option<String>,
+    /// How many streams a user may have open at the same time, 0 for no limit.
+    #[serde(default = "default_max_streams_per_user")]
+    pub max_streams_per_user: usize,
+    /// How many streams may be open across all users, 0 for no limit. See `stream_limit`.
+    #[serde(default)]
+    pub max_streams: usize,
+    /// Bandwidth in kbit/s the streams of a user share, 0 for no limit. Admins can set other
+    /// limits for single users. Streams are only throttled by the streaming server.
+    #[serde(default)]
+    pub user_bandwidth: u64,
+    /// Bandwidth in kbit/s all streams share, 0 for no limit.
+    #[serde(default)]
+    pub total_bandwidth: u64,
+}
+
+impl Default for StreamingConfig {
+    fn default() -> Self {
+        StreamingConfig {
+            port: None,
+            workers: default_streaming_workers(),
+            public_url: None,
+            max_streams_per_user: default_max_streams_per_user(),
+            max_streams: 0,
+            user_bandwidth: 0,
+            total_bandwidth: 0,
+        }
+    }
+}
+
+impl StreamingConfig {
+    /// How many streams may be open across all users. Every stream keeps a worker of the
+    /// streaming server busy, so it never takes more than it has `workers`.
+    pub fn stream_limit(&self) -> usize {
+        let workers = usize::from(self.workers);
+        match self.port {
+            Some(_) if self.max_streams == 0 || self.max_streams > workers => workers,
+            _ => self.max_streams
+        }
+    }
+}
+
+/// Transcodes of books for slow connections are made on request and kept in the data directory.
+/// Clients can only pick one of `bitrates`, an empty list turns transcoding off.
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+pub struct TranscodingConfig {
+    /// Bitrates in kbit/s clients may ask for.
+    #[serde(default = "default_transcode_bitrates")]
+    pub bitrates: Vec<u32>,
+    /// How many books are transcoded at the same time.
+    #[serde(default = "default_transcode_workers")]
+    pub workers: usize,
+    /// How many transcodes may wait or run, further requests are turned away until some finish.
+    #[serde(default = "default_transcode_queue")]
+    pub queue: usize,
+}
+
+impl Default for TranscodingConfig {
+    fn default() -> Self {
+        TranscodingConfig {
+            bitrates: default_transcode_bitrates(),
+            workers: default_transcode_workers(),
+            queue: default_transcode_queue(),
+        }
+    }
 }
 
 fn default_log_level() -> String {
@@ -89,6 +249,38 @@ fn default_scan_interval() -> u64 {
     600
 }
 
+fn default_streaming_workers() -> u16 {
+    64
+}
+
+fn default_transcode_bitrates() -> Vec<u32> {
+    vec![32, 64, 128]
+}
+
+fn default_transcode_workers() -> usize {
+    1
+}
+
+fn default_transcode_queue() -> usize {
+    8
+}
+
+fn default_max_streams_per_user() -> usize {
+    4
+}
+
+fn default_chapter_interval() -> f64 {
+    15.0
+}
+
+fn default_silence_threshold() -> f64 {
+    -40.0
+}
+
+fn default_min_silence() -> f64 {
+    1.5
+}
+
 fn default_data_address() -> String {
     "localhost".to_owned()
 }
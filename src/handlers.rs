@@ -2,7 +2,7 @@ use rocket::Outcome;
 use rocket::http::Status;
 use rocket::request::{self, Request, FromRequest};
 
-use crate::models::user::{self, User, ApiToken};
+use crate::models::user::{self, User, Admin, ApiToken};
 use crate::models::library::Library;
 use diesel;
 use diesel::prelude::*;
@@ -33,6 +33,22 @@ impl<'a, 'r> FromRequest<'a, 'r> for User {
     }
 }
 
+impl<'a, 'r> FromRequest<'a, 'r> for Admin {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Admin, ()> {
+        match <User as FromRequest>::from_request(request) {
+            Outcome::Success(user) => if user.admin {
+                Outcome::Success(Admin(user))
+            } else {
+                Outcome::Failure((Status::Forbidden, ()))
+            },
+            Outcome::Failure(err) => Outcome::Failure(err),
+            Outcome::Forward(()) => Outcome::Forward(())
+        }
+    }
+}
+
 impl<'a, 'r> FromRequest<'a, 'r> for ApiToken {
     type Error = ();
 
@@ -1,3 +1,5 @@
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
 use std::path::Path;
 use std::time::Duration;
 use std::mem::transmute;
@@ -179,6 +181,145 @@ fn build_mllt<P: AsRef<Path>>(file: P) -> Result<Option<Vec<u8>>, Error> {
 }
 
 
+/// MP3 bitrates in kbit/s by bitrate index, for MPEG-1 and for MPEG-2/2.5 layer III.
+const MPEG1_BITRATES: [u64; 15] = [0, 32, 40, 48, 56, 64, 80, 96, 112, 128, 160, 192, 224, 256, 320];
+const MPEG2_BITRATES: [u64; 15] = [0, 8, 16, 24, 32, 40, 48, 56, 64, 80, 96, 112, 128, 144, 160];
+const MPEG1_SAMPLE_RATES: [u64; 3] = [44100, 48000, 32000];
+
+/// How many frames are compared to tell VBR files from CBR ones, about 15 seconds of audio.
+const VBR_SAMPLE_FRAMES: usize = 500;
+
+/// How far to look for the next frame after bytes that aren't one, like padding after the tags.
+const RESYNC_WINDOW: usize = 64 * 1024;
+
+/// Read up to `len` bytes at `offset`, less at the end of the file.
+pub(crate) fn read_at(file: &mut File, offset: u64, len: usize) -> io::Result<Vec<u8>> {
+    let mut buf = Vec::with_capacity(len);
+    file.seek(SeekFrom::Start(offset))?;
+    file.take(len as u64).read_to_end(&mut buf)?;
+    Ok(buf)
+}
+
+/// Length of the ID3v2 tags at the start of a file, some files carry more than one.
+pub(crate) fn id3v2_len(file: &mut File, size: u64) -> io::Result<u64> {
+    let mut offset = 0;
+    loop {
+        let header = read_at(file, offset, 10)?;
+        if header.len() < 10 || &header[..3] != b"ID3" {
+            return Ok(offset);
+        }
+        // the size is stored in 7 bits per byte and doesn't count the header or footer
+        let tag_size = header[6..10].iter().fold(0u64, |acc, b| (acc << 7) | u64::from(b & 0x7f));
+        let footer = if header[5] & 0x10 != 0 { 10 } else { 0 };
+        offset = (offset + 10 + tag_size + footer).min(size);
+    }
+}
+
+/// The header of an MP3 frame.
+pub(crate) struct FrameHeader {
+    /// Bitrate in kbit/s.
+    pub bitrate: u64,
+    /// Length of the frame including the header.
+    pub len: u64,
+    /// `Xing`, `Info` or `VBRI` if this frame holds one of those headers rather than audio.
+    pub vbr_tag: Option<&'static str>,
+}
+
+impl FrameHeader {
+    /// Parse the first 40 bytes of a frame, `None` if they aren't the start of a layer III frame.
+    pub fn parse(frame: &[u8]) -> Option<FrameHeader> {
+        if frame.len() < 40 || frame[0] != 0xff || frame[1] & 0xe0 != 0xe0 {
+            return None;
+        }
+        let version = (frame[1] >> 3) & 0x03;
+        let layer = (frame[1] >> 1) & 0x03;
+        let bitrate_index = (frame[2] >> 4) as usize;
+        let sample_rate_index = ((frame[2] >> 2) & 0x03) as usize;
+        let padding = u64::from((frame[2] >> 1) & 0x01);
+        let mono = frame[3] >> 6 == 3;
+        // only layer III files carry VBR headers, free format and reserved values can't be measured
+        if layer != 1 || version == 1 || bitrate_index == 0 || bitrate_index == 15 || sample_rate_index == 3 {
+            return None;
+        }
+        let mpeg1 = version == 3;
+        let side_info = match (mpeg1, mono) {
+            (true, false) => 32,
+            (true, true) | (false, false) => 17,
+            (false, true) => 9,
+        };
+        let xing = &frame[4 + side_info..8 + side_info];
+        let vbr_tag = if xing == b"Xing" {
+            Some("Xing")
+        } else if xing == b"Info" {
+            Some("Info")
+        } else if &frame[36..40] == b"VBRI" {
+            Some("VBRI")
+        } else {
+            None
+        };
+        let (bitrate, sample_rate, factor) = if mpeg1 {
+            (MPEG1_BITRATES[bitrate_index], MPEG1_SAMPLE_RATES[sample_rate_index], 144)
+        } else {
+            // MPEG-2 halves the sample rates of MPEG-1, MPEG-2.5 quarters them
+            let divisor = if version == 2 { 2 } else { 4 };
+            (MPEG2_BITRATES[bitrate_index], MPEG1_SAMPLE_RATES[sample_rate_index] / divisor, 72)
+        };
+        Some(FrameHeader { bitrate, len: factor * bitrate * 1000 / sample_rate + padding, vbr_tag })
+    }
+}
+
+/// The first frame after `offset`, found by its sync word. Junk can contain a sync word by
+/// chance, so the frame has to be followed by another one.
+fn next_frame(file: &mut File, offset: u64) -> io::Result<Option<(u64, FrameHeader)>> {
+    let window = read_at(file, offset + 1, RESYNC_WINDOW)?;
+    for i in 0..window.len() {
+        if window[i] != 0xff {
+            continue;
+        }
+        if let Some(header) = FrameHeader::parse(&window[i..]) {
+            let next = i + header.len as usize;
+            if window.get(next..).and_then(FrameHeader::parse).is_some() {
+                return Ok(Some((offset + 1 + i as u64, header)));
+            }
+        }
+    }
+    Ok(None)
+}
+
+/// Whether an MP3 file has a variable bitrate, going by its Xing or VBRI header and the bitrates
+/// of the first frames. Players can't seek precisely in such files without a seek table, so the
+/// scanner serves a copy with one. Scans look at every linked MP3, so the whole file is not read.
+pub fn is_vbr<P: AsRef<Path>>(path: P) -> Result<bool, Error> {
+    let mut file = File::open(path)?;
+    let size = file.metadata()?.len();
+    let mut offset = id3v2_len(&mut file, size)?;
+    let mut first_bitrate = None;
+    for _ in 0..VBR_SAMPLE_FRAMES {
+        let header = match FrameHeader::parse(&read_at(&mut file, offset, 40)?) {
+            Some(h) => h,
+            None => match next_frame(&mut file, offset)? {
+                Some((next, h)) => {
+                    offset = next;
+                    h
+                },
+                None => break
+            }
+        };
+        match header.vbr_tag {
+            // encoders write Info instead of Xing for CBR files
+            Some("Info") => {},
+            Some(_) => return Ok(true),
+            None => match first_bitrate {
+                None => first_bitrate = Some(header.bitrate),
+                Some(bitrate) if bitrate != header.bitrate => return Ok(true),
+                Some(_) => {},
+            },
+        }
+        offset += header.len;
+    }
+    Ok(false)
+}
+
 pub fn mlltify<P: AsRef<Path>>(file: P) -> Result<(), Error> {
     let mut tag = Tag::read_from_path(&file)?;
 
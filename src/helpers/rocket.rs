@@ -10,6 +10,7 @@ use std::io::Cursor;
 use std::path::PathBuf;
 use rocket::config::Result;
 
+use crate::api::streaming::ServerRole;
 use crate::config;
 pub struct CORS();
 
@@ -24,7 +25,7 @@ impl Fairing for CORS {
     fn on_response(&self, request: &Request, response: &mut Response) {
         if request.method() == Method::Options || response.content_type() == Some(ContentType::JSON) {
             response.set_header(Header::new("Access-Control-Allow-Origin", "*"));
-            response.set_header(Header::new("Access-Control-Allow-Methods", "POST, GET, PUT, GET, DELETE"));
+            response.set_header(Header::new("Access-Control-Allow-Methods", "POST, GET, PUT, PATCH, DELETE"));
             response.set_header(Header::new("Access-Control-Allow-Headers", "Content-Type, Authorization"));
             // response.set_header(Header::new("Access-Control-Allow-Credentials", "true"));
         }
@@ -40,7 +41,7 @@ impl Fairing for CORS {
 fn options_handler<'a>(path: PathBuf) -> Response<'a> {
     Response::build()
         .raw_header("Access-Control-Allow-Origin", "*")
-        .raw_header("Access-Control-Allow-Methods", "OPTIONS, POST, PUT, GET, DELETE")
+        .raw_header("Access-Control-Allow-Methods", "OPTIONS, POST, PUT, PATCH, GET, DELETE")
         .raw_header("Access-Control-Allow-Headers", "Content-Type, Authorization")
         .finalize()
 }
@@ -91,8 +92,13 @@ pub fn base_factory(pool: super::db::Pool, config: config::Config) -> Result<Roc
         .attach(CORS())
         .manage(pool)
         .manage(config.clone())
+        .manage(ServerRole::Api)
         .mount("/", routes![options_handler])
-        .mount("/", routes![api::audiobooks::get_data_file])
+        .mount("/", routes![
+            api::audiobooks::get_data_file,
+            api::hls::get_playlist,
+            api::hls::get_segment,
+        ])
         .mount("/api", routes![
             api::libraries::libraries,
             api::libraries::all_the_things,
@@ -100,6 +106,17 @@ pub fn base_factory(pool: super::db::Pool, config: config::Config) -> Result<Roc
             api::audiobooks::get_coverart,
             api::audiobooks::get_audiobook,
             api::audiobooks::get_audiobooks,
+            api::audiobooks::patch_audiobook,
+            api::audiobooks::get_chapters,
+            api::audiobooks::get_chapter_audio,
+            api::audiobooks::download_audiobook,
+            api::audiobooks::upload_cover,
+            api::audiobooks::revert_cover,
+            api::audiobooks::add_chapter,
+            api::audiobooks::edit_chapter,
+            api::audiobooks::delete_chapter,
+            api::users::get_limits,
+            api::users::patch_limits,
         ])
         .mount("/api/auth", routes![
             api::auth::login,
@@ -110,3 +127,30 @@ pub fn base_factory(pool: super::db::Pool, config: config::Config) -> Result<Roc
         ])
     )
 }
+
+/// The server sending audio when `web.streaming.port` is set. It has its own workers, so long
+/// downloads can't take up the workers answering the API.
+pub fn streaming_factory(pool: super::db::Pool, config: config::Config) -> Result<Rocket> {
+    let port = config.web.streaming.port.unwrap_or(config.web.port);
+    let rocket_config = Config::build(Environment::Production)
+        .address(config.web.address.clone())
+        .port(port)
+        .workers(config.web.streaming.workers)
+        .finalize();
+    add_catchers(rocket_config.map(|rocket_config|
+        rocket::custom(rocket_config)
+            .attach(CORS())
+            .manage(pool)
+            .manage(config.clone())
+            .manage(ServerRole::Streaming)
+            .mount("/", routes![
+                options_handler,
+                api::audiobooks::get_data_file,
+                api::hls::get_segment,
+            ])
+            .mount("/api", routes![
+                api::audiobooks::get_chapter_audio,
+                api::audiobooks::download_audiobook,
+            ])
+    ))
+}
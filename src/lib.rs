@@ -35,6 +35,8 @@ extern crate humanesort;
 extern crate toml;
 extern crate id3;
 extern crate mp3_metadata;
+extern crate libc;
+extern crate crc32fast;
 
 #[cfg(test)] #[macro_use] extern crate speculate;
 
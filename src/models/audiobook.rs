@@ -1,20 +1,29 @@
 use diesel;
 use diesel::prelude::*;
-use std::path::Path;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use diesel::backend::Backend;
+use diesel::deserialize::{self, FromSql};
 use diesel::result::Error;
+use diesel::serialize::{self, ToSql};
+use diesel::sql_types::Text;
+use diesel::sqlite::Sqlite;
 use chrono::NaiveDateTime;
+use serde_json;
 use diesel::sqlite::SqliteConnection;
 use crate::models::user::User;
 use crate::helpers::uuid::Uuid;
 
 use crate::models::library::Library;
 use crate::models::chapter::Chapter;
+use crate::worker::virtual_file;
 use crate::schema::{audiobooks, playstates, library_permissions};
 
 #[table_name="audiobooks"]
 #[derive(PartialEq, Debug, Queryable, AsChangeset, Associations, Identifiable, Serialize, Clone,
          Insertable)]
 #[belongs_to(Library)]
+#[changeset_options(treat_none_as_null = "true")]
 pub struct Audiobook {
     pub id: Uuid,
     pub location: String,
@@ -24,7 +33,109 @@ pub struct Audiobook {
     pub library_id: Uuid,
     pub hash: Vec<u8>,
     pub file_extension: String,
-    pub deleted: bool
+    pub deleted: bool,
+    pub narrator: Option<String>,
+    pub series: Option<String>,
+    /// Position within the series, fractional for novellas between two books
+    pub series_index: Option<f64>,
+    pub description: Option<String>,
+    pub publisher: Option<String>,
+    pub year: Option<i32>,
+    pub genres: Genres,
+    pub language: Option<String>,
+    pub isbn: Option<String>,
+    pub asin: Option<String>,
+    /// Where the cover came from, `COVER_EMBEDDED` or the path of an image within the library
+    pub cover_source: Option<String>,
+    pub palette: Option<Palette>,
+}
+
+/// `cover_source` of books whose cover is the picture embedded in their audio
+pub const COVER_EMBEDDED: &str = "embedded";
+/// `cover_source` of books whose cover was uploaded by an admin
+pub const COVER_UPLOADED: &str = "uploaded";
+
+/// Genres of a book, stored in a single text column separated by semicolons.
+#[derive(PartialEq, Debug, Clone, Default, Serialize, Deserialize, AsExpression, FromSqlRow)]
+#[sql_type = "Text"]
+pub struct Genres(pub Vec<String>);
+
+impl Genres {
+    /// Genre tags often hold several genres separated by semicolons, commas or slashes.
+    pub fn from_tag(tag: &str) -> Self {
+        let mut genres: Vec<String> = Vec::new();
+        for genre in tag.split(|c| c == ';' || c == ',' || c == '/').map(str::trim) {
+            if !genre.is_empty() && !genres.iter().any(|g| g.eq_ignore_ascii_case(genre)) {
+                genres.push(genre.to_owned());
+            }
+        }
+        Genres(genres)
+    }
+}
+
+impl ToSql<Text, Sqlite> for Genres {
+    fn to_sql<W: Write>(&self, out: &mut serialize::Output<W, Sqlite>) -> serialize::Result {
+        ToSql::<Text, Sqlite>::to_sql(&self.0.join(";"), out)
+    }
+}
+
+impl FromSql<Text, Sqlite> for Genres {
+    fn from_sql(value: Option<&<Sqlite as Backend>::RawValue>) -> deserialize::Result<Self> {
+        let text: String = FromSql::<Text, Sqlite>::from_sql(value)?;
+        Ok(Genres(text.split(';').filter(|g| !g.is_empty()).map(str::to_owned).collect()))
+    }
+}
+
+/// Colours of the cover for clients to theme their player with, as `#rrggbb`, and a
+/// [blurhash](https://blurha.sh) to show while the cover loads. Stored as JSON.
+#[derive(PartialEq, Debug, Clone, Serialize, Deserialize, AsExpression, FromSqlRow)]
+#[sql_type = "Text"]
+pub struct Palette {
+    pub dominant: String,
+    pub vibrant: String,
+    pub muted: String,
+    pub blurhash: String,
+}
+
+impl ToSql<Text, Sqlite> for Palette {
+    fn to_sql<W: Write>(&self, out: &mut serialize::Output<W, Sqlite>) -> serialize::Result {
+        ToSql::<Text, Sqlite>::to_sql(&serde_json::to_string(self)?, out)
+    }
+}
+
+impl FromSql<Text, Sqlite> for Palette {
+    fn from_sql(value: Option<&<Sqlite as Backend>::RawValue>) -> deserialize::Result<Self> {
+        let text: String = FromSql::<Text, Sqlite>::from_sql(value)?;
+        Ok(serde_json::from_str(&text)?)
+    }
+}
+
+/// Narrows down a list of books, text fields match case insensitively on any part of the value.
+#[derive(Debug, Default, FromForm)]
+pub struct AudiobookFilter {
+    pub author: Option<String>,
+    pub narrator: Option<String>,
+    pub series: Option<String>,
+    pub publisher: Option<String>,
+    pub genre: Option<String>,
+    pub year: Option<i32>,
+    pub language: Option<String>,
+}
+
+impl AudiobookFilter {
+    /// Matches the filters for values an admin can override, these aren't queried in SQL.
+    pub fn matches_overridable(&self, book: &Audiobook) -> bool {
+        fn contains(value: &Option<String>, part: &Option<String>) -> bool {
+            match (value, part) {
+                (_, &None) => true,
+                (&Some(ref value), &Some(ref part)) => value.to_lowercase().contains(&part.to_lowercase()),
+                (&None, &Some(_)) => false,
+            }
+        }
+        contains(&book.artist, &self.author)
+            && contains(&book.narrator, &self.narrator)
+            && contains(&book.series, &self.series)
+    }
 }
 
 pub enum Update {
@@ -54,6 +165,16 @@ impl Audiobook {
         }
     }
 
+    /// Where the scanner keeps the playable copy of the book. Multifile books of libraries that
+    /// don't remux have a manifest of their files instead, see `worker::virtual_file`.
+    pub fn data_file(&self, data_directory: &str) -> PathBuf {
+        let mut path = PathBuf::from(data_directory);
+        path.push(self.id.hyphenated().to_string());
+        path.set_extension(&self.file_extension);
+        let manifest = virtual_file::manifest_path(&path);
+        if manifest.exists() { manifest } else { path }
+    }
+
     pub fn delete_all_chapters(&self, conn: &diesel::sqlite::SqliteConnection) -> diesel::result::QueryResult<usize> {
         diesel::delete(Chapter::belonging_to(self)).execute(&*conn)
     }
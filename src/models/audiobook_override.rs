@@ -0,0 +1,326 @@
+use diesel;
+use diesel::prelude::*;
+use std::collections::HashMap;
+use std::io::Write;
+use diesel::backend::Backend;
+use diesel::deserialize::{self, FromSql};
+use diesel::serialize::{self, ToSql};
+use diesel::sql_types::Text;
+use diesel::sqlite::{Sqlite, SqliteConnection};
+use serde::{Deserialize, Deserializer};
+use serde_json;
+use crate::helpers::uuid::Uuid;
+
+use crate::models::audiobook::{Audiobook, Palette};
+use crate::models::chapter::Chapter;
+use crate::schema::audiobook_overrides;
+
+/// Metadata of a book set by an admin. The scanner never writes these, they are merged over the
+/// scanned values whenever a book is read. `None` keeps the scanned value.
+#[table_name="audiobook_overrides"]
+#[derive(PartialEq, Debug, Clone, Queryable, AsChangeset, Associations, Identifiable, Serialize,
+         Insertable)]
+#[belongs_to(Audiobook)]
+#[primary_key(audiobook_id)]
+#[changeset_options(treat_none_as_null = "true")]
+pub struct AudiobookOverride {
+    pub audiobook_id: Uuid,
+    pub title: Option<String>,
+    pub artist: Option<String>,
+    pub narrator: Option<String>,
+    pub series: Option<String>,
+    pub series_index: Option<f64>,
+    pub description: Option<String>,
+    pub chapters: Option<ChapterList>,
+    /// `COVER_UPLOADED` if an admin uploaded a cover, it is kept apart from the scanned one
+    pub cover_source: Option<String>,
+    /// Colours of the uploaded cover
+    pub palette: Option<Palette>,
+}
+
+/// Chapters replacing those of the scanner, stored as JSON in a single text column.
+#[derive(PartialEq, Debug, Clone, Default, Serialize, Deserialize, AsExpression, FromSqlRow)]
+#[sql_type = "Text"]
+pub struct ChapterList(pub Vec<ChapterOverride>);
+
+#[derive(PartialEq, Debug, Clone, Serialize, Deserialize)]
+pub struct ChapterOverride {
+    pub id: Uuid,
+    pub title: Option<String>,
+    pub start_time: f64,
+}
+
+impl ToSql<Text, Sqlite> for ChapterList {
+    fn to_sql<W: Write>(&self, out: &mut serialize::Output<W, Sqlite>) -> serialize::Result {
+        ToSql::<Text, Sqlite>::to_sql(&serde_json::to_string(&self.0)?, out)
+    }
+}
+
+impl FromSql<Text, Sqlite> for ChapterList {
+    fn from_sql(value: Option<&<Sqlite as Backend>::RawValue>) -> deserialize::Result<Self> {
+        let text: String = FromSql::<Text, Sqlite>::from_sql(value)?;
+        Ok(ChapterList(serde_json::from_str(&text)?))
+    }
+}
+
+impl ChapterList {
+    pub fn from_chapters(chapters: &[Chapter]) -> Self {
+        ChapterList(chapters.iter().map(|c| ChapterOverride {
+            id: c.id,
+            title: c.title.clone(),
+            start_time: c.start_time,
+        }).collect())
+    }
+
+    /// Chapters need to start in order and before the end of the book.
+    pub fn validate(&self, length: f64) -> Result<(), String> {
+        let mut previous: Option<f64> = None;
+        for chapter in &self.0 {
+            if !(chapter.start_time >= 0.0 && chapter.start_time < length) {
+                return Err(format!("Chapter {:?} starts outside of the book", chapter.title));
+            }
+            if let Some(p) = previous {
+                if chapter.start_time <= p {
+                    return Err(format!("Chapter {:?} doesn't start after the previous one", chapter.title));
+                }
+            }
+            previous = Some(chapter.start_time);
+        }
+        Ok(())
+    }
+
+    /// Drop chapters starting at or after `length`. They are left over when a rescan found a
+    /// shorter file than the one the chapters were made for.
+    pub fn fit_to(&mut self, length: f64) {
+        self.0.retain(|c| c.start_time < length);
+    }
+
+    /// Insert a chapter in front of the first one starting later.
+    pub fn add(&mut self, title: Option<String>, start_time: f64) -> Uuid {
+        let id = Uuid::new_v4();
+        let position = self.0.iter().position(|c| c.start_time > start_time).unwrap_or(self.0.len());
+        self.0.insert(position, ChapterOverride { id, title, start_time });
+        id
+    }
+
+    pub fn get_mut(&mut self, id: &Uuid) -> Option<&mut ChapterOverride> {
+        self.0.iter_mut().find(|c| c.id == *id)
+    }
+
+    /// Returns false if there is no chapter with this id.
+    pub fn remove(&mut self, id: &Uuid) -> bool {
+        let before = self.0.len();
+        self.0.retain(|c| c.id != *id);
+        self.0.len() != before
+    }
+
+    pub fn to_chapters(&self, book_id: Uuid) -> Vec<Chapter> {
+        self.0.iter().enumerate().map(|(i, c)| Chapter {
+            id: c.id,
+            title: c.title.clone(),
+            audiobook_id: book_id,
+            start_time: c.start_time,
+            number: i as i64,
+            generated: false,
+        }).collect()
+    }
+}
+
+impl AudiobookOverride {
+    pub fn new(book_id: Uuid) -> Self {
+        AudiobookOverride {
+            audiobook_id: book_id,
+            title: None,
+            artist: None,
+            narrator: None,
+            series: None,
+            series_index: None,
+            description: None,
+            chapters: None,
+            cover_source: None,
+            palette: None,
+        }
+    }
+
+    pub fn find(book_id: &Uuid, conn: &SqliteConnection) -> QueryResult<Option<Self>> {
+        audiobook_overrides::table
+            .filter(audiobook_overrides::dsl::audiobook_id.eq(book_id))
+            .first::<Self>(conn)
+            .optional()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        *self == Self::new(self.audiobook_id)
+    }
+
+    /// Store the overrides, a row without any values left is removed.
+    pub fn save(&self, conn: &SqliteConnection) -> QueryResult<usize> {
+        if self.is_empty() {
+            diesel::delete(audiobook_overrides::table
+                .filter(audiobook_overrides::dsl::audiobook_id.eq(&self.audiobook_id)))
+                .execute(conn)
+        } else {
+            diesel::replace_into(audiobook_overrides::table).values(self).execute(conn)
+        }
+    }
+
+    pub fn apply_to(&self, book: &mut Audiobook) {
+        if let Some(ref title) = self.title {
+            book.title = title.clone();
+        }
+        if self.artist.is_some() {
+            book.artist = self.artist.clone();
+        }
+        if self.narrator.is_some() {
+            book.narrator = self.narrator.clone();
+        }
+        if self.series.is_some() {
+            book.series = self.series.clone();
+            book.series_index = self.series_index;
+        } else if self.series_index.is_some() {
+            book.series_index = self.series_index;
+        }
+        if self.description.is_some() {
+            book.description = self.description.clone();
+        }
+        if self.cover_source.is_some() {
+            book.cover_source = self.cover_source.clone();
+            book.palette = self.palette.clone();
+        }
+    }
+
+    /// The book with its overrides, if it has any.
+    pub fn merged(mut book: Audiobook, conn: &SqliteConnection) -> QueryResult<Audiobook> {
+        if let Some(overrides) = Self::find(&book.id, conn)? {
+            overrides.apply_to(&mut book);
+        }
+        Ok(book)
+    }
+
+    pub fn merge_all(books: Vec<Audiobook>, conn: &SqliteConnection) -> QueryResult<Vec<Audiobook>> {
+        let overrides: HashMap<Uuid, Self> = audiobook_overrides::table.load::<Self>(conn)?
+            .into_iter()
+            .map(|o| (o.audiobook_id, o))
+            .collect();
+        Ok(books.into_iter().map(|mut book| {
+            if let Some(o) = overrides.get(&book.id) {
+                o.apply_to(&mut book);
+            }
+            book
+        }).collect())
+    }
+
+    /// Chapters of a book as shown to users, the overridden ones if there are any that still fit
+    /// the book.
+    pub fn chapters_of(book: &Audiobook, conn: &SqliteConnection) -> QueryResult<Vec<Chapter>> {
+        use crate::schema::chapters::dsl::number;
+        match Self::chapter_list_of(book, conn)? {
+            Some(list) => Ok(list.to_chapters(book.id)),
+            None => Chapter::belonging_to(book).order(number.asc()).load::<Chapter>(conn)
+        }
+    }
+
+    /// The overridden chapters of a book without those that don't fit its length anymore, `None`
+    /// if none are left.
+    pub fn chapter_list_of(book: &Audiobook, conn: &SqliteConnection) -> QueryResult<Option<ChapterList>> {
+        Ok(Self::find(&book.id, conn)?.and_then(|o| o.chapters).and_then(|mut list| {
+            list.fit_to(book.length);
+            if list.0.is_empty() { None } else { Some(list) }
+        }))
+    }
+}
+
+/// Body of `PATCH /audiobooks/<id>`. Fields left out stay as they are, `null` resets a field to
+/// the value found by the scanner.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AudiobookPatch {
+    #[serde(default, deserialize_with = "present")]
+    pub title: Option<Option<String>>,
+    #[serde(default, deserialize_with = "present")]
+    pub author: Option<Option<String>>,
+    #[serde(default, deserialize_with = "present")]
+    pub narrator: Option<Option<String>>,
+    #[serde(default, deserialize_with = "present")]
+    pub series: Option<Option<String>>,
+    #[serde(default, deserialize_with = "present")]
+    pub series_index: Option<Option<f64>>,
+    #[serde(default, deserialize_with = "present")]
+    pub description: Option<Option<String>>,
+    #[serde(default, deserialize_with = "present")]
+    pub chapters: Option<Option<Vec<ChapterPatch>>>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ChapterPatch {
+    pub title: Option<String>,
+    pub start_time: f64,
+}
+
+/// Body of `PATCH /audiobooks/<id>/chapters/<chapter_id>`, a new title renames a chapter and a
+/// new start time moves it. Moving a chapter past one of its neighbours is refused.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ChapterEdit {
+    #[serde(default, deserialize_with = "present")]
+    pub title: Option<Option<String>>,
+    #[serde(default)]
+    pub start_time: Option<f64>,
+}
+
+/// Tells an explicit `null` apart from a missing field, which serde's `default` turns into `None`.
+pub fn present<'de, T, D>(deserializer: D) -> Result<Option<T>, D::Error>
+    where T: Deserialize<'de>, D: Deserializer<'de> {
+    T::deserialize(deserializer).map(Some)
+}
+
+impl ChapterEdit {
+    pub fn apply_to(self, chapter: &mut ChapterOverride) {
+        if let Some(title) = self.title {
+            chapter.title = title;
+        }
+        if let Some(start_time) = self.start_time {
+            chapter.start_time = start_time;
+        }
+    }
+}
+
+impl AudiobookPatch {
+    pub fn apply_to(self, overrides: &mut AudiobookOverride, length: f64) -> Result<(), String> {
+        if let Some(Some(ref title)) = self.title {
+            if title.trim().is_empty() {
+                return Err("The title must not be empty".to_owned());
+            }
+        }
+        if let Some(title) = self.title {
+            overrides.title = title;
+        }
+        if let Some(author) = self.author {
+            overrides.artist = author;
+        }
+        if let Some(narrator) = self.narrator {
+            overrides.narrator = narrator;
+        }
+        if let Some(series) = self.series {
+            overrides.series = series;
+        }
+        if let Some(series_index) = self.series_index {
+            overrides.series_index = series_index;
+        }
+        if let Some(description) = self.description {
+            overrides.description = description;
+        }
+        if let Some(chapters) = self.chapters {
+            let list = chapters.map(|chapters| ChapterList(chapters.into_iter().map(|c| ChapterOverride {
+                id: Uuid::new_v4(),
+                title: c.title,
+                start_time: c.start_time,
+            }).collect()));
+            if let Some(ref list) = list {
+                list.validate(length)?;
+            }
+            overrides.chapters = list;
+        }
+        Ok(())
+    }
+}
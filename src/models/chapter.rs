@@ -11,5 +11,7 @@ pub struct Chapter {
     pub title: Option<String>,
     pub audiobook_id: Uuid,
     pub start_time: f64,
-    pub number: i64
+    pub number: i64,
+    /// Set for chapters made up by silence detection rather than read from the book
+    pub generated: bool,
 }
@@ -1,8 +1,16 @@
 use crate::helpers::uuid::Uuid;
 use chrono::NaiveDateTime;
 use std::time::SystemTime;
+use std::fmt;
+use std::io::Write;
+use std::str::FromStr;
 use diesel;
 use diesel::prelude::*;
+use diesel::backend::Backend;
+use diesel::deserialize::{self, FromSql};
+use diesel::serialize::{self, ToSql};
+use diesel::sql_types::Text;
+use diesel::sqlite::Sqlite;
 use crate::schema::{libraries, audiobooks, library_permissions, self};
 use crate::models::audiobook::Audiobook;
 use crate::models::library_permission::LibraryPermission;
@@ -19,7 +27,124 @@ pub struct Library {
     #[serde(skip_serializing)]
     pub is_audiobook_regex: String,
     #[serde(skip_serializing)]
-    pub last_scan: Option<NaiveDateTime>
+    pub last_scan: Option<NaiveDateTime>,
+    #[serde(skip_serializing)]
+    pub chapter_strategy: ChapterStrategy,
+    /// Regex used by the `MergeRegex` and `Filename` chapter strategies
+    #[serde(skip_serializing)]
+    pub chapter_regex: Option<String>,
+    #[serde(skip_serializing)]
+    pub cover_preference: CoverPreference,
+    #[serde(skip_serializing)]
+    pub multifile_storage: MultifileStorage,
+}
+
+/// Settings of a library stored by name, e.g. `merge_titles`. Generates the enum along with its
+/// conversions from and to the names used in the database and on the command line.
+macro_rules! setting_enum {
+    (
+        $(#[$meta:meta])*
+        pub enum $name:ident ($description:expr, error $error:ident, default $default:ident) {
+            $( $(#[$variant_meta:meta])* $variant:ident => $text:expr, )+
+        }
+    ) => {
+        $(#[$meta])*
+        #[derive(Debug, Clone, Copy, PartialEq, Eq, AsExpression, FromSqlRow)]
+        #[sql_type = "Text"]
+        pub enum $name {
+            $( $(#[$variant_meta])* $variant, )+
+        }
+
+        impl Default for $name {
+            fn default() -> Self {
+                $name::$default
+            }
+        }
+
+        impl $name {
+            pub const NAMES: &'static [&'static str] = &[$($text),+];
+
+            pub fn as_str(&self) -> &'static str {
+                match *self {
+                    $( $name::$variant => $text, )+
+                }
+            }
+        }
+
+        impl fmt::Display for $name {
+            fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                write!(f, "{}", self.as_str())
+            }
+        }
+
+        #[derive(Debug, Fail)]
+        pub struct $error(String);
+
+        impl fmt::Display for $error {
+            fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                write!(f, "Unknown {} {}, use one of {}", $description, self.0, $name::NAMES.join(", "))
+            }
+        }
+
+        impl FromStr for $name {
+            type Err = $error;
+
+            fn from_str(s: &str) -> Result<Self, Self::Err> {
+                $( if s == $text { return Ok($name::$variant); } )+
+                Err($error(s.to_owned()))
+            }
+        }
+
+        impl ToSql<Text, Sqlite> for $name {
+            fn to_sql<W: Write>(&self, out: &mut serialize::Output<W, Sqlite>) -> serialize::Result {
+                ToSql::<Text, Sqlite>::to_sql(self.as_str(), out)
+            }
+        }
+
+        impl FromSql<Text, Sqlite> for $name {
+            fn from_sql(value: Option<&<Sqlite as Backend>::RawValue>) -> deserialize::Result<Self> {
+                let text: String = FromSql::<Text, Sqlite>::from_sql(value)?;
+                text.parse().map_err(|e: $error| e.to_string().into())
+            }
+        }
+    };
+}
+
+setting_enum! {
+    /// How the scanner turns the files of a multifile book into chapters.
+    pub enum ChapterStrategy ("chapter strategy", error UnknownChapterStrategy, default MergeTitles) {
+        /// One chapter per file, titled by the file's title tag.
+        PerFile => "per_file",
+        /// Consecutive files with equal title tags form one chapter.
+        MergeTitles => "merge_titles",
+        /// Consecutive files whose names produce the same match of the chapter regex form one
+        /// chapter. If the regex has a capture group, only the first group is compared.
+        MergeRegex => "merge_regex",
+        /// One chapter per file, titled by the file name with all matches of the chapter regex
+        /// removed.
+        Filename => "filename",
+    }
+}
+
+setting_enum! {
+    /// Which cover the scanner picks when a book has both an embedded picture and an image file.
+    pub enum CoverPreference ("cover preference", error UnknownCoverPreference, default Embedded) {
+        /// The picture embedded in the audio, images like `cover.jpg` are only used for books without.
+        Embedded => "embedded",
+        /// Images like `cover.jpg`, the embedded picture is only used for books without.
+        Folder => "folder",
+    }
+}
+
+setting_enum! {
+    /// How the scanner stores multifile books in the data directory.
+    pub enum MultifileStorage ("multifile storage", error UnknownMultifileStorage, default Remux) {
+        /// The files are remuxed into one file.
+        Remux => "remux",
+        /// The files are served as one stream straight from the library, only a manifest of them is
+        /// stored. Books in formats that can't be concatenated are remuxed anyway.
+        Virtual => "virtual",
+    }
 }
 
 impl Library {
@@ -30,7 +155,11 @@ impl Library {
                 id: Uuid::new_v4(),
                 location,
                 is_audiobook_regex: audiobook_regex,
-                last_scan: None
+                last_scan: None,
+                chapter_strategy: ChapterStrategy::default(),
+                chapter_regex: None,
+                cover_preference: CoverPreference::default(),
+                multifile_storage: MultifileStorage::default(),
             };
             diesel::insert_into(libraries::table)
                 .values(&lib).execute(&*db)?;
@@ -42,4 +171,13 @@ impl Library {
             Ok(lib)
         })
     }
+
+    /// Store changed settings. Changes of the chapter rules take effect on the next full scan,
+    /// those of the cover preference and multifile storage when books are scanned again.
+    pub fn save(&self, db: &db::Connection) -> Result<(), diesel::result::Error> {
+        diesel::update(libraries::table.filter(libraries::dsl::id.eq(&self.id)))
+            .set(self)
+            .execute(&*db)?;
+        Ok(())
+    }
 }
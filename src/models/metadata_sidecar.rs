@@ -0,0 +1,37 @@
+use crate::helpers::uuid::Uuid;
+use diesel;
+use diesel::prelude::*;
+use diesel::sqlite::SqliteConnection;
+use crate::models::audiobook::Audiobook;
+use crate::schema::metadata_sidecars;
+
+/// A metadata sidecar the values of a single file book were last read from. The scanner compares
+/// these with the sidecars next to the book to notice removed ones.
+#[table_name="metadata_sidecars"]
+#[derive(Debug, Clone, Queryable, Associations, Identifiable, Insertable)]
+#[belongs_to(Audiobook)]
+#[primary_key(audiobook_id, file_name)]
+pub struct MetadataSidecar {
+    pub audiobook_id: Uuid,
+    pub file_name: String,
+}
+
+impl MetadataSidecar {
+    /// File names of the sidecars a book was last read with, sorted.
+    pub fn file_names_of(book: &Audiobook, conn: &SqliteConnection) -> Result<Vec<String>, diesel::result::Error> {
+        MetadataSidecar::belonging_to(book)
+            .select(metadata_sidecars::dsl::file_name)
+            .order(metadata_sidecars::dsl::file_name.asc())
+            .load(conn)
+    }
+
+    pub fn replace_for(book: &Audiobook, file_names: &[String], conn: &SqliteConnection)
+        -> Result<usize, diesel::result::Error> {
+        diesel::delete(MetadataSidecar::belonging_to(book)).execute(&*conn)?;
+        let rows: Vec<MetadataSidecar> = file_names.iter().map(|name| MetadataSidecar {
+            audiobook_id: book.id,
+            file_name: name.clone(),
+        }).collect();
+        diesel::insert_into(metadata_sidecars::table).values(&rows).execute(&*conn)
+    }
+}
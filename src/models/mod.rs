@@ -1,8 +1,12 @@
 pub mod user;
+pub mod user_limits;
 pub mod audiobook;
+pub mod audiobook_override;
 pub mod chapter;
 pub mod library;
 pub mod library_permission;
 pub mod playstate;
+pub mod scan_warning;
+pub mod metadata_sidecar;
 #[cfg(test)]
 pub mod tests;
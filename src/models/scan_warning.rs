@@ -0,0 +1,38 @@
+use crate::helpers::uuid::Uuid;
+use chrono::NaiveDateTime;
+use chrono::prelude::*;
+use diesel;
+use diesel::prelude::*;
+use diesel::sqlite::SqliteConnection;
+use crate::models::audiobook::Audiobook;
+use crate::schema::scan_warnings;
+
+/// Something noteworthy the scanner encountered while processing a book.
+/// Warnings are replaced whenever a book is rescanned.
+#[table_name="scan_warnings"]
+#[derive(Debug, Clone, Queryable, Associations, Identifiable, Serialize, Insertable)]
+#[belongs_to(Audiobook)]
+pub struct ScanWarning {
+    pub id: Uuid,
+    pub audiobook_id: Uuid,
+    pub message: String,
+    pub created_at: NaiveDateTime,
+}
+
+impl ScanWarning {
+    pub fn new(book: &Audiobook, message: String) -> Self {
+        Self {
+            id: Uuid::new_v4(),
+            audiobook_id: book.id,
+            message,
+            created_at: Utc::now().naive_utc(),
+        }
+    }
+
+    /// Replace all warnings of a book with the given ones.
+    pub fn replace_for(book: &Audiobook, warnings: &[ScanWarning], conn: &SqliteConnection)
+        -> Result<usize, diesel::result::Error> {
+        diesel::delete(ScanWarning::belonging_to(book)).execute(&*conn)?;
+        diesel::insert_into(scan_warnings::table).values(warnings).execute(&*conn)
+    }
+}
@@ -4,9 +4,9 @@ use diesel::prelude::*;
 use crate::helpers::db::init_test_db_pool;
 use crate::*;
 use crate::models::user::{NewUser, User};
-use crate::models::library::Library;
+use crate::models::library::{Library, ChapterStrategy, CoverPreference, MultifileStorage};
 use crate::models::library_permission::LibraryPermission;
-use crate::models::audiobook::Audiobook;
+use crate::models::audiobook::{Audiobook, Genres};
 use crate::helpers::uuid::Uuid;
 
 speculate! {
@@ -25,6 +25,10 @@ speculate! {
                 location: "/foo/bar".to_string(),
                 is_audiobook_regex: ".*".to_string(),
                 last_scan: None,
+                chapter_strategy: ChapterStrategy::default(),
+                chapter_regex: None,
+                cover_preference: CoverPreference::default(),
+                multifile_storage: MultifileStorage::default(),
             };
             diesel::insert_into(schema::libraries::table)
                 .values(&accessible_lib).execute(&*db).unwrap();
@@ -34,6 +38,10 @@ speculate! {
                 location: "/foo/baz".to_string(),
                 is_audiobook_regex: ".*".to_string(),
                 last_scan: None,
+                chapter_strategy: ChapterStrategy::default(),
+                chapter_regex: None,
+                cover_preference: CoverPreference::default(),
+                multifile_storage: MultifileStorage::default(),
             };
             diesel::insert_into(schema::libraries::table)
                 .values(&inaccessible_lib).execute(&*db).unwrap();
@@ -54,6 +62,18 @@ speculate! {
                     hash: vec![1, 2, 3],
                     file_extension: ".mp3".to_owned(),
                     deleted: false,
+                    narrator: None,
+                    series: None,
+                    series_index: None,
+                    description: None,
+                    publisher: None,
+                    year: None,
+                    genres: Genres::default(),
+                    language: None,
+                    isbn: None,
+                    asin: None,
+                    cover_source: None,
+                    palette: None,
                 },
                 Audiobook {
                     id: Uuid::new_v4(),
@@ -65,6 +85,18 @@ speculate! {
                     hash: vec![3, 4, 5],
                     file_extension: ".mp3".to_owned(),
                     deleted: false,
+                    narrator: None,
+                    series: None,
+                    series_index: None,
+                    description: None,
+                    publisher: None,
+                    year: None,
+                    genres: Genres::default(),
+                    language: None,
+                    isbn: None,
+                    asin: None,
+                    cover_source: None,
+                    palette: None,
                 },
             ];
 
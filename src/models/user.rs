@@ -6,7 +6,8 @@ use argon2rs::{verifier, Argon2};
 use diesel::sqlite::SqliteConnection;
 use diesel::prelude::*;
 use diesel::expression::exists;
-use crate::models::audiobook::Audiobook;
+use crate::models::audiobook::{Audiobook, AudiobookFilter};
+use crate::models::audiobook_override::AudiobookOverride;
 use crate::models::library::Library;
 use crate::models::library_permission::LibraryPermission;
 use std::result::Result as StdResult;
@@ -29,6 +30,8 @@ pub struct User {
     pub email: String,
     #[serde(skip_serializing)]
     pub password_hash: String,
+    /// Admins may edit the metadata of books
+    pub admin: bool,
 }
 
 type Result<T> = StdResult<T, Error>;
@@ -74,21 +77,43 @@ impl User {
 
     pub fn accessible_audiobooks(&self, conn: &SqliteConnection)
                 -> QueryResult<Vec<Audiobook>> {
-        use diesel::expression::sql_literal::*;
-        use diesel::sql_types::*;
+        self.filter_accessible_audiobooks(&AudiobookFilter::default(), conn)
+    }
+
+    pub fn filter_accessible_audiobooks(&self, filter: &AudiobookFilter, conn: &SqliteConnection)
+                -> QueryResult<Vec<Audiobook>> {
         use crate::schema::library_permissions::dsl::{library_permissions, user_id as library_permissions_user_id};
-        use crate::schema::libraries::dsl::{libraries, id};
-        use crate::schema::audiobooks::dsl::{audiobooks, library_id, location, deleted};
+        use crate::schema::libraries::dsl::libraries;
+        use crate::schema::audiobooks::dsl::*;
         use crate::schema::audiobooks::all_columns;
-        use crate::schema::users::dsl::users;
 
-        audiobooks.inner_join(
+        // `%` and `_` in a filter are matched literally
+        let contains = |value: &str| format!(
+            "%{}%",
+            value.replace('\\', "\\\\").replace('%', "\\%").replace('_', "\\_")
+        );
+        let mut query = audiobooks.inner_join(
             libraries.inner_join(library_permissions))
             .filter(deleted.eq(false))
             .filter(library_permissions_user_id.eq(&self.id))
             .select(all_columns)
             .order(location.asc())
-            .get_results::<Audiobook>(&*conn)
+            .into_boxed();
+        if let Some(ref value) = filter.publisher {
+            query = query.filter(publisher.like(contains(value)).escape('\\'));
+        }
+        if let Some(ref value) = filter.genre {
+            query = query.filter(genres.like(contains(value)).escape('\\'));
+        }
+        if let Some(value) = filter.year {
+            query = query.filter(year.eq(value));
+        }
+        if let Some(ref value) = filter.language {
+            query = query.filter(language.like(contains(value)).escape('\\'));
+        }
+        // author, narrator and series can be overridden, so they are matched on the merged books
+        let books = AudiobookOverride::merge_all(query.get_results::<Audiobook>(&*conn)?, conn)?;
+        Ok(books.into_iter().filter(|book| filter.matches_overridable(book)).collect())
     }
 
     pub fn create(email: &dyn AsRef<str>, password: &dyn AsRef<str>, conn: &SqliteConnection) -> Result<User> {
@@ -110,6 +135,7 @@ impl User {
                 updated_at: Utc::now().naive_utc(),
                 email: email.as_ref().to_owned(),
                 password_hash: new_password_hash,
+                admin: false,
             };
             diesel::insert_into(users::table).values(&user).execute(&*conn)?;
             let libraries: Vec<Library> = schema::libraries::table.load(&*conn)?;
@@ -121,6 +147,13 @@ impl User {
         })
     }
 
+    pub fn set_admin(&self, admin: bool, conn: &SqliteConnection) -> QueryResult<usize> {
+        use crate::schema::users::dsl;
+        diesel::update(dsl::users.filter(dsl::id.eq(&self.id)))
+            .set(dsl::admin.eq(admin))
+            .execute(conn)
+    }
+
     pub fn verify_password(&self, candidate_password: &str) -> bool {
         let data = base64::decode(&self.password_hash).expect("Malformed hash");
         let session = verifier::Encoded::from_u8(
@@ -155,7 +188,7 @@ impl User {
         }
     }
 
-    pub fn get_book_if_accessible(self, book_id: &Uuid, conn: &SqliteConnection) -> QueryResult<Option<Audiobook>> {
+    pub fn get_book_if_accessible(&self, book_id: &Uuid, conn: &SqliteConnection) -> QueryResult<Option<Audiobook>> {
         use diesel::expression::sql_literal::*;
         use diesel::sql_types::*;
         use crate::schema::library_permissions::dsl::{library_permissions, user_id as library_permissions_user_id};
@@ -169,10 +202,16 @@ impl User {
             .filter(library_permissions_user_id.eq(self.id))
             .filter(audiobook_id.eq(book_id))
             .select(all_columns)
-            .get_result::<Audiobook>(&*conn).optional()?)
+            .get_result::<Audiobook>(&*conn).optional()?
+            .map(|book| AudiobookOverride::merged(book, conn))
+            .transpose()?)
     }
 }
 
+/// A user allowed to change books, see `User::admin`.
+#[derive(Debug)]
+pub struct Admin(pub User);
+
 #[derive(Insertable)]
 #[table_name="users"]
 pub struct NewUser {
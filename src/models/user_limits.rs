@@ -0,0 +1,105 @@
+use diesel;
+use diesel::prelude::*;
+use diesel::sqlite::SqliteConnection;
+use crate::helpers::uuid::Uuid;
+
+use crate::config::StreamingConfig;
+use crate::models::audiobook_override::present;
+use crate::models::user::User;
+use crate::schema::user_limits;
+
+/// Streaming limits of a user set by an admin. `None` keeps the configured limit, 0 lifts it.
+#[table_name="user_limits"]
+#[derive(PartialEq, Debug, Clone, Queryable, AsChangeset, Associations, Identifiable, Serialize,
+         Insertable)]
+#[belongs_to(User)]
+#[primary_key(user_id)]
+#[changeset_options(treat_none_as_null = "true")]
+pub struct UserLimits {
+    pub user_id: Uuid,
+    /// How many streams the user may have open at the same time
+    pub max_streams: Option<i32>,
+    /// Bandwidth in kbit/s the streams of the user share
+    pub bandwidth: Option<i32>,
+}
+
+/// The limits a user streams with, 0 meaning no limit.
+#[derive(PartialEq, Debug, Clone, Copy, Serialize)]
+pub struct Limits {
+    pub max_streams: usize,
+    /// kbit/s
+    pub bandwidth: u64,
+}
+
+/// Body of `PATCH /users/<id>/limits`, `null` brings back the configured limit.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LimitsPatch {
+    #[serde(default, deserialize_with = "present")]
+    pub max_streams: Option<Option<i32>>,
+    #[serde(default, deserialize_with = "present")]
+    pub bandwidth: Option<Option<i32>>,
+}
+
+impl UserLimits {
+    pub fn new(user_id: Uuid) -> Self {
+        UserLimits {
+            user_id,
+            max_streams: None,
+            bandwidth: None,
+        }
+    }
+
+    pub fn find(user_id: &Uuid, conn: &SqliteConnection) -> QueryResult<Option<Self>> {
+        user_limits::table
+            .filter(user_limits::dsl::user_id.eq(user_id))
+            .first::<Self>(conn)
+            .optional()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        *self == Self::new(self.user_id)
+    }
+
+    /// Store the limits, a row without any values left is removed.
+    pub fn save(&self, conn: &SqliteConnection) -> QueryResult<usize> {
+        if self.is_empty() {
+            diesel::delete(user_limits::table
+                .filter(user_limits::dsl::user_id.eq(&self.user_id)))
+                .execute(conn)
+        } else {
+            diesel::replace_into(user_limits::table).values(self).execute(conn)
+        }
+    }
+
+    /// The configured limits with these applied.
+    pub fn apply_to(&self, config: &StreamingConfig) -> Limits {
+        Limits {
+            max_streams: self.max_streams.map(|m| m as usize).unwrap_or(config.max_streams_per_user),
+            bandwidth: self.bandwidth.map(|b| b as u64).unwrap_or(config.user_bandwidth),
+        }
+    }
+
+    /// The limits a user streams with.
+    pub fn of(user: &User, config: &StreamingConfig, conn: &SqliteConnection) -> QueryResult<Limits> {
+        Ok(Self::find(&user.id, conn)?
+            .unwrap_or_else(|| Self::new(user.id))
+            .apply_to(config))
+    }
+}
+
+impl LimitsPatch {
+    pub fn apply_to(self, limits: &mut UserLimits) -> Result<(), String> {
+        let negative = |value: Option<Option<i32>>| value.and_then(|v| v).map_or(false, |v| v < 0);
+        if negative(self.max_streams) || negative(self.bandwidth) {
+            return Err("Limits must not be negative".to_owned());
+        }
+        if let Some(max_streams) = self.max_streams {
+            limits.max_streams = max_streams;
+        }
+        if let Some(bandwidth) = self.bandwidth {
+            limits.bandwidth = bandwidth;
+        }
+        Ok(())
+    }
+}
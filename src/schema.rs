@@ -6,6 +6,21 @@ table! {
     }
 }
 
+table! {
+    audiobook_overrides (audiobook_id) {
+        audiobook_id -> Text,
+        title -> Nullable<Varchar>,
+        artist -> Nullable<Varchar>,
+        narrator -> Nullable<Varchar>,
+        series -> Nullable<Varchar>,
+        series_index -> Nullable<Float8>,
+        description -> Nullable<Text>,
+        chapters -> Nullable<Text>,
+        cover_source -> Nullable<Varchar>,
+        palette -> Nullable<Text>,
+    }
+}
+
 table! {
     audiobooks (id) {
         id -> Text,
@@ -17,6 +32,18 @@ table! {
         hash -> Binary,
         file_extension -> Varchar,
         deleted -> Bool,
+        narrator -> Nullable<Varchar>,
+        series -> Nullable<Varchar>,
+        series_index -> Nullable<Float8>,
+        description -> Nullable<Text>,
+        publisher -> Nullable<Varchar>,
+        year -> Nullable<Integer>,
+        genres -> Text,
+        language -> Nullable<Varchar>,
+        isbn -> Nullable<Varchar>,
+        asin -> Nullable<Varchar>,
+        cover_source -> Nullable<Text>,
+        palette -> Nullable<Text>,
     }
 }
 
@@ -27,6 +54,7 @@ table! {
         audiobook_id -> Text,
         start_time -> Float8,
         number -> Int8,
+        generated -> Bool,
     }
 }
 
@@ -36,6 +64,10 @@ table! {
         location -> Text,
         is_audiobook_regex -> Text,
         last_scan -> Nullable<Timestamp>,
+        chapter_strategy -> Text,
+        chapter_regex -> Nullable<Text>,
+        cover_preference -> Text,
+        multifile_storage -> Text,
     }
 }
 
@@ -46,6 +78,13 @@ table! {
     }
 }
 
+table! {
+    metadata_sidecars (audiobook_id, file_name) {
+        audiobook_id -> Text,
+        file_name -> Text,
+    }
+}
+
 table! {
     playstates (audiobook_id, user_id) {
         audiobook_id -> Text,
@@ -55,6 +94,23 @@ table! {
     }
 }
 
+table! {
+    scan_warnings (id) {
+        id -> Text,
+        audiobook_id -> Text,
+        message -> Text,
+        created_at -> Timestamp,
+    }
+}
+
+table! {
+    user_limits (user_id) {
+        user_id -> Text,
+        max_streams -> Nullable<Integer>,
+        bandwidth -> Nullable<Integer>,
+    }
+}
+
 table! {
     users (id) {
         id -> Text,
@@ -62,23 +118,32 @@ table! {
         updated_at -> Timestamp,
         email -> Varchar,
         password_hash -> Varchar,
+        admin -> Bool,
     }
 }
 
 joinable!(api_tokens -> users (user_id));
+joinable!(audiobook_overrides -> audiobooks (audiobook_id));
 joinable!(audiobooks -> libraries (library_id));
 joinable!(chapters -> audiobooks (audiobook_id));
 joinable!(library_permissions -> libraries (library_id));
 joinable!(library_permissions -> users (user_id));
+joinable!(metadata_sidecars -> audiobooks (audiobook_id));
 joinable!(playstates -> audiobooks (audiobook_id));
 joinable!(playstates -> users (user_id));
+joinable!(scan_warnings -> audiobooks (audiobook_id));
+joinable!(user_limits -> users (user_id));
 
 allow_tables_to_appear_in_same_query!(
     api_tokens,
+    audiobook_overrides,
     audiobooks,
     chapters,
     libraries,
     library_permissions,
+    metadata_sidecars,
     playstates,
+    scan_warnings,
+    user_limits,
     users,
 );
@@ -8,8 +8,14 @@ use rocket::http::{Status, Method, Header, ContentType};
 use serde_json::{self, Value};
 use crate::worker::scanner::{Scanner, LockingBehavior};
 use crate::models::library::Library;
+use crate::models::audiobook::{Audiobook, Genres};
+use crate::helpers::uuid::Uuid;
+use crate::schema::audiobooks;
+use diesel::sqlite::SqliteConnection;
+use std::path::Path;
 use regex::Regex;
 use crate::config;
+use image::{self, GenericImage};
 
 fn post<'a>(client: &'a Client, url: &'a str, data: &Value, auth: Option<&str>) -> LocalResponse<'a> {
     if let Some(token) = auth {
@@ -26,6 +32,20 @@ fn post<'a>(client: &'a Client, url: &'a str, data: &Value, auth: Option<&str>)
     }
 }
 
+fn patch<'a>(client: &'a Client, url: &'a str, data: &Value, auth: &str) -> LocalResponse<'a> {
+    client.patch(url)
+        .header(Header::new("Authorization", auth.to_owned()))
+        .header(ContentType::JSON)
+        .body(data.to_string())
+        .dispatch()
+}
+
+fn delete<'a>(client: &'a Client, url: &'a str, auth: &str) -> LocalResponse<'a> {
+    client.delete(url)
+        .header(Header::new("Authorization", auth.to_owned()))
+        .dispatch()
+}
+
 fn get<'a>(client: &'a Client, url: &'a str, auth: Option<&str>) -> LocalResponse<'a> {
     if let Some(token) = auth {
         client.get(url)
@@ -37,6 +57,42 @@ fn get<'a>(client: &'a Client, url: &'a str, auth: Option<&str>) -> LocalRespons
     }
 }
 
+/// A book of `library` with only the columns the scanner always sets, the file extension is
+/// taken from `location`.
+fn new_book(library: &Library, title: &str, location: &str, length: f64) -> Audiobook {
+    Audiobook {
+        id: Uuid::new_v4(),
+        location: location.to_owned(),
+        title: title.to_owned(),
+        artist: None,
+        length,
+        library_id: library.id,
+        hash: title.as_bytes().to_vec(),
+        file_extension: Path::new(location).extension()
+            .map(|e| e.to_string_lossy().into_owned())
+            .unwrap_or_default(),
+        deleted: false,
+        narrator: None,
+        series: None,
+        series_index: None,
+        description: None,
+        publisher: None,
+        year: None,
+        genres: Genres::default(),
+        language: None,
+        isbn: None,
+        asin: None,
+        cover_source: None,
+        palette: None,
+    }
+}
+
+fn insert_book(conn: &SqliteConnection, library: &Library, title: &str, location: &str, length: f64) -> Audiobook {
+    let book = new_book(library, title, location, length);
+    diesel::insert_into(audiobooks::table).values(&book).execute(conn).unwrap();
+    book
+}
+
 speculate! {
     before {
         let pool = init_test_db_pool();
@@ -163,4 +219,638 @@ speculate! {
         }
     }
 
+    describe "filter_books" {
+        before {
+            let library = Library::create("test-data".to_owned(), "^[^/]+$".to_owned(), &*pool.get().unwrap()).unwrap();
+            let book = |title: &str, narrator: &str, genres: &[&str], year: i32| Audiobook {
+                narrator: Some(narrator.to_owned()),
+                year: Some(year),
+                genres: Genres(genres.iter().map(|g| (*g).to_owned()).collect()),
+                ..new_book(&library, title, &format!("{}.mp3", title), 1.0)
+            };
+            let books = vec![
+                book("Der Zauberberg", "Gert Westphal", &["Classics"], 2000),
+                book("Buddenbrooks", "Gert Westphal", &["Classics", "Family"], 1990),
+                book("Tintenherz", "Rainer Strecker", &["Fantasy"], 2004),
+            ];
+            diesel::insert_into(audiobooks::table).values(&books).execute(&*pool.get().unwrap()).unwrap();
+        }
+
+        it "filters books by metadata" {
+            let titles = |url: &str| -> Vec<String> {
+                let mut res = get(&client, url, Some(auth_token));
+                assert_eq!(res.status(), Status::Ok);
+                let data: Value = serde_json::from_str(&res.body_string().unwrap()).unwrap();
+                data.as_array().unwrap().iter().map(|b| b["title"].as_str().unwrap().to_owned()).collect()
+            };
+            assert_eq!(titles("/api/audiobooks").len(), 3);
+            assert_eq!(titles("/api/audiobooks?narrator=westphal"), vec!["Buddenbrooks", "Der Zauberberg"]);
+            assert_eq!(titles("/api/audiobooks?narrator=westphal&genre=family"), vec!["Buddenbrooks"]);
+            assert_eq!(titles("/api/audiobooks?year=2004"), vec!["Tintenherz"]);
+            diesel::update(audiobooks::table.filter(audiobooks::dsl::title.eq("Tintenherz")))
+                .set(audiobooks::dsl::language.eq("de-DE"))
+                .execute(&*pool.get().unwrap()).unwrap();
+            assert_eq!(titles("/api/audiobooks?language=de"), vec!["Tintenherz"]);
+            // wildcards are matched literally
+            assert!(titles("/api/audiobooks?genre=%25").is_empty());
+            assert!(titles("/api/audiobooks?genre=_").is_empty());
+            assert_eq!(titles("/api/audiobooks?unknown=1"), titles("/api/audiobooks"));
+        }
+    }
+
+    describe "override_books" {
+        before {
+            use crate::models::chapter::Chapter;
+            use crate::schema::chapters;
+            let library = Library::create("test-data".to_owned(), "^[^/]+$".to_owned(), &*pool.get().unwrap()).unwrap();
+            let book = Audiobook {
+                artist: Some("Thomas Mann".to_owned()),
+                ..new_book(&library, "Der Zauberbrg", "zauberberg.mp3", 100.0)
+            };
+            diesel::insert_into(audiobooks::table).values(&book).execute(&*pool.get().unwrap()).unwrap();
+            let chapter = Chapter {
+                id: Uuid::new_v4(),
+                title: Some("Track 1".to_owned()),
+                audiobook_id: book.id,
+                start_time: 0.0,
+                number: 0,
+                generated: false,
+            };
+            diesel::insert_into(chapters::table).values(&chapter).execute(&*pool.get().unwrap()).unwrap();
+            let url = format!("/api/audiobooks/{}", book.id.hyphenated());
+        }
+
+        it "lets admins override and reset metadata" {
+            user.set_admin(true, &*pool.get().unwrap()).unwrap();
+            let changes = json!({
+                "title": "Der Zauberberg",
+                "narrator": "Gert Westphal",
+                "chapters": [{"title": "Vorsatz", "start_time": 0}, {"title": "Erstes Kapitel", "start_time": 30.5}]
+            });
+            let mut res = patch(&client, &url, &changes, auth_token);
+            assert_eq!(res.status(), Status::Ok);
+            let data: Value = serde_json::from_str(&res.body_string().unwrap()).unwrap();
+            assert_eq!(data["title"], "Der Zauberberg");
+            assert_eq!(data["artist"], "Thomas Mann");
+
+            // a rescan only changes the scanned values
+            diesel::update(audiobooks::table).set(audiobooks::dsl::title.eq("Der Zauberberg (scanned)"))
+                .execute(&*pool.get().unwrap()).unwrap();
+            let mut res = get(&client, &url, Some(auth_token));
+            let data: Value = serde_json::from_str(&res.body_string().unwrap()).unwrap();
+            assert_eq!(data["title"], "Der Zauberberg");
+            assert_eq!(data["narrator"], "Gert Westphal");
+            let mut res = get(&client, "/api/all_the_things", Some(auth_token));
+            let data: Value = serde_json::from_str(&res.body_string().unwrap()).unwrap();
+            assert_eq!(data["chapters"].as_array().unwrap().len(), 2);
+            assert_eq!(data["chapters"][1]["title"], "Erstes Kapitel");
+
+            let res = patch(&client, &url, &json!({"title": null, "chapters": null}), auth_token);
+            assert_eq!(res.status(), Status::Ok);
+            let mut res = get(&client, &url, Some(auth_token));
+            let data: Value = serde_json::from_str(&res.body_string().unwrap()).unwrap();
+            assert_eq!(data["title"], "Der Zauberberg (scanned)");
+            assert_eq!(data["narrator"], "Gert Westphal");
+            let mut res = get(&client, "/api/all_the_things", Some(auth_token));
+            let data: Value = serde_json::from_str(&res.body_string().unwrap()).unwrap();
+            assert_eq!(data["chapters"][0]["title"], "Track 1");
+        }
+
+        it "drops overridden chapters past the end of a rescanned book" {
+            user.set_admin(true, &*pool.get().unwrap()).unwrap();
+            let changes = json!({"chapters": [{"title": "Vorsatz", "start_time": 0}, {"title": "Erstes Kapitel", "start_time": 80}]});
+            assert_eq!(patch(&client, &url, &changes, auth_token).status(), Status::Ok);
+            diesel::update(audiobooks::table).set(audiobooks::dsl::length.eq(60.0))
+                .execute(&*pool.get().unwrap()).unwrap();
+
+            let mut res = get(&client, &format!("{}/chapters", url), Some(auth_token));
+            let data: Value = serde_json::from_str(&res.body_string().unwrap()).unwrap();
+            assert_eq!(data.as_array().unwrap().len(), 1);
+            assert_eq!(data[0]["title"], "Vorsatz");
+            let res = get(&client, &format!("{}/chapters/1/audio", url), Some(auth_token));
+            assert_eq!(res.status(), Status::NotFound);
+
+            // editing works on the chapters that are left
+            let res = post(&client, &format!("{}/chapters", url), &json!({"title": "Ende", "start_time": 50}), Some(auth_token));
+            assert_eq!(res.status(), Status::Ok);
+        }
+
+        it "rejects invalid overrides" {
+            user.set_admin(true, &*pool.get().unwrap()).unwrap();
+            let unordered = json!({"chapters": [{"title": "B", "start_time": 50}, {"title": "A", "start_time": 10}]});
+            assert_eq!(patch(&client, &url, &unordered, auth_token).status(), Status::UnprocessableEntity);
+            let too_late = json!({"chapters": [{"title": "A", "start_time": 0}, {"title": "B", "start_time": 120}]});
+            assert_eq!(patch(&client, &url, &too_late, auth_token).status(), Status::UnprocessableEntity);
+            assert_eq!(patch(&client, &url, &json!({"title": " "}), auth_token).status(), Status::UnprocessableEntity);
+        }
+
+        it "lets admins edit single chapters" {
+            user.set_admin(true, &*pool.get().unwrap()).unwrap();
+            let chapters_url = format!("{}/chapters", url);
+            let chapter_list = |res: &mut LocalResponse| -> Vec<Value> {
+                assert_eq!(res.status(), Status::Ok);
+                let data: Value = serde_json::from_str(&res.body_string().unwrap()).unwrap();
+                data.as_array().unwrap().clone()
+            };
+
+            let list = chapter_list(&mut post(&client, &chapters_url, &json!({"title": "Zweites Kapitel", "start_time": 50}), Some(auth_token)));
+            assert_eq!(list.len(), 2);
+            assert_eq!(list[1]["title"], "Zweites Kapitel");
+            let second_url = format!("{}/{}", chapters_url, list[1]["id"].as_str().unwrap());
+
+            let first_url = format!("{}/{}", chapters_url, chapter.id.hyphenated());
+            let list = chapter_list(&mut patch(&client, &first_url, &json!({"title": "Vorsatz"}), auth_token));
+            assert_eq!(list[0]["title"], "Vorsatz");
+            let list = chapter_list(&mut patch(&client, &second_url, &json!({"start_time": 40.5}), auth_token));
+            assert_eq!(list[1]["start_time"], 40.5);
+
+            // chapters can't move past their neighbours, before the start or after the end
+            assert_eq!(patch(&client, &first_url, &json!({"start_time": 60}), auth_token).status(), Status::UnprocessableEntity);
+            assert_eq!(patch(&client, &second_url, &json!({"start_time": 100}), auth_token).status(), Status::UnprocessableEntity);
+            assert_eq!(post(&client, &chapters_url, &json!({"title": "Doppelt", "start_time": 0}), Some(auth_token)).status(), Status::UnprocessableEntity);
+            assert_eq!(post(&client, &chapters_url, &json!({"title": "Negativ", "start_time": -1}), Some(auth_token)).status(), Status::UnprocessableEntity);
+
+            // edits survive the scanner replacing the chapters
+            diesel::delete(chapters::table).execute(&*pool.get().unwrap()).unwrap();
+            let list = chapter_list(&mut get(&client, &chapters_url, Some(auth_token)));
+            assert_eq!(list.len(), 2);
+
+            let list = chapter_list(&mut delete(&client, &second_url, auth_token));
+            assert_eq!(list.len(), 1);
+            assert_eq!(delete(&client, &second_url, auth_token).status(), Status::NotFound);
+        }
+
+        it "only lets admins edit chapters" {
+            let res = post(&client, &format!("{}/chapters", url), &json!({"title": "Zweites Kapitel", "start_time": 50}), Some(auth_token));
+            assert_eq!(res.status(), Status::Forbidden);
+        }
+
+        it "only lets admins override metadata" {
+            let res = patch(&client, &url, &json!({"title": "Der Zauberberg"}), auth_token);
+            assert_eq!(res.status(), Status::Forbidden);
+        }
+    }
+
+    describe "cover_art" {
+        before {
+            let library = Library::create("test-data".to_owned(), "^[^/]+$".to_owned(), &*pool.get().unwrap()).unwrap();
+            let book = Audiobook {
+                cover_source: Some("cover.png".to_owned()),
+                ..new_book(&library, "Cover", "1.mp3", 1.0)
+            };
+            diesel::insert_into(audiobooks::table).values(&book).execute(&*pool.get().unwrap()).unwrap();
+            std::fs::create_dir_all("data/img").unwrap();
+            std::fs::copy("integration-tests/cover_images/book/cover.png",
+                          format!("data/img/{}", book.id.hyphenated())).unwrap();
+            let url = format!("/api/coverart/{}", book.id.hyphenated());
+        }
+
+        it "serves covers with their content type and an etag" {
+            let mut res = get(&client, &url, Some(auth_token));
+            assert_eq!(res.status(), Status::Ok);
+            assert_eq!(res.content_type(), Some(ContentType::PNG));
+            assert!(res.headers().get_one("Cache-Control").unwrap().contains("max-age"));
+            assert!(res.body_bytes().unwrap().starts_with(b"\x89PNG"));
+            let etag = res.headers().get_one("ETag").unwrap().to_owned();
+
+            let res = client.get(url.clone())
+                .header(Header::new("Authorization", auth_token.to_owned()))
+                .header(Header::new("If-None-Match", etag))
+                .dispatch();
+            assert_eq!(res.status(), Status::NotModified);
+        }
+
+        it "serves thumbnails as jpeg" {
+            let mut res = get(&client, &format!("{}?size=64", url), Some(auth_token));
+            assert_eq!(res.status(), Status::Ok);
+            assert_eq!(res.content_type(), Some(ContentType::JPEG));
+            let thumbnail = image::load_from_memory(&res.body_bytes().unwrap()).unwrap();
+            assert_eq!(thumbnail.dimensions(), (16, 16));
+
+            // larger than all thumbnails, the original is sent
+            let res = get(&client, &format!("{}?size=4096", url), Some(auth_token));
+            assert_eq!(res.content_type(), Some(ContentType::PNG));
+        }
+
+        it "lets admins upload and revert covers" {
+            user.set_admin(true, &*pool.get().unwrap()).unwrap();
+            let book_url = format!("/api/audiobooks/{}", book.id.hyphenated());
+            let upload = |data: &[u8]| client.put(format!("{}/cover", book_url))
+                .header(Header::new("Authorization", auth_token.to_owned()))
+                .body(data)
+                .dispatch();
+            assert_eq!(upload(b"not an image").status(), Status::UnprocessableEntity);
+
+            let png = std::fs::read("integration-tests/cover_images/book/cover.png").unwrap();
+            let mut res = upload(&png);
+            assert_eq!(res.status(), Status::Ok);
+            let data: Value = serde_json::from_str(&res.body_string().unwrap()).unwrap();
+            assert_eq!(data["cover_source"], "uploaded");
+            let res = get(&client, &url, Some(auth_token));
+            assert_eq!(res.content_type(), Some(ContentType::JPEG));
+
+            // rescans don't replace uploaded covers
+            diesel::update(audiobooks::table).set(audiobooks::dsl::cover_source.eq("embedded"))
+                .execute(&*pool.get().unwrap()).unwrap();
+            let mut res = get(&client, &book_url, Some(auth_token));
+            let data: Value = serde_json::from_str(&res.body_string().unwrap()).unwrap();
+            assert_eq!(data["cover_source"], "uploaded");
+
+            let mut res = delete(&client, &format!("{}/cover", book_url), auth_token);
+            assert_eq!(res.status(), Status::Ok);
+            let data: Value = serde_json::from_str(&res.body_string().unwrap()).unwrap();
+            assert_eq!(data["cover_source"], "embedded");
+            let res = get(&client, &url, Some(auth_token));
+            assert_eq!(res.content_type(), Some(ContentType::PNG));
+        }
+
+        it "only lets admins upload covers" {
+            let res = client.put(format!("/api/audiobooks/{}/cover", book.id.hyphenated()))
+                .header(Header::new("Authorization", auth_token.to_owned()))
+                .body(&b"\x89PNG"[..])
+                .dispatch();
+            assert_eq!(res.status(), Status::Forbidden);
+        }
+
+        it "doesn't serve covers of books that don't exist" {
+            let res = get(&client, &format!("/api/coverart/{}", Uuid::new_v4().hyphenated()), Some(auth_token));
+            assert_eq!(res.status(), Status::NotFound);
+        }
+    }
+
+    describe "data_files" {
+        before {
+            let library = Library::create("test-data".to_owned(), "^[^/]+$".to_owned(), &*pool.get().unwrap()).unwrap();
+            let book = insert_book(&*pool.get().unwrap(), &library, "Data", "1.mp3", 1.0);
+            std::fs::create_dir_all("data").unwrap();
+            let data_file = format!("data/{}.mp3", book.id.hyphenated());
+            std::fs::copy("test-data/1.mp3", &data_file).unwrap();
+            let size = std::fs::metadata(&data_file).unwrap().len();
+            let url = format!("/data/{}", book.id.hyphenated());
+            let ranged = |range: &str, if_range: Option<&str>| {
+                let mut req = client.get(url.clone())
+                    .header(Header::new("Authorization", auth_token.to_owned()))
+                    .header(Header::new("Range", range.to_owned()));
+                if let Some(validator) = if_range {
+                    req = req.header(Header::new("If-Range", validator.to_owned()));
+                }
+                req.dispatch()
+            };
+        }
+
+        it "serves whole files with validators" {
+            let mut res = get(&client, &url, Some(auth_token));
+            assert_eq!(res.status(), Status::Ok);
+            assert_eq!(res.content_type(), Some(ContentType::new("audio", "mpeg")));
+            assert_eq!(res.headers().get_one("Accept-Ranges"), Some("bytes"));
+            assert!(res.headers().get_one("ETag").is_some());
+            assert!(res.headers().get_one("Last-Modified").unwrap().ends_with(" GMT"));
+            assert_eq!(res.body_bytes().unwrap().len() as u64, size);
+        }
+
+        it "derives the content type from the file extension" {
+            diesel::update(audiobooks::table).set(audiobooks::dsl::file_extension.eq("m4b"))
+                .execute(&*pool.get().unwrap()).unwrap();
+            std::fs::copy("test-data/all.m4b", format!("data/{}.m4b", book.id.hyphenated())).unwrap();
+            let res = get(&client, &url, Some(auth_token));
+            assert_eq!(res.content_type(), Some(ContentType::new("audio", "mp4")));
+        }
+
+        it "answers HEAD requests without a body" {
+            let mut res = client.head(url.clone())
+                .header(Header::new("Authorization", auth_token.to_owned()))
+                .dispatch();
+            assert_eq!(res.status(), Status::Ok);
+            assert_eq!(res.headers().get_one("Content-Length"), Some(size.to_string().as_str()));
+            assert!(res.body_bytes().unwrap_or_default().is_empty());
+        }
+
+        it "serves single ranges" {
+            let mut res = ranged("bytes=0-9", None);
+            assert_eq!(res.status(), Status::PartialContent);
+            assert_eq!(res.headers().get_one("Content-Range"), Some(format!("bytes 0-9/{}", size).as_str()));
+            assert_eq!(res.body_bytes().unwrap().len(), 10);
+
+            // the end is capped to the size of the file
+            let mut res = ranged(&format!("bytes={}-{}", size - 5, size + 100), None);
+            assert_eq!(res.status(), Status::PartialContent);
+            assert_eq!(res.body_bytes().unwrap().len(), 5);
+            let mut res = ranged("bytes=-3", None);
+            assert_eq!(res.body_bytes().unwrap().len(), 3);
+        }
+
+        it "serves several ranges as multipart" {
+            let mut res = ranged("bytes=0-1,4-5", None);
+            assert_eq!(res.status(), Status::PartialContent);
+            let content_type = res.headers().get_one("Content-Type").unwrap().to_owned();
+            assert!(content_type.starts_with("multipart/byteranges; boundary="));
+            let body = String::from_utf8_lossy(&res.body_bytes().unwrap()).into_owned();
+            assert!(body.contains(&format!("Content-Range: bytes 0-1/{}", size)));
+            assert!(body.contains(&format!("Content-Range: bytes 4-5/{}", size)));
+            assert!(body.ends_with(&format!("--{}--\r\n", &content_type["multipart/byteranges; boundary=".len()..])));
+        }
+
+        it "rejects unsatisfiable ranges" {
+            let res = ranged(&format!("bytes={}-", size), None);
+            assert_eq!(res.status(), Status::RangeNotSatisfiable);
+            assert_eq!(res.headers().get_one("Content-Range"), Some(format!("bytes */{}", size).as_str()));
+            // broken headers are ignored
+            let res = ranged("bytes=abc", None);
+            assert_eq!(res.status(), Status::Ok);
+        }
+
+        it "transcodes books in the background" {
+            let res = get(&client, &format!("{}?format=flac", url), Some(auth_token));
+            assert_eq!(res.status(), Status::BadRequest);
+            let res = get(&client, &format!("{}?format=aac&bitrate=2000", url), Some(auth_token));
+            assert_eq!(res.status(), Status::BadRequest);
+            // only the configured bitrates are transcoded
+            let res = get(&client, &format!("{}?format=aac&bitrate=48", url), Some(auth_token));
+            assert_eq!(res.status(), Status::BadRequest);
+
+            // answered with a 202 until the transcode is done
+            let transcoded = format!("{}?format=aac&bitrate=32", url);
+            let mut tries = 0;
+            let mut res = loop {
+                let res = get(&client, &transcoded, Some(auth_token));
+                if res.status() != Status::Accepted || tries == 60 {
+                    break res;
+                }
+                tries += 1;
+                std::thread::sleep(std::time::Duration::from_millis(500));
+            };
+            assert_eq!(res.status(), Status::Ok);
+            assert_eq!(res.content_type(), Some(ContentType::new("audio", "mp4")));
+            assert!((res.body_bytes().unwrap().len() as u64) < size);
+
+            // cached transcodes answer range requests as well
+            let res = client.get(transcoded.clone())
+                .header(Header::new("Authorization", auth_token.to_owned()))
+                .header(Header::new("Range", "bytes=0-9"))
+                .dispatch();
+            assert_eq!(res.status(), Status::PartialContent);
+
+            // the transcode carries the title, so a new one is made after it changed
+            let book_url = format!("/api/audiobooks/{}", book.id.hyphenated());
+            assert_eq!(patch(&client, &book_url, &json!({"title": "Renamed"}), auth_token).status(), Status::Ok);
+            let res = get(&client, &transcoded, Some(auth_token));
+            assert_eq!(res.status(), Status::Accepted);
+        }
+
+        it "packs books into a zip for offline listening" {
+            let mut res = get(&client, &format!("/api/audiobooks/{}/download", book.id.hyphenated()), Some(auth_token));
+            assert_eq!(res.status(), Status::Ok);
+            assert_eq!(res.content_type(), Some(ContentType::new("application", "zip")));
+            assert!(res.headers().get_one("Content-Disposition").unwrap().contains("filename=\"Data.zip\""));
+            let length: usize = res.headers().get_one("Content-Length").unwrap().parse().unwrap();
+            let zip = res.body_bytes().unwrap();
+            assert_eq!(zip.len(), length);
+            assert!(zip.starts_with(b"PK\x03\x04"));
+
+            // the audio comes first, followed by its data descriptor
+            let name = b"Data/Data.mp3";
+            assert_eq!(&zip[30..30 + name.len()], &name[..]);
+            let audio = std::fs::read(&data_file).unwrap();
+            let descriptor = &zip[30 + name.len() + audio.len()..];
+            assert!(descriptor.starts_with(b"PK\x07\x08"));
+            assert_eq!(&descriptor[4..8], &crc32fast::hash(&audio).to_le_bytes());
+
+            // the end of central directory record counts chapters.json and audiobook.json as well
+            let end = &zip[zip.len() - 22..];
+            assert!(end.starts_with(b"PK\x05\x06"));
+            assert_eq!(end[10], 3);
+        }
+
+        it "serves multifile books from their files" {
+            use std::path::{Path, PathBuf};
+            use crate::worker::virtual_file::{self, VirtualFile};
+            let files = vec![PathBuf::from("test-data/all/1.mp3"), PathBuf::from("test-data/all/2.mp3")];
+            let parts = VirtualFile::for_files(&files).unwrap();
+            std::fs::remove_file(&data_file).unwrap();
+            parts.write(&virtual_file::manifest_path(Path::new(&data_file))).unwrap();
+
+            let mut res = get(&client, &url, Some(auth_token));
+            assert_eq!(res.status(), Status::Ok);
+            assert_eq!(res.content_type(), Some(ContentType::new("audio", "mpeg")));
+            let whole = res.body_bytes().unwrap();
+            assert_eq!(whole.len() as u64, parts.len());
+
+            // ranges may span both files
+            let boundary = parts.parts[0].len() + parts.parts[1].len();
+            let mut res = ranged(&format!("bytes={}-{}", boundary - 5, boundary + 4), None);
+            assert_eq!(res.status(), Status::PartialContent);
+            assert_eq!(res.headers().get_one("Content-Range"),
+                       Some(format!("bytes {}-{}/{}", boundary - 5, boundary + 4, parts.len()).as_str()));
+            assert_eq!(res.body_bytes().unwrap(), &whole[boundary as usize - 5..boundary as usize + 5]);
+        }
+
+        it "limits the streams a user has open" {
+            use crate::api::streaming::StreamSlot;
+            let mut conf = config::load_config_from_path(&"test-data/test-config.toml").unwrap();
+            conf.web.streaming.max_streams_per_user = 1;
+            let client = Client::new(helpers::rocket::factory(pool.clone(), conf).unwrap()).unwrap();
+
+            let mut open = get(&client, &url, Some(auth_token));
+            assert_eq!(open.status(), Status::Ok);
+            assert_eq!(StreamSlot::open_streams(&user.id), 1);
+            let res = get(&client, &url, Some(auth_token));
+            assert_eq!(res.status(), Status::TooManyRequests);
+
+            assert_eq!(open.body_bytes().unwrap().len() as u64, size);
+            drop(open);
+            assert_eq!(StreamSlot::open_streams(&user.id), 0);
+            let res = get(&client, &url, Some(auth_token));
+            assert_eq!(res.status(), Status::Ok);
+        }
+
+        it "throttles streams to the bandwidth of the user" {
+            use std::time::{Duration, Instant};
+            let mut conf = config::load_config_from_path(&"test-data/test-config.toml").unwrap();
+            // the file takes about a second at 4000 kbit/s
+            conf.web.streaming.user_bandwidth = 4000;
+            // only the streaming server throttles
+            assert!(conf.check().is_err());
+            let api = Client::new(helpers::rocket::factory(pool.clone(), conf.clone()).unwrap()).unwrap();
+            let started = Instant::now();
+            let mut res = get(&api, &url, Some(auth_token));
+            assert_eq!(res.body_bytes().unwrap().len() as u64, size);
+            assert!(started.elapsed() < Duration::from_millis(800));
+
+            conf.web.streaming.port = Some(8001);
+            conf.web.streaming.public_url = Some("http://localhost:8001".to_owned());
+            assert!(conf.check().is_ok());
+            let client = Client::new(helpers::rocket::streaming_factory(pool.clone(), conf).unwrap()).unwrap();
+            let started = Instant::now();
+            let mut res = get(&client, &url, Some(auth_token));
+            assert_eq!(res.body_bytes().unwrap().len() as u64, size);
+            assert!(started.elapsed() > Duration::from_millis(800));
+        }
+
+        it "keeps streams to the workers of the streaming server" {
+            let mut conf = config::load_config_from_path(&"test-data/test-config.toml").unwrap();
+            conf.web.streaming.max_streams = 0;
+            assert_eq!(conf.web.streaming.stream_limit(), 0);
+            conf.web.streaming.port = Some(8001);
+            conf.web.streaming.workers = 16;
+            assert_eq!(conf.web.streaming.stream_limit(), 16);
+            conf.web.streaming.max_streams = 100;
+            assert_eq!(conf.web.streaming.stream_limit(), 16);
+            conf.web.streaming.max_streams = 8;
+            assert_eq!(conf.web.streaming.stream_limit(), 8);
+        }
+
+        it "lets admins override the limits of users" {
+            let mut conf = config::load_config_from_path(&"test-data/test-config.toml").unwrap();
+            conf.web.streaming.max_streams_per_user = 1;
+            let client = Client::new(helpers::rocket::factory(pool.clone(), conf).unwrap()).unwrap();
+            let limits_url = format!("/api/users/{}/limits", user.id.hyphenated());
+
+            let res = patch(&client, &limits_url, &json!({"max_streams": 0}), auth_token);
+            assert_eq!(res.status(), Status::Forbidden);
+            user.set_admin(true, &*pool.get().unwrap()).unwrap();
+            let res = patch(&client, &limits_url, &json!({"max_streams": -1}), auth_token);
+            assert_eq!(res.status(), Status::UnprocessableEntity);
+
+            let mut res = patch(&client, &limits_url, &json!({"max_streams": 0, "bandwidth": 64}), auth_token);
+            assert_eq!(res.status(), Status::Ok);
+            let data: Value = serde_json::from_str(&res.body_string().unwrap()).unwrap();
+            assert_eq!(data["effective"], json!({"max_streams": 0, "bandwidth": 64}));
+            let first = get(&client, &url, Some(auth_token));
+            let second = get(&client, &url, Some(auth_token));
+            assert_eq!(first.status(), Status::Ok);
+            assert_eq!(second.status(), Status::Ok);
+            drop((first, second));
+
+            // null brings back the configured limit
+            patch(&client, &limits_url, &json!({"max_streams": null, "bandwidth": null}), auth_token);
+            let mut res = get(&client, &limits_url, Some(auth_token));
+            let data: Value = serde_json::from_str(&res.body_string().unwrap()).unwrap();
+            assert_eq!(data, json!({"max_streams": null, "bandwidth": null,
+                                    "effective": {"max_streams": 1, "bandwidth": 0}}));
+            let _open = get(&client, &url, Some(auth_token));
+            let res = get(&client, &url, Some(auth_token));
+            assert_eq!(res.status(), Status::TooManyRequests);
+        }
+
+        it "sends media from the streaming server" {
+            let mut conf = config::load_config_from_path(&"test-data/test-config.toml").unwrap();
+            conf.web.streaming.port = Some(8001);
+            assert!(conf.check().is_err());
+            conf.web.streaming.public_url = Some("https://media.example.com/".to_owned());
+            assert!(conf.check().is_ok());
+            let api = Client::new(helpers::rocket::factory(pool.clone(), conf.clone()).unwrap()).unwrap();
+            let res = get(&api, &url, Some(auth_token));
+            assert_eq!(res.status(), Status::TemporaryRedirect);
+            let location = format!("https://media.example.com{}?auth={}", url, auth_token);
+            assert_eq!(res.headers().get_one("Location"), Some(location.as_str()));
+
+            let streaming = Client::new(helpers::rocket::streaming_factory(pool.clone(), conf).unwrap()).unwrap();
+            let mut res = streaming.get(format!("{}?auth={}", url, auth_token)).dispatch();
+            assert_eq!(res.status(), Status::Ok);
+            assert_eq!(res.body_bytes().unwrap().len() as u64, size);
+            let res = get(&streaming, "/api/audiobooks", Some(auth_token));
+            assert_eq!(res.status(), Status::NotFound);
+        }
+
+        it "only serves ranges of the version named in If-Range" {
+            let etag = get(&client, &url, Some(auth_token)).headers().get_one("ETag").unwrap().to_owned();
+            let res = ranged("bytes=0-9", Some(&etag));
+            assert_eq!(res.status(), Status::PartialContent);
+            let res = ranged("bytes=0-9", Some("\"outdated\""));
+            assert_eq!(res.status(), Status::Ok);
+        }
+    }
+
+    describe "hls" {
+        before {
+            let library = Library::create("test-data".to_owned(), "^[^/]+$".to_owned(), &*pool.get().unwrap()).unwrap();
+            let book = insert_book(&*pool.get().unwrap(), &library, "Streamed", "all.m4b", 25.0);
+            std::fs::create_dir_all("data").unwrap();
+            std::fs::copy("test-data/all.m4b", format!("data/{}.m4b", book.id.hyphenated())).unwrap();
+            let url = format!("/hls/{}", book.id.hyphenated());
+        }
+
+        it "serves a playlist with authenticated segment urls" {
+            let mut res = get(&client, &format!("{}/playlist.m3u8", url), Some(auth_token));
+            assert_eq!(res.status(), Status::Ok);
+            assert_eq!(res.content_type(), Some(ContentType::new("application", "vnd.apple.mpegurl")));
+            let playlist = res.body_string().unwrap();
+            assert_eq!(playlist.matches("#EXTINF:").count(), 3);
+            assert!(playlist.contains(&format!("0.ts?auth={}", auth_token)));
+        }
+
+        it "serves segments as MPEG-TS" {
+            let mut res = client.get(format!("{}/1.ts?auth={}", url, auth_token)).dispatch();
+            assert_eq!(res.status(), Status::Ok);
+            assert_eq!(res.content_type(), Some(ContentType::new("video", "mp2t")));
+            // every TS packet starts with a sync byte
+            assert_eq!(res.body_bytes().unwrap()[0], 0x47);
+
+            let res = get(&client, &format!("{}/3.ts", url), Some(auth_token));
+            assert_eq!(res.status(), Status::NotFound);
+        }
+
+        it "sends segments from the streaming server" {
+            let mut conf = config::load_config_from_path(&"test-data/test-config.toml").unwrap();
+            conf.web.streaming.port = Some(8001);
+            conf.web.streaming.public_url = Some("https://media.example.com".to_owned());
+            let segment = format!("{}/0.ts?auth={}", url, auth_token);
+            let api = Client::new(helpers::rocket::factory(pool.clone(), conf.clone()).unwrap()).unwrap();
+            let res = api.get(segment.clone()).dispatch();
+            assert_eq!(res.status(), Status::TemporaryRedirect);
+            let location = format!("https://media.example.com{}", segment);
+            assert_eq!(res.headers().get_one("Location"), Some(location.as_str()));
+
+            let streaming = Client::new(helpers::rocket::streaming_factory(pool.clone(), conf).unwrap()).unwrap();
+            let res = streaming.get(segment).dispatch();
+            assert_eq!(res.status(), Status::Ok);
+            assert_eq!(res.content_type(), Some(ContentType::new("video", "mp2t")));
+        }
+    }
+
+    describe "chapter_audio" {
+        before {
+            use crate::models::chapter::Chapter;
+            use crate::schema::chapters;
+            let library = Library::create("test-data".to_owned(), "^[^/]+$".to_owned(), &*pool.get().unwrap()).unwrap();
+            let book = insert_book(&*pool.get().unwrap(), &library, "Chapters", "all.m4b", 10.0);
+            let chapter_list: Vec<Chapter> = vec![0.0, 4.0].into_iter().enumerate().map(|(i, start)| Chapter {
+                id: Uuid::new_v4(),
+                title: Some(format!("Part {}", i + 1)),
+                audiobook_id: book.id,
+                start_time: start,
+                number: i as i64,
+                generated: false,
+            }).collect();
+            diesel::insert_into(chapters::table).values(&chapter_list).execute(&*pool.get().unwrap()).unwrap();
+            std::fs::create_dir_all("data").unwrap();
+            std::fs::copy("test-data/all.m4b", format!("data/{}.m4b", book.id.hyphenated())).unwrap();
+            let url = format!("/api/audiobooks/{}/chapters", book.id.hyphenated());
+        }
+
+        it "serves the audio of single chapters" {
+            use crate::worker::mediafile::MediaFile;
+            let mut res = get(&client, &format!("{}/1/audio", url), Some(auth_token));
+            assert_eq!(res.status(), Status::Ok);
+            assert_eq!(res.content_type(), Some(ContentType::new("audio", "mp4")));
+            let path = std::env::temp_dir().join("vorleser-chapter.m4b");
+            std::fs::write(&path, res.body_bytes().unwrap()).unwrap();
+            let info = MediaFile::read_file(&path).unwrap().get_mediainfo();
+            assert!((info.length - 6.0).abs() < 0.1, "chapter is {} seconds long", info.length);
+            assert_eq!(info.title, "Part 2");
+
+            let res = get(&client, &format!("{}/2/audio", url), Some(auth_token));
+            assert_eq!(res.status(), Status::NotFound);
+        }
+
+        it "replaces chapters extracted before the book was renamed" {
+            assert_eq!(get(&client, &format!("{}/1/audio", url), Some(auth_token)).status(), Status::Ok);
+            let book_url = format!("/api/audiobooks/{}", book.id.hyphenated());
+            assert_eq!(patch(&client, &book_url, &json!({"title": "Renamed"}), auth_token).status(), Status::Ok);
+            assert_eq!(get(&client, &format!("{}/1/audio", url), Some(auth_token)).status(), Status::Ok);
+            let cache = format!("data/chapters/{}", book.id.hyphenated());
+            assert_eq!(std::fs::read_dir(cache).unwrap().count(), 1);
+        }
+    }
+
 }
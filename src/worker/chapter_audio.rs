@@ -0,0 +1,97 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::helpers::uuid::Uuid;
+use crate::models::audiobook::Audiobook;
+use crate::models::chapter::Chapter;
+use crate::worker::error::Result;
+use crate::worker::hashing;
+use crate::worker::mediafile::Image;
+use crate::worker::muxer::{self, OutputMetadata};
+
+/// Where the extracted chapters of a book are cached.
+pub fn cache_directory(data_directory: &str, book_id: &Uuid) -> PathBuf {
+    let mut path = PathBuf::from(data_directory);
+    path.push("chapters");
+    path.push(book_id.hyphenated().to_string());
+    path
+}
+
+/// Title of a chapter as written into its file.
+fn title_of(chapter: &Chapter) -> String {
+    chapter.title.clone().unwrap_or_else(|| format!("Chapter {}", chapter.number + 1))
+}
+
+/// Name of the cached chapter. Besides its bounds it contains a hash of the tags, so renaming the
+/// chapter or the book doesn't serve stale files. The hash has to be stable across releases.
+fn file_name(book: &Audiobook, chapter: &Chapter, end: f64) -> String {
+    let artist = format!("{:?}", book.artist);
+    let tags = [title_of(chapter).as_bytes(), book.title.as_bytes(), artist.as_bytes()];
+    format!(
+        "{}-{}-{}-{}.{}",
+        chapter.number,
+        (chapter.start_time * 1000.0).round() as u64,
+        (end * 1000.0).round() as u64,
+        hashing::cache_key(&tags),
+        book.file_extension
+    )
+}
+
+/// Bounds in milliseconds of a cached chapter, `None` for other files like those being written.
+fn bounds_of(file_name: &str, extension: &str) -> Option<(u64, u64)> {
+    let suffix = format!(".{}", extension);
+    if !file_name.ends_with(&suffix) {
+        return None;
+    }
+    let parts: Vec<&str> = file_name[..file_name.len() - suffix.len()].split('-').collect();
+    match (parts.len(), parts.get(1).and_then(|s| s.parse().ok()), parts.get(2).and_then(|e| e.parse().ok())) {
+        (4, Some(start), Some(end)) => Some((start, end)),
+        _ => None
+    }
+}
+
+/// Remove chapters extracted before the chapters or tags changed, they overlap the chapter
+/// written to `path`.
+fn remove_overlapping(cache_directory: &Path, path: &Path, book: &Audiobook, start: f64, end: f64) {
+    let (start, end) = ((start * 1000.0).round() as u64, (end * 1000.0).round() as u64);
+    let entries = match fs::read_dir(cache_directory) {
+        Ok(entries) => entries,
+        Err(_) => return
+    };
+    for stale in entries.filter_map(|e| e.ok()).map(|e| e.path()) {
+        let overlaps = stale.file_name()
+            .and_then(|n| bounds_of(&n.to_string_lossy(), &book.file_extension))
+            .map_or(false, |(s, e)| s < end && start < e);
+        if overlaps && stale != path {
+            if let Err(e) = fs::remove_file(&stale) {
+                warn!("Could not remove stale chapter {:?}: {}", stale, e);
+            }
+        }
+    }
+}
+
+/// Path of a file holding the audio of a chapter that ends at `end`, copied from the data file
+/// without transcoding. It is tagged with the chapter and book titles and the cover. The file is
+/// created on first use and again whenever the data file is newer than it.
+pub fn chapter_file(data_file: &Path, cache_directory: &Path, book: &Audiobook, chapter: &Chapter, end: f64,
+                    cover: Option<&Image>) -> Result<PathBuf> {
+    let path = cache_directory.join(file_name(book, chapter, end));
+    let data_changed = fs::metadata(data_file)?.modified()?;
+    if let Ok(meta) = fs::metadata(&path) {
+        if meta.modified()? >= data_changed {
+            return Ok(path);
+        }
+    }
+
+    let mut metadata = OutputMetadata::for_book(book, &[], end - chapter.start_time, cover);
+    metadata.tags.insert("title".to_owned(), title_of(chapter));
+    metadata.tags.insert("track".to_owned(), (chapter.number + 1).to_string());
+
+    fs::create_dir_all(cache_directory)?;
+    // concurrent requests each write their own file, the rename makes the last one win
+    let temporary = path.with_extension(format!("{}.{}", Uuid::new_v4().hyphenated(), book.file_extension));
+    muxer::extract_slice(&temporary, data_file, chapter.start_time, end, &metadata)?;
+    fs::rename(&temporary, &path)?;
+    remove_overlapping(cache_directory, &path, book, chapter.start_time, end);
+    Ok(path)
+}
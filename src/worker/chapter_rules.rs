@@ -0,0 +1,67 @@
+use std::path::Path;
+use regex::Regex;
+
+use crate::models::library::{Library, ChapterStrategy};
+use crate::worker::error::Result;
+use crate::worker::mediafile::MediaInfo;
+
+/// Default regex for `ChapterStrategy::MergeRegex`, groups files by their leading number.
+static DEFAULT_MERGE_REGEX: &'static str = r"^\d+";
+/// Default regex for `ChapterStrategy::Filename`, strips leading track numbers.
+static DEFAULT_CLEANUP_REGEX: &'static str = r"^[\d\s._-]+";
+
+/// Decides the titles of chapters in multifile books and which files share a chapter.
+pub struct ChapterRules {
+    strategy: ChapterStrategy,
+    regex: Regex,
+}
+
+impl ChapterRules {
+    pub fn new(strategy: ChapterStrategy, regex: Option<&str>) -> Result<Self> {
+        let default_regex = match strategy {
+            ChapterStrategy::Filename => DEFAULT_CLEANUP_REGEX,
+            _ => DEFAULT_MERGE_REGEX,
+        };
+        Ok(Self {
+            strategy,
+            regex: Regex::new(regex.unwrap_or(default_regex))?,
+        })
+    }
+
+    pub fn for_library(library: &Library) -> Result<Self> {
+        Self::new(library.chapter_strategy, library.chapter_regex.as_ref().map(String::as_str))
+    }
+
+    /// Title of the chapter starting with this file.
+    pub fn title(&self, info: &MediaInfo, path: &Path) -> String {
+        match self.strategy {
+            ChapterStrategy::Filename => {
+                let stem = file_stem(path);
+                let cleaned = self.regex.replace_all(&stem, "").trim().to_owned();
+                if cleaned.is_empty() { stem } else { cleaned }
+            },
+            _ => info.title.clone(),
+        }
+    }
+
+    /// Files following each other with equal keys belong to the same chapter.
+    /// `None` means the file always starts a new chapter.
+    pub fn merge_key(&self, title: &str, path: &Path) -> Option<String> {
+        match self.strategy {
+            ChapterStrategy::PerFile | ChapterStrategy::Filename => None,
+            ChapterStrategy::MergeTitles => Some(title.to_owned()),
+            ChapterStrategy::MergeRegex => {
+                let stem = file_stem(path);
+                self.regex.captures(&stem).map(|cap| {
+                    cap.get(1).or_else(|| cap.get(0)).map(|m| m.as_str().to_owned()).unwrap_or_default()
+                })
+            },
+        }
+    }
+}
+
+fn file_stem(path: &Path) -> String {
+    path.file_stem()
+        .map(|s| s.to_string_lossy().into_owned())
+        .unwrap_or_default()
+}
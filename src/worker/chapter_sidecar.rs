@@ -0,0 +1,158 @@
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use regex::Regex;
+use serde_json::{self, Value};
+
+use crate::worker::error::{Result, WorkerError};
+use crate::worker::mediafile::Chapter;
+
+lazy_static! {
+    /// `01:02:03.456 Title`, the format used by mp4chaps and Audible exports
+    static ref SIMPLE_LINE: Regex = Regex::new(r"^((?:\d+:)?\d+:\d+(?:\.\d+)?)\s+(.*)$").unwrap();
+    /// `CHAPTER01=01:02:03.456` followed by `CHAPTER01NAME=Title`, the OGM format
+    static ref OGM_LINE: Regex = Regex::new(r"^CHAPTER(\d+)(NAME)?=(.*)$").unwrap();
+}
+
+/// Find a chapter list belonging to a book. Multifile books have a `chapters.txt` or
+/// `chapters.json` in their directory, single files a `book.chapters.txt` or `book.chapters.json`
+/// next to them.
+pub fn find_for(book_path: &Path) -> Option<PathBuf> {
+    let candidates = if book_path.is_dir() {
+        vec![book_path.join("chapters.txt"), book_path.join("chapters.json")]
+    } else {
+        let stem = book_path.file_stem()?.to_string_lossy().into_owned();
+        vec![
+            book_path.with_file_name(format!("{}.chapters.txt", stem)),
+            book_path.with_file_name(format!("{}.chapters.json", stem)),
+        ]
+    };
+    candidates.into_iter().find(|p| p.is_file())
+}
+
+/// Read chapters from a sidecar, the format is picked by the file extension.
+pub fn read_file(path: &Path) -> Result<Vec<Chapter>> {
+    let mut content = String::new();
+    File::open(path)?.read_to_string(&mut content)?;
+    match path.extension().and_then(|e| e.to_str()) {
+        Some("json") => parse_json(&content),
+        _ => parse_text(&content),
+    }
+}
+
+pub fn parse_text(content: &str) -> Result<Vec<Chapter>> {
+    let mut chapters = Vec::new();
+    let mut ogm: BTreeMap<u32, (Option<f64>, Option<String>)> = BTreeMap::new();
+    for line in content.lines() {
+        let line = line.trim().trim_start_matches('\u{feff}');
+        if line.is_empty() || line.starts_with('#') { continue };
+        if let Some(cap) = OGM_LINE.captures(line) {
+            let number: u32 = cap[1].parse().map_err(|_| invalid_line(line))?;
+            let entry = ogm.entry(number).or_insert((None, None));
+            if cap.get(2).is_some() {
+                entry.1 = Some(cap[3].trim().to_owned());
+            } else {
+                entry.0 = Some(parse_timestamp(&cap[3]).ok_or_else(|| invalid_line(line))?);
+            }
+        } else if let Some(cap) = SIMPLE_LINE.captures(line) {
+            chapters.push(chapter(
+                parse_timestamp(&cap[1]).ok_or_else(|| invalid_line(line))?,
+                Some(cap[2].trim().to_owned())
+            ));
+        } else {
+            return Err(invalid_line(line));
+        }
+    }
+    for (_, (start, title)) in ogm {
+        match start {
+            Some(s) => chapters.push(chapter(s, title)),
+            None => return Err(WorkerError::Other {
+                description: format!("Chapter {:?} has a name but no start time", title)
+            }.into())
+        }
+    }
+    finish(chapters)
+}
+
+/// Accepts either a list of chapters or an object with a `chapters` list. Each chapter has a
+/// `title` and a start given as `start`/`start_time` (seconds or a timestamp string) or
+/// `start_offset_ms`/`startOffsetMs` (milliseconds, as exported from Audible).
+pub fn parse_json(content: &str) -> Result<Vec<Chapter>> {
+    let value: Value = serde_json::from_str(content)?;
+    let entries = match value {
+        Value::Array(entries) => entries,
+        Value::Object(mut object) => match object.remove("chapters") {
+            Some(Value::Array(entries)) => entries,
+            _ => return Err(invalid_json("no chapters list"))
+        },
+        _ => return Err(invalid_json("expected a list or an object"))
+    };
+    let chapters = entries.iter().map(|entry| -> Result<Chapter> {
+        let title = entry.get("title").and_then(Value::as_str).map(str::to_owned);
+        let millis = entry.get("start_offset_ms")
+            .or_else(|| entry.get("startOffsetMs"))
+            .and_then(Value::as_f64);
+        let start = match millis {
+            Some(ms) => Some(ms / 1000.0),
+            None => match entry.get("start").or_else(|| entry.get("start_time")) {
+                Some(&Value::Number(ref n)) => n.as_f64(),
+                Some(&Value::String(ref s)) => parse_timestamp(s),
+                _ => None
+            }
+        };
+        match start {
+            Some(s) => Ok(chapter(s, title)),
+            None => Err(invalid_json(&format!("chapter {:?} has no valid start", title)))
+        }
+    }).collect::<Result<Vec<Chapter>>>()?;
+    finish(chapters)
+}
+
+/// Parse `HH:MM:SS.fff`, `MM:SS.fff` or plain seconds.
+pub fn parse_timestamp(time: &str) -> Option<f64> {
+    let mut seconds = 0.0;
+    for part in time.trim().split(':') {
+        let value: f64 = part.parse().ok()?;
+        // `NaN` and `inf` parse as well
+        if !value.is_finite() || value < 0.0 { return None };
+        seconds = seconds * 60.0 + value;
+    }
+    Some(seconds)
+}
+
+fn chapter(start: f64, title: Option<String>) -> Chapter {
+    Chapter {
+        title,
+        metadata: HashMap::new(),
+        start,
+    }
+}
+
+fn finish(mut chapters: Vec<Chapter>) -> Result<Vec<Chapter>> {
+    if chapters.is_empty() {
+        return Err(WorkerError::Other {
+            description: "Chapter file contains no chapters".to_owned()
+        }.into());
+    }
+    if let Some(chapter) = chapters.iter().find(|c| !c.start.is_finite() || c.start < 0.0) {
+        return Err(WorkerError::Other {
+            description: format!("Chapter {:?} has an invalid start", chapter.title)
+        }.into());
+    }
+    chapters.sort_by(|a, b| a.start.partial_cmp(&b.start).unwrap_or(Ordering::Equal));
+    Ok(chapters)
+}
+
+fn invalid_line(line: &str) -> failure::Error {
+    WorkerError::Other {
+        description: format!("Invalid line in chapter file: {}", line)
+    }.into()
+}
+
+fn invalid_json(reason: &str) -> failure::Error {
+    WorkerError::Other {
+        description: format!("Invalid chapter JSON: {}", reason)
+    }.into()
+}
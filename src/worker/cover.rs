@@ -0,0 +1,141 @@
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use image::{self, DynamicImage, FilterType, GenericImage, ImageOutputFormat};
+
+use crate::worker::error::{Result, WorkerError};
+use crate::helpers::uuid::Uuid;
+use crate::worker::mediafile::{Image, ImageType};
+
+/// Names of cover images in book directories, most common first.
+const COVER_NAMES: &[&str] = &["cover", "folder", "front", "albumart", "album", "poster"];
+pub const IMAGE_EXTENSIONS: &[&str] = &["jpg", "jpeg", "png"];
+/// Edge lengths of the cached cover thumbnails.
+pub const THUMBNAIL_SIZES: &[u32] = &[64, 128, 256, 512];
+
+/// Find the cover image of a book. Multifile books have one in their directory, named like
+/// `cover.jpg` or `folder.png`. Single files can't use those as they share their directory with
+/// other books, theirs is named after them like `book.jpg` or `book.cover.png`.
+pub fn find_for(book_path: &Path) -> Option<PathBuf> {
+    if book_path.is_dir() {
+        let images: Vec<PathBuf> = fs::read_dir(book_path).ok()?
+            .filter_map(|e| e.ok())
+            .map(|e| e.path())
+            .filter(|p| p.is_file() && is_image(p))
+            .collect();
+        COVER_NAMES.iter().filter_map(|name| {
+            images.iter().find(|p| {
+                p.file_stem().map_or(false, |s| s.to_string_lossy().eq_ignore_ascii_case(name))
+            })
+        }).next().cloned()
+    } else {
+        let stem = book_path.file_stem()?.to_string_lossy().into_owned();
+        [stem.clone(), format!("{}.cover", stem)].iter()
+            .flat_map(|name| IMAGE_EXTENSIONS.iter().map(move |ext| format!("{}.{}", name, ext)))
+            .map(|name| book_path.with_file_name(name))
+            .find(|p| p.is_file())
+    }
+}
+
+pub fn is_image(path: &Path) -> bool {
+    match path.extension() {
+        Some(ext) => IMAGE_EXTENSIONS.iter().any(|e| ext.to_string_lossy().eq_ignore_ascii_case(e)),
+        None => false
+    }
+}
+
+/// Read an image file, the type is taken from its content as extensions are often wrong.
+pub fn read_image(path: &Path) -> Result<Image> {
+    let mut data = Vec::new();
+    File::open(path)?.read_to_end(&mut data)?;
+    let image_type = match ImageType::sniff(&data) {
+        Some(t) => t,
+        None => return Err(WorkerError::Other {
+            description: format!("{:?} is neither a PNG nor a JPEG image", path)
+        }.into())
+    };
+    Ok(Image { data, image_type })
+}
+
+/// The smallest thumbnail at least as large as requested, `None` if only the original will do.
+pub fn thumbnail_size(requested: u32) -> Option<u32> {
+    THUMBNAIL_SIZES.iter().cloned().find(|s| *s >= requested)
+}
+
+/// Where the scanner saves the cover of a book.
+pub fn saved_path(data_directory: &str, book_id: &Uuid) -> PathBuf {
+    let mut path = PathBuf::from(data_directory);
+    path.push("img");
+    path.push(book_id.hyphenated().to_string());
+    path
+}
+
+/// Where a cover uploaded by an admin is kept, apart from the scanned one so rescans leave it be.
+pub fn uploaded_path(data_directory: &str, book_id: &Uuid) -> PathBuf {
+    saved_path(data_directory, book_id).with_extension("uploaded")
+}
+
+fn thumbnail_path(cover: &Path, size: u32) -> PathBuf {
+    let mut name = cover.file_name().unwrap_or_default().to_owned();
+    name.push(format!("_{}.jpg", size));
+    cover.with_file_name(name)
+}
+
+/// Path of a JPEG thumbnail of a saved cover fitting into `size`×`size` pixels. It is created next
+/// to the cover on first use and again whenever the cover is newer than it.
+pub fn thumbnail(cover: &Path, size: u32) -> Result<PathBuf> {
+    let path = thumbnail_path(cover, size);
+    let cover_changed = fs::metadata(cover)?.modified()?;
+    if let Ok(meta) = fs::metadata(&path) {
+        if meta.modified()? >= cover_changed {
+            return Ok(path);
+        }
+    }
+
+    let original = image::load_from_memory(&fs::read(cover)?)?;
+    // concurrent requests each write their own file, the rename makes the last one win
+    write_jpeg(&original, size, 85, &path)?;
+    Ok(path)
+}
+
+/// Uploaded covers are scaled down to this many pixels at most.
+const MAX_UPLOAD_SIZE: u32 = 2048;
+
+/// Store an uploaded cover as JPEG, after making sure it is an image at all.
+pub fn save_upload(data: &[u8], dest: &Path) -> Result<()> {
+    let image = image::load_from_memory(data)?;
+    if let Some(dir) = dest.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    write_jpeg(&image, MAX_UPLOAD_SIZE, 90, dest)
+}
+
+/// Remove a cover and all its thumbnails.
+pub fn remove_with_thumbnails(cover: &Path) -> Result<()> {
+    let paths = THUMBNAIL_SIZES.iter().map(|s| thumbnail_path(cover, *s));
+    for path in Some(cover.to_path_buf()).into_iter().chain(paths) {
+        if let Err(e) = fs::remove_file(&path) {
+            if e.kind() != io::ErrorKind::NotFound {
+                return Err(e.into());
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Write `image` as JPEG fitting into `size`×`size` pixels. The file is written under a temporary
+/// name first, so nobody ever reads a half written image.
+fn write_jpeg(image: &DynamicImage, size: u32, quality: u8, dest: &Path) -> Result<()> {
+    let (width, height) = image.dimensions();
+    let resized = if width > size || height > size {
+        image.resize(size, size, FilterType::Triangle)
+    } else {
+        image.clone()
+    };
+    // JPEG has no alpha channel
+    let resized = DynamicImage::ImageRgb8(resized.to_rgb());
+    let temporary = dest.with_extension(format!("{}.tmp", Uuid::new_v4().hyphenated()));
+    resized.write_to(&mut File::create(&temporary)?, ImageOutputFormat::JPEG(quality))?;
+    fs::rename(&temporary, dest)?;
+    Ok(())
+}
@@ -0,0 +1,195 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use crate::worker::error::{Result, WorkerError};
+use crate::worker::mediafile::Chapter;
+
+/// CUE sheets count time in frames, there are 75 of them per second.
+const FRAMES_PER_SECOND: f64 = 75.0;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CueTrack {
+    pub number: u32,
+    pub title: Option<String>,
+    pub performer: Option<String>,
+    /// Start of the track in seconds, taken from `INDEX 01`
+    pub start: f64,
+    /// The `FILE` this track is part of
+    pub file: Option<String>,
+}
+
+/// The parts of a CUE sheet that matter to us, everything else is ignored.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct CueSheet {
+    pub title: Option<String>,
+    pub performer: Option<String>,
+    pub tracks: Vec<CueTrack>,
+}
+
+impl CueSheet {
+    pub fn read_file(path: &dyn AsRef<Path>) -> Result<Self> {
+        let mut content = Vec::new();
+        File::open(path.as_ref())?.read_to_end(&mut content)?;
+        Self::parse(&decode(&content))
+    }
+
+    pub fn parse(content: &str) -> Result<Self> {
+        let mut sheet = CueSheet::default();
+        let mut current_file: Option<String> = None;
+        // INDEX 00 marks the pregap, it is only used for tracks without INDEX 01
+        let mut pregap: Option<f64> = None;
+        for (line_number, line) in content.lines().enumerate() {
+            let args = split_args(line.trim_start_matches('\u{feff}'));
+            let command = match args.first() {
+                Some(c) => c.to_uppercase(),
+                None => continue,
+            };
+            let invalid = || WorkerError::Other {
+                description: format!("Invalid CUE sheet line {}: {}", line_number + 1, line.trim())
+            };
+            match command.as_str() {
+                "FILE" => current_file = args.get(1).cloned(),
+                "TRACK" => {
+                    let number = args.get(1).and_then(|n| n.parse().ok()).ok_or_else(invalid)?;
+                    if let Some(start) = pregap.take() {
+                        fill_missing_start(&mut sheet, start);
+                    }
+                    sheet.tracks.push(CueTrack {
+                        number,
+                        title: None,
+                        performer: None,
+                        start: -1.0,
+                        file: current_file.clone(),
+                    });
+                },
+                "TITLE" | "PERFORMER" => {
+                    let value = args.get(1).cloned();
+                    let is_title = command == "TITLE";
+                    match (sheet.tracks.last_mut(), is_title) {
+                        (Some(track), true) => track.title = value,
+                        (Some(track), false) => track.performer = value,
+                        (None, true) => sheet.title = value,
+                        (None, false) => sheet.performer = value,
+                    }
+                },
+                "INDEX" => {
+                    let index: u32 = args.get(1).and_then(|n| n.parse().ok()).ok_or_else(invalid)?;
+                    let time = args.get(2).and_then(|t| parse_time(t)).ok_or_else(invalid)?;
+                    match (index, sheet.tracks.last_mut()) {
+                        (1, Some(track)) => {
+                            track.start = time;
+                            pregap = None;
+                        },
+                        (0, Some(_)) => pregap = Some(time),
+                        _ => {},
+                    }
+                },
+                _ => {},
+            }
+        }
+        if let Some(start) = pregap {
+            fill_missing_start(&mut sheet, start);
+        }
+        sheet.tracks.retain(|t| t.start >= 0.0);
+        Ok(sheet)
+    }
+
+    /// Chapters for the audio file with the given file name.
+    /// Sheets referencing only a single file are assumed to belong to the audio file, even when
+    /// names differ, as files are often renamed without updating the sheet.
+    pub fn chapters_for(&self, file_name: &str) -> Vec<Chapter> {
+        let mut files: Vec<&Option<String>> = self.tracks.iter().map(|t| &t.file).collect();
+        files.dedup();
+        let single_file = files.len() <= 1;
+        self.tracks.iter()
+            .filter(|t| single_file || t.file.as_ref().map(|f| file_name_of(f) == file_name).unwrap_or(false))
+            .map(|t| {
+                let mut metadata = HashMap::new();
+                if let Some(ref performer) = t.performer {
+                    metadata.insert("artist".to_owned(), performer.clone());
+                }
+                Chapter {
+                    title: t.title.clone(),
+                    metadata,
+                    start: t.start,
+                }
+            })
+            .collect()
+    }
+}
+
+/// Find a CUE sheet belonging to an audio file: either `book.cue` or `book.mp3.cue`.
+pub fn find_for(audio_path: &Path) -> Option<PathBuf> {
+    let file_name = audio_path.file_name()?.to_string_lossy().into_owned();
+    let candidates = vec![
+        audio_path.with_extension("cue"),
+        audio_path.with_extension("CUE"),
+        audio_path.with_file_name(format!("{}.cue", file_name)),
+    ];
+    candidates.into_iter().find(|p| p.is_file())
+}
+
+fn fill_missing_start(sheet: &mut CueSheet, start: f64) {
+    if let Some(track) = sheet.tracks.last_mut() {
+        if track.start < 0.0 {
+            track.start = start;
+        }
+    }
+}
+
+/// Strip directories from a `FILE` entry, these may use either kind of slash.
+fn file_name_of(entry: &str) -> &str {
+    entry.rsplit(|c| c == '/' || c == '\\').next().unwrap_or(entry)
+}
+
+/// Parse `mm:ss:ff` into seconds.
+fn parse_time(time: &str) -> Option<f64> {
+    let parts: Vec<u64> = time.split(':').map(|p| p.parse().ok()).collect::<Option<_>>()?;
+    match parts.as_slice() {
+        [minutes, seconds, frames] => Some(
+            (minutes * 60 + seconds) as f64 + *frames as f64 / FRAMES_PER_SECOND
+        ),
+        _ => None,
+    }
+}
+
+/// Split a line into whitespace separated arguments, double quotes group arguments.
+fn split_args(line: &str) -> Vec<String> {
+    let mut args = Vec::new();
+    let mut current = String::new();
+    let mut in_quotes = false;
+    let mut has_arg = false;
+    for c in line.chars() {
+        match c {
+            '"' => {
+                in_quotes = !in_quotes;
+                has_arg = true;
+            },
+            c if c.is_whitespace() && !in_quotes => {
+                if has_arg {
+                    args.push(current.clone());
+                    current.clear();
+                    has_arg = false;
+                }
+            },
+            c => {
+                current.push(c);
+                has_arg = true;
+            }
+        }
+    }
+    if has_arg {
+        args.push(current);
+    }
+    args
+}
+
+/// CUE sheets are frequently written in Latin-1 instead of UTF-8.
+fn decode(content: &[u8]) -> String {
+    match String::from_utf8(content.to_owned()) {
+        Ok(s) => s,
+        Err(_) => content.iter().map(|&b| b as char).collect(),
+    }
+}
@@ -16,6 +16,17 @@ pub fn checksum_file(path: &dyn AsRef<Path>) -> Result<Vec<u8>> {
     Ok(res)
 }
 
+/// Checksum of some values for names of cached files, which have to stay the same across
+/// releases. Every value is prefixed with its length, so neighbouring values can't run together.
+pub fn cache_key(values: &[&[u8]]) -> String {
+    let mut ctx = digest::Context::new(&digest::SHA256);
+    for value in values {
+        ctx.update(&(value.len() as u64).to_be_bytes());
+        ctx.update(value);
+    }
+    ctx.finish().as_ref()[..8].iter().map(|b| format!("{:02x}", b)).collect()
+}
+
 /// Update hash object using file content
 fn update_hash_from_file(ctx: &mut digest::Context, path: &dyn AsRef<Path>) -> Result<()> {
     let mut file = File::open(path.as_ref())?;
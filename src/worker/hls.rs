@@ -0,0 +1,145 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::helpers::uuid::Uuid;
+use crate::models::chapter::Chapter;
+use crate::worker::error::Result;
+use crate::worker::muxer;
+
+/// Segments are at most this many seconds long.
+pub const TARGET_DURATION: f64 = 10.0;
+
+/// A slice of a book served as one HLS segment.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Segment {
+    pub start: f64,
+    pub end: f64,
+    /// Title of the chapter the segment belongs to.
+    pub title: Option<String>,
+}
+
+impl Segment {
+    pub fn duration(&self) -> f64 {
+        self.end - self.start
+    }
+
+    /// Name of the cached segment, made of its bounds so moving chapters doesn't serve stale ones.
+    fn file_name(&self) -> String {
+        format!("{}-{}.ts", (self.start * 1000.0).round() as u64, (self.end * 1000.0).round() as u64)
+    }
+}
+
+/// Split a book into segments of at most `TARGET_DURATION` seconds. Every chapter starts a new
+/// segment, so clients can jump to a chapter without fetching the end of the previous one.
+/// Chapters are split into segments of equal length.
+pub fn segments(chapters: &[Chapter], length: f64) -> Vec<Segment> {
+    let mut bounds: Vec<(f64, Option<&String>)> = vec![(0.0, None)];
+    for chapter in chapters {
+        if chapter.start_time <= 0.0 {
+            bounds[0].1 = chapter.title.as_ref();
+        } else if chapter.start_time < length {
+            bounds.push((chapter.start_time, chapter.title.as_ref()));
+        }
+    }
+    bounds.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap());
+
+    let mut segments = Vec::new();
+    for (i, &(start, title)) in bounds.iter().enumerate() {
+        let end = bounds.get(i + 1).map(|b| b.0).unwrap_or(length);
+        if end <= start {
+            continue;
+        }
+        let count = ((end - start) / TARGET_DURATION).ceil().max(1.0) as usize;
+        let bound = |k: usize| if k == count {
+            end
+        } else {
+            start + (end - start) * k as f64 / count as f64
+        };
+        segments.extend((0..count).map(|k| Segment {
+            start: bound(k),
+            end: bound(k + 1),
+            title: title.cloned(),
+        }));
+    }
+    segments
+}
+
+/// A VOD media playlist of the segments, which are referred to as `<index>.ts` followed by
+/// `query`.
+pub fn playlist(segments: &[Segment], query: &str) -> String {
+    let target = segments.iter()
+        .map(|s| s.duration().ceil() as u64)
+        .max()
+        .unwrap_or(TARGET_DURATION as u64);
+    let mut playlist = format!(
+        "#EXTM3U\n#EXT-X-VERSION:3\n#EXT-X-TARGETDURATION:{}\n#EXT-X-MEDIA-SEQUENCE:0\n#EXT-X-PLAYLIST-TYPE:VOD\n",
+        target
+    );
+    for (i, segment) in segments.iter().enumerate() {
+        let title = segment.title.as_ref()
+            .map(|t| t.replace(|c| c == '\r' || c == '\n', " "))
+            .unwrap_or_default();
+        playlist.push_str(&format!("#EXTINF:{:.3},{}\n{}.ts{}\n", segment.duration(), title, i, query));
+    }
+    playlist.push_str("#EXT-X-ENDLIST\n");
+    playlist
+}
+
+/// Where the segments of a book are cached.
+pub fn cache_directory(data_directory: &str, book_id: &Uuid) -> PathBuf {
+    let mut path = PathBuf::from(data_directory);
+    path.push("hls");
+    path.push(book_id.hyphenated().to_string());
+    path
+}
+
+/// Path of an MPEG-TS file holding the segment. It is remuxed from the data file on first use and
+/// again whenever the data file is newer than it.
+pub fn segment_file(data_file: &Path, cache_directory: &Path, segment: &Segment) -> Result<PathBuf> {
+    let path = cache_directory.join(segment.file_name());
+    let data_changed = fs::metadata(data_file)?.modified()?;
+    if let Ok(meta) = fs::metadata(&path) {
+        if meta.modified()? >= data_changed {
+            return Ok(path);
+        }
+    }
+
+    fs::create_dir_all(cache_directory)?;
+    // concurrent requests each write their own file, the rename makes the last one win
+    let temporary = path.with_extension(format!("{}.ts", Uuid::new_v4().hyphenated()));
+    muxer::remux_slice(&temporary, data_file, segment.start, segment.end)?;
+    fs::rename(&temporary, &path)?;
+    remove_overlapping(cache_directory, &path, segment);
+    Ok(path)
+}
+
+/// Bounds in milliseconds of a cached segment, `None` for other files like those being written.
+fn bounds_of(file_name: &str) -> Option<(u64, u64)> {
+    if !file_name.ends_with(".ts") {
+        return None;
+    }
+    let mut bounds = file_name[..file_name.len() - 3].split('-').map(|b| b.parse::<u64>().ok());
+    match (bounds.next(), bounds.next(), bounds.next()) {
+        (Some(Some(start)), Some(Some(end)), None) => Some((start, end)),
+        _ => None
+    }
+}
+
+/// Remove segments cut before the chapters changed, they overlap the segment written to `path`.
+fn remove_overlapping(cache_directory: &Path, path: &Path, segment: &Segment) {
+    let (start, end) = ((segment.start * 1000.0).round() as u64, (segment.end * 1000.0).round() as u64);
+    let entries = match fs::read_dir(cache_directory) {
+        Ok(entries) => entries,
+        Err(_) => return
+    };
+    for stale in entries.filter_map(|e| e.ok()).map(|e| e.path()) {
+        let overlaps = stale.file_name()
+            .and_then(|n| bounds_of(&n.to_string_lossy()))
+            .map_or(false, |(s, e)| s < end && start < e);
+        if overlaps && stale != path {
+            if let Err(e) = fs::remove_file(&stale) {
+                warn!("Could not remove stale segment {:?}: {}", stale, e);
+            }
+        }
+    }
+}
@@ -16,10 +16,16 @@ use crate::ffmpeg::{
     AVProbeData,
     av_probe_input_format,
     av_read_frame,
+    av_packet_unref,
+    AVDiscard,
     AV_TIME_BASE_Q,
+    AV_TIME_BASE,
+    AVSEEK_FLAG_BACKWARD,
+    av_seek_frame,
     AVERROR_EOF,
 };
 
+use std::cmp;
 use std::mem;
 use std::ffi::{CStr, CString};
 use std::ptr;
@@ -31,6 +37,7 @@ use std::fmt::{Formatter, Debug};
 use std::str::Split;
 use crate::worker::error::{Result, WorkerError};
 use crate::worker::util::string_from_ptr;
+use crate::worker::virtual_file::{self, VirtualFile};
 use std::fmt;
 use std::error;
 use std::result;
@@ -48,6 +55,19 @@ pub struct Image {
     pub image_type: ImageType
 }
 
+impl ImageType {
+    /// Tell the type of an image from its first bytes.
+    pub fn sniff(data: &[u8]) -> Option<ImageType> {
+        if data.starts_with(b"\x89PNG\r\n\x1a\n") {
+            Some(ImageType::PNG)
+        } else if data.starts_with(b"\xff\xd8\xff") {
+            Some(ImageType::JPG)
+        } else {
+            None
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct MediaInfo {
     pub length: f64,
@@ -123,12 +143,18 @@ impl Debug for MediaFile {
 impl MediaFile {
     pub fn read_file(file_name: &Path) -> Result<Self> {
         let file_name_str = match file_name.to_str() {
-            Some(s) => s,
+            Some(s) => s.to_owned(),
             None => return Err(WorkerError::InvalidUtf8.into())
         };
+        // a manifest is opened as the stream made of its parts
+        let url = if virtual_file::is_manifest(file_name) {
+            VirtualFile::read(file_name)?.ffmpeg_url()
+        } else {
+            file_name_str
+        };
         unsafe {
             ensure_av_register_all();
-            let c_file_name = CString::new(file_name_str).expect("Null byte in filename.");
+            let c_file_name = CString::new(url).expect("Null byte in filename.");
             let mut new = Self {
                 path: file_name.to_owned(),
                 ctx: avformat_alloc_context(),
@@ -198,6 +224,15 @@ impl MediaFile {
         }
     }
 
+    /// Seek to the last keyframe at or before `seconds`, the next packets read start there.
+    pub fn seek(&self, seconds: f64) -> Result<()> {
+        let timestamp = (seconds * f64::from(AV_TIME_BASE)) as i64;
+        unsafe {
+            check_av_result(av_seek_frame(self.ctx, -1, timestamp, AVSEEK_FLAG_BACKWARD as _))?;
+        }
+        Ok(())
+    }
+
     pub fn has_audio_track(&self) -> bool {
         match self.get_best_stream(AVMediaType::AVMEDIA_TYPE_AUDIO) {
             Err(_) => false,
@@ -234,8 +269,63 @@ impl MediaFile {
         }
     }
 
+    /// Chapters of the file. QuickTime chapter tracks referenced by the audio track are turned into
+    /// regular chapters by ffmpeg, if there are none we look for a stray text track instead.
     pub fn get_chapters(&self) -> Vec<Chapter> {
-        Chapter::from_av_chapters(self.av_chapter_slice())
+        let chapters = Chapter::from_av_chapters(self.av_chapter_slice());
+        if !chapters.is_empty() || self.text_stream().is_none() {
+            return chapters;
+        }
+        // reading the text track consumes packets, so use a fresh instance
+        match MediaFile::read_file(&self.path).and_then(|f| f.read_text_track_chapters()) {
+            Ok(text_chapters) => text_chapters,
+            Err(e) => {
+                warn!("Could not read text track of {:?}: {}", self.path, e);
+                chapters
+            }
+        }
+    }
+
+    fn text_stream(&self) -> Option<&AVStream> {
+        self.get_streams().iter().cloned().find(|s| unsafe {
+            match (*s.codecpar).codec_id {
+                AVCodecID::AV_CODEC_ID_MOV_TEXT | AVCodecID::AV_CODEC_ID_TEXT => true,
+                _ => false
+            }
+        })
+    }
+
+    /// Every sample of a text track is the title of a chapter starting at the sample's timestamp.
+    fn read_text_track_chapters(&self) -> Result<Vec<Chapter>> {
+        let (index, time_base, codec_id) = match self.text_stream() {
+            Some(s) => unsafe { (s.index, s.time_base, (*s.codecpar).codec_id) },
+            None => return Ok(Vec::new())
+        };
+        unsafe {
+            // skip decoding the audio
+            let streams = slice::from_raw_parts((*self.ctx).streams, (*self.ctx).nb_streams as usize);
+            for &stream in streams {
+                if (*stream).index != index {
+                    (*stream).discard = AVDiscard::AVDISCARD_ALL;
+                }
+            }
+        }
+        let mut chapters = Vec::new();
+        while let Some(mut pkt) = self.read_packet()? {
+            if pkt.stream_index == index && pkt.size > 0 {
+                let data = unsafe { slice::from_raw_parts(pkt.data, pkt.size as usize) };
+                // the minimum of an i64 is AV_NOPTS_VALUE
+                let timestamp = if pkt.pts == i64::min_value() { pkt.dts } else { pkt.pts };
+                chapters.push(Chapter {
+                    title: text_sample(data, codec_id),
+                    metadata: HashMap::new(),
+                    start: apply_timebase(timestamp, time_base),
+                });
+            }
+            unsafe { av_packet_unref(&mut pkt) };
+        }
+        chapters.sort_by(|a, b| a.start.partial_cmp(&b.start).unwrap());
+        Ok(chapters)
     }
 
     fn av_chapter_slice(&self) -> &[&AVChapter] {
@@ -286,6 +376,20 @@ impl MediaFile {
     }
 }
 
+/// Text of a text track sample. QuickTime samples start with the length of the text, which may be
+/// followed by style information.
+fn text_sample(data: &[u8], codec_id: AVCodecID) -> Option<String> {
+    let text = match codec_id {
+        AVCodecID::AV_CODEC_ID_MOV_TEXT if data.len() >= 2 => {
+            let length = (usize::from(data[0]) << 8) | usize::from(data[1]);
+            &data[2..cmp::min(2 + length, data.len())]
+        },
+        _ => data
+    };
+    let title = String::from_utf8_lossy(text).trim().to_owned();
+    if title.is_empty() { None } else { Some(title) }
+}
+
 impl Drop for MediaFile {
     fn drop(&mut self) {
         if self.averror == 0 {
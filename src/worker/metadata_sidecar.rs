@@ -0,0 +1,281 @@
+use std::fs::{self, File};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use regex::Regex;
+use serde_json::{self, Value};
+
+use crate::worker::error::{Result, WorkerError};
+use crate::worker::tags::{self, BookTags};
+
+lazy_static! {
+    /// Dublin Core elements of an OPF file, the content runs up to the next closing `dc` tag
+    static ref OPF_ELEMENT: Regex = Regex::new(
+        r"(?s)<dc:(title|creator|description|publisher|date|language|subject|identifier)\b([^>]*)>(.*?)</dc:"
+    ).unwrap();
+    static ref OPF_META: Regex = Regex::new(r"<meta\b([^>]*?)/?>").unwrap();
+    static ref XML_ATTRIBUTE: Regex = Regex::new(r#"([\w:-]+)\s*=\s*"([^"]*)""#).unwrap();
+    static ref XML_TAG: Regex = Regex::new(r"<[^>]*>").unwrap();
+    /// `Title..........: The Book` lines of release .nfo files
+    static ref NFO_LINE: Regex = Regex::new(r"^\s*([A-Za-z][A-Za-z ]*?)[\s.]*:\s*(.+?)\s*$").unwrap();
+    static ref YEAR: Regex = Regex::new(r"\b(1[5-9]|20)\d\d\b").unwrap();
+    /// `The Expanse #3` or `The Expanse, Book 3`
+    static ref SERIES_WITH_INDEX: Regex = Regex::new(
+        r"(?i)^(.+?)\s*(?:#|,\s*(?:book|vol(?:ume)?|part)\.?\s*)(\d+(?:\.\d+)?)$"
+    ).unwrap();
+}
+
+/// Find the metadata files of a book, ordered by increasing precedence: an `.nfo`, then a
+/// `metadata.opf`, then a `metadata.json`. Multifile books keep them in their directory, single
+/// files as `book.nfo`, `book.opf` and `book.metadata.json` next to them.
+pub fn find_for(book_path: &Path) -> Vec<PathBuf> {
+    let candidates = if book_path.is_dir() {
+        let mut nfos: Vec<PathBuf> = fs::read_dir(book_path).into_iter()
+            .flat_map(|entries| entries.filter_map(|e| e.ok()).map(|e| e.path()))
+            .filter(|p| p.extension().map_or(false, |e| e.to_string_lossy().eq_ignore_ascii_case("nfo")))
+            .collect();
+        nfos.sort();
+        nfos.truncate(1);
+        nfos.push(book_path.join("metadata.opf"));
+        nfos.push(book_path.join("metadata.json"));
+        nfos
+    } else {
+        let stem = match book_path.file_stem() {
+            Some(s) => s.to_string_lossy().into_owned(),
+            None => return Vec::new()
+        };
+        vec![
+            book_path.with_file_name(format!("{}.nfo", stem)),
+            book_path.with_file_name(format!("{}.opf", stem)),
+            book_path.with_file_name(format!("{}.metadata.json", stem)),
+        ]
+    };
+    candidates.into_iter().filter(|p| p.is_file()).collect()
+}
+
+/// Read book metadata from a sidecar, the format is picked by the file extension.
+/// Fields the file doesn't mention are `None`.
+pub fn read_file(path: &Path) -> Result<BookTags> {
+    let mut bytes = Vec::new();
+    File::open(path)?.read_to_end(&mut bytes)?;
+    // .nfo files are frequently written in some legacy code page
+    let content = String::from_utf8_lossy(&bytes);
+    match path.extension().map(|e| e.to_string_lossy().to_lowercase()) {
+        Some(ref e) if e == "json" => parse_json(&content),
+        Some(ref e) if e == "opf" => parse_opf(&content),
+        _ => Ok(parse_nfo(&content)),
+    }
+}
+
+/// Replace the values of `tags` with those the sidecar provides.
+pub fn merge(tags: &mut BookTags, sidecar: BookTags) {
+    fn over<T>(value: &mut Option<T>, new: Option<T>) {
+        if new.is_some() {
+            *value = new;
+        }
+    }
+    // a series from the sidecar comes with its own index, or none at all
+    if sidecar.series.is_some() {
+        tags.series_index = None;
+    }
+    over(&mut tags.title, sidecar.title);
+    over(&mut tags.album, sidecar.album);
+    over(&mut tags.author, sidecar.author);
+    over(&mut tags.narrator, sidecar.narrator);
+    over(&mut tags.description, sidecar.description);
+    over(&mut tags.year, sidecar.year);
+    over(&mut tags.genre, sidecar.genre);
+    over(&mut tags.series, sidecar.series);
+    over(&mut tags.series_index, sidecar.series_index);
+    over(&mut tags.publisher, sidecar.publisher);
+    over(&mut tags.language, sidecar.language);
+    over(&mut tags.isbn, sidecar.isbn);
+    over(&mut tags.asin, sidecar.asin);
+}
+
+/// The `metadata.json` written by Audiobookshelf, people are a list of names or of objects with a
+/// `name`, `series` a list of `Name #3` strings. Plain strings and snake case keys work as well.
+pub fn parse_json(content: &str) -> Result<BookTags> {
+    let value: Value = serde_json::from_str(content)?;
+    let object = match value {
+        Value::Object(object) => object,
+        _ => return Err(WorkerError::Other {
+            description: "Invalid metadata JSON: expected an object".to_owned()
+        }.into())
+    };
+    let first = |keys: &[&str]| -> Option<Vec<String>> {
+        keys.iter().filter_map(|k| object.get(*k)).map(json_strings).find(|v| !v.is_empty())
+    };
+    let text = |keys: &[&str], separator: &str| first(keys).map(|v| v.join(separator));
+
+    let mut tags = BookTags {
+        title: text(&["title"], " "),
+        author: text(&["authors", "author"], ", "),
+        narrator: text(&["narrators", "narrator"], ", "),
+        description: text(&["description"], "\n"),
+        year: text(&["publishedYear", "published_year", "year", "publishedDate", "date"], " ")
+            .and_then(|y| tags::parse_year(&y)),
+        genre: text(&["genres", "genre"], ";"),
+        publisher: text(&["publisher"], ", "),
+        language: text(&["language"], ", "),
+        isbn: text(&["isbn"], ", "),
+        asin: text(&["asin"], ", "),
+        ..BookTags::default()
+    };
+    match object.get("series") {
+        Some(&Value::Array(ref entries)) if !entries.is_empty() => match entries[0] {
+            Value::Object(ref series) => {
+                tags.series = series.get("name").and_then(Value::as_str).map(str::to_owned);
+                tags.series_index = series.get("sequence").and_then(json_number);
+            },
+            ref other => set_series(&mut tags, &json_strings(other).join(" ")),
+        },
+        Some(other) => set_series(&mut tags, &json_strings(other).join(" ")),
+        None => {},
+    }
+    if let Some(index) = ["seriesIndex", "series_index", "sequence"].iter()
+        .filter_map(|k| object.get(*k)).filter_map(json_number).next() {
+        tags.series_index = Some(index);
+    }
+    Ok(tags)
+}
+
+/// Calibre style OPF package documents. Creators with the role `nrt` are narrators, all others
+/// authors. The series is read from the `calibre:series` meta tags.
+pub fn parse_opf(content: &str) -> Result<BookTags> {
+    if !content.contains("<dc:") {
+        return Err(WorkerError::Other {
+            description: "Invalid OPF file: no Dublin Core metadata".to_owned()
+        }.into());
+    }
+    let mut tags = BookTags::default();
+    let mut authors = Vec::new();
+    let mut narrators = Vec::new();
+    let mut genres = Vec::new();
+    for cap in OPF_ELEMENT.captures_iter(content) {
+        let attributes = xml_attribute_map(&cap[2]);
+        let value = unescape_xml(&cap[3]);
+        if value.is_empty() { continue };
+        match &cap[1] {
+            "title" => { tags.title.get_or_insert(value); },
+            "creator" => match attributes.iter().find(|a| a.0 == "role") {
+                Some(&(_, ref role)) if role == "nrt" => narrators.push(value),
+                _ => authors.push(value),
+            },
+            // descriptions are HTML, escaped once more inside the XML
+            "description" => tags.description = Some(unescape_xml(&XML_TAG.replace_all(&value, " "))
+                .split_whitespace().collect::<Vec<_>>().join(" ")),
+            "publisher" => tags.publisher = Some(value),
+            "date" => tags.year = tags::parse_year(&value),
+            "language" => tags.language = Some(value),
+            "subject" => genres.push(value),
+            "identifier" => {
+                let scheme = attributes.iter().find(|a| a.0 == "scheme")
+                    .map(|a| a.1.to_lowercase())
+                    .unwrap_or_default();
+                let lower = value.to_lowercase();
+                if scheme == "isbn" {
+                    tags.isbn = Some(value);
+                } else if lower.starts_with("urn:isbn:") {
+                    tags.isbn = Some(value["urn:isbn:".len()..].to_owned());
+                } else if scheme == "asin" || scheme == "mobi-asin" || scheme == "amazon" {
+                    tags.asin = Some(value);
+                }
+            },
+            _ => {}
+        }
+    }
+    for cap in OPF_META.captures_iter(content) {
+        let attributes = xml_attribute_map(&cap[1]);
+        let get = |name: &str| attributes.iter().find(|a| a.0 == name).map(|a| unescape_xml(&a.1));
+        match get("name").as_ref().map(String::as_str) {
+            Some("calibre:series") => tags.series = get("content").filter(|s| !s.is_empty()),
+            Some("calibre:series_index") => tags.series_index = get("content").and_then(|i| tags::parse_index(&i)),
+            _ => {}
+        }
+    }
+    if !authors.is_empty() { tags.author = Some(authors.join(", ")) };
+    if !narrators.is_empty() { tags.narrator = Some(narrators.join(", ")) };
+    if !genres.is_empty() { tags.genre = Some(genres.join(";")) };
+    Ok(tags)
+}
+
+/// `Key: Value` lines as found in the .nfo files of audiobook releases. Unknown keys and
+/// everything else, like ASCII art, is ignored.
+pub fn parse_nfo(content: &str) -> BookTags {
+    let mut tags = BookTags::default();
+    for line in content.lines() {
+        let cap = match NFO_LINE.captures(line) {
+            Some(cap) => cap,
+            None => continue
+        };
+        let key = cap[1].to_lowercase();
+        let value = cap[2].to_owned();
+        match key.as_str() {
+            "title" | "book title" => tags.title = Some(value),
+            "author" | "authors" | "written by" | "writer" => tags.author = Some(value),
+            "narrator" | "narrators" | "read by" | "narrated by" | "reader" => tags.narrator = Some(value),
+            "genre" | "genres" => tags.genre = Some(value),
+            "publisher" => tags.publisher = Some(value),
+            "year" | "release date" | "published" | "copyright" | "date" => {
+                if let Some(year) = YEAR.find(&value) {
+                    tags.year = year.as_str().parse().ok();
+                }
+            },
+            "series" => set_series(&mut tags, &value),
+            "language" => tags.language = Some(value),
+            "isbn" => tags.isbn = Some(value),
+            "asin" => tags.asin = Some(value),
+            "description" | "summary" | "synopsis" => tags.description = Some(value),
+            _ => {}
+        }
+    }
+    tags
+}
+
+fn set_series(tags: &mut BookTags, series: &str) {
+    let series = series.trim();
+    if series.is_empty() { return };
+    match SERIES_WITH_INDEX.captures(series) {
+        Some(cap) => {
+            tags.series = Some(cap[1].trim().to_owned());
+            tags.series_index = cap[2].parse().ok();
+        },
+        None => tags.series = Some(series.to_owned())
+    }
+}
+
+fn json_strings(value: &Value) -> Vec<String> {
+    match *value {
+        Value::String(ref s) if !s.trim().is_empty() => vec![s.trim().to_owned()],
+        Value::Number(ref n) => vec![n.to_string()],
+        Value::Array(ref values) => values.iter().flat_map(json_strings).collect(),
+        Value::Object(ref object) => object.get("name").map(json_strings).unwrap_or_default(),
+        _ => Vec::new()
+    }
+}
+
+fn json_number(value: &Value) -> Option<f64> {
+    match *value {
+        Value::Number(ref n) => n.as_f64(),
+        Value::String(ref s) => tags::parse_index(s),
+        _ => None
+    }
+}
+
+/// Attributes without their namespace prefix, `opf:role` becomes `role`.
+fn xml_attribute_map(attributes: &str) -> Vec<(String, String)> {
+    XML_ATTRIBUTE.captures_iter(attributes).map(|cap| {
+        let name = cap[1].rsplit(':').next().unwrap_or("").to_owned();
+        (name, cap[2].to_owned())
+    }).collect()
+}
+
+fn unescape_xml(text: &str) -> String {
+    text.trim()
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&#39;", "'")
+        .replace("&amp;", "&")
+}
@@ -1,9 +1,22 @@
 pub mod muxer;
+pub mod hls;
+pub mod chapter_audio;
+pub mod transcoder;
+pub mod transcode_cache;
 pub mod mediafile;
+pub mod tags;
 pub mod error;
 pub mod scanner;
+pub mod chapter_rules;
+pub mod cue;
+pub mod chapter_sidecar;
+pub mod cover;
+pub mod palette;
+pub mod metadata_sidecar;
+pub mod silence;
 pub mod util;
 pub mod hashing;
+pub mod virtual_file;
 #[cfg(test)]
 pub mod tests;
 #[cfg(test)]
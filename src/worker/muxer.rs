@@ -2,13 +2,19 @@ use crate::ffmpeg::*;
 use crate::ffmpeg::AVMediaType::AVMEDIA_TYPE_AUDIO;
 
 use std::ffi::{CString, CStr, OsStr};
+use std::collections::HashMap;
 use std::fs;
 use std::mem;
 use std::ptr;
+use std::os::raw::{c_int, c_void};
 use std::path::{Path, PathBuf};
-use super::mediafile::MediaFile;
+use image;
+use image::GenericImage;
+use super::mediafile::{MediaFile, Image, ImageType};
 use super::util::*;
 use crate::helpers::mllt;
+use crate::models::audiobook::Audiobook;
+use crate::models::chapter::Chapter;
 use crate::worker::error::*;
 use std::error::Error;
 
@@ -17,9 +23,50 @@ use log::error as error_log;
 pub struct NewMediaFile {
     ctx: *mut AVFormatContext,
     is_mp3: bool,
+    supports_cover: bool,
+    cover_stream: Option<c_int>,
     path: PathBuf
 }
 
+/// Everything besides the audio packets that ends up in a remuxed file.
+/// This makes the files in the data directory usable in third party players.
+#[derive(Default)]
+pub struct OutputMetadata<'a> {
+    pub tags: HashMap<String, String>,
+    pub chapters: &'a [Chapter],
+    pub length: f64,
+    pub cover: Option<&'a Image>,
+}
+
+impl<'a> OutputMetadata<'a> {
+    pub fn for_book(book: &Audiobook, chapters: &'a [Chapter], length: f64, cover: Option<&'a Image>) -> Self {
+        let mut tags = HashMap::new();
+        tags.insert("title".to_owned(), book.title.clone());
+        tags.insert("album".to_owned(), book.title.clone());
+        if let Some(ref artist) = book.artist {
+            tags.insert("artist".to_owned(), artist.clone());
+            tags.insert("album_artist".to_owned(), artist.clone());
+        }
+        tags.insert("genre".to_owned(), "Audiobook".to_owned());
+        // the same keys ffmpeg reads these from, see `BookTags`
+        if let Some(ref narrator) = book.narrator {
+            tags.insert("composer".to_owned(), narrator.clone());
+        }
+        if let Some(ref description) = book.description {
+            tags.insert("description".to_owned(), description.clone());
+        }
+        if let Some(year) = book.year {
+            tags.insert("date".to_owned(), year.to_string());
+        }
+        Self {
+            tags,
+            chapters,
+            length,
+            cover,
+        }
+    }
+}
+
 impl NewMediaFile {
     pub fn from_stream(file_name: &Path, stream: &AVStream) -> Result<Self> {
         unsafe {
@@ -39,37 +86,31 @@ impl NewMediaFile {
                     Some(s) => s,
                     None => return Err(WorkerError::InvalidUtf8.into())
                 }).unwrap();
-        let ipod_short_name = CString::new("ipod").unwrap();
         unsafe {
-            let extension = file_name.extension().and_then(OsStr::to_str);
-            debug!("finding format for {:?}", extension);
-            let short_name = match extension {
-                Some("m4b") => {
-                    debug!("short_name ipod");
-                    ipod_short_name.as_ptr()
-                },
-                _ => ptr::null()
-            };
-            debug!("decided on short_name {:?}", short_name);
-            let format = match ptr_to_opt_mut(av_guess_format(short_name, c_file_name.as_ptr(), ptr::null())) {
-                Some(f) => f,
-                None => return Err(WorkerError::UnkownFormat.into())
-            };
+            let format = guess_output_format(file_name)?;
             let mut ctx = ptr::null_mut();
             check_av_result(avformat_alloc_output_context2(&mut ctx, format, ptr::null(), c_file_name.as_ptr()))?;
 
             let mut is_mp3 = false;
+            let mut supports_cover = false;
             match CStr::from_ptr((*format).name).to_str().expect("ffmpeg format name not valid utf8 (╯°□°）╯︵ ┻━┻") {
-                "ipod" => {
+                "ipod" | "mp4" => {
                     let faststart = CString::new("movflags").unwrap();
                     let opt_true = CString::new("faststart").unwrap();
                     check_av_result(av_opt_set((*ctx).priv_data, faststart.as_ptr(), opt_true.as_ptr(), AV_OPT_SEARCH_CHILDREN))?;
+                    supports_cover = true;
                 }
                 "mp3" => {
                     // let write_xing = CString::new("write_xing").unwrap();
                     // let f = CString::new("0").unwrap();
                     // try!(check_av_result(av_opt_set((*ctx).priv_data, write_xing.as_ptr(), f.as_ptr(), AV_OPT_SEARCH_CHILDREN)));
+                    // The MLLT tag is written using ID3v2.3, so stick to that version from the start
+                    // to keep chapter and picture frames intact when the tag is rewritten.
+                    let id3_version = CString::new("id3v2_version").unwrap();
+                    let three = CString::new("3").unwrap();
+                    check_av_result(av_opt_set((*ctx).priv_data, id3_version.as_ptr(), three.as_ptr(), AV_OPT_SEARCH_CHILDREN))?;
                     is_mp3 = true;
+                    supports_cover = true;
                 }
                 _ => {}
             }
@@ -80,8 +121,100 @@ impl NewMediaFile {
             let stream = ptr_to_opt_mut(avformat_new_stream(ctx, ptr::null())).unwrap();
             (*stream).time_base = time_base;
             avcodec_parameters_copy((*stream).codecpar, codec);
-            Ok(Self{ ctx, is_mp3, path: file_name.to_owned() })
+            Ok(Self{ ctx, is_mp3, supports_cover, cover_stream: None, path: file_name.to_owned() })
+        }
+    }
+
+    /// Set container level tags, these need to be set before writing the header.
+    pub fn set_tags(&mut self, tags: &HashMap<String, String>) -> Result<()> {
+        for (key, value) in tags {
+            let c_key = CString::new(key.as_str()).expect("Null byte in tag key.");
+            let c_value = CString::new(value.as_str()).expect("Null byte in tag value.");
+            unsafe {
+                check_av_result(av_dict_set(&mut (*self.ctx).metadata, c_key.as_ptr(), c_value.as_ptr(), 0))?;
+            }
         }
+        Ok(())
+    }
+
+    /// Add chapters to the output, these need to be set before writing the header.
+    /// Each chapter ends where the next one starts, the last one ends at `length`.
+    pub fn set_chapters(&mut self, chapters: &[Chapter], length: f64) -> Result<()> {
+        let time_base = AVRational { num: 1, den: 1000 };
+        for (i, chapter) in chapters.iter().enumerate() {
+            let end = chapters.get(i + 1).map(|c| c.start_time).unwrap_or(length);
+            unsafe {
+                let av_chapter = av_mallocz(mem::size_of::<AVChapter>()) as *mut AVChapter;
+                if av_chapter.is_null() {
+                    return Err(WorkerError::Other {
+                        description: "Could not allocate chapter".to_owned()
+                    }.into());
+                }
+                (*av_chapter).id = i as _;
+                (*av_chapter).time_base = time_base;
+                (*av_chapter).start = (chapter.start_time * 1000.0) as i64;
+                (*av_chapter).end = (end * 1000.0) as i64;
+                if let Some(ref title) = chapter.title {
+                    let key = CString::new("title").unwrap();
+                    let value = CString::new(title.as_str()).expect("Null byte in chapter title.");
+                    av_dict_set(&mut (*av_chapter).metadata, key.as_ptr(), value.as_ptr(), 0);
+                }
+                // The format context takes ownership, chapters are freed in `avformat_free_context`.
+                av_dynarray_add(
+                    &mut (*self.ctx).chapters as *mut *mut *mut AVChapter as *mut c_void,
+                    &mut (*self.ctx).nb_chapters as *mut _ as *mut c_int,
+                    av_chapter as *mut c_void
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Add an attached picture stream for the cover, needs to happen before writing the header.
+    /// The picture itself is written by `write_cover` once the header is written.
+    /// Formats that can't hold cover art are silently skipped.
+    pub fn add_cover_stream(&mut self, cover: &Image) -> Result<()> {
+        if !self.supports_cover {
+            debug!("Output format does not support cover art, skipping.");
+            return Ok(());
+        }
+        let (width, height) = image::load_from_memory(&cover.data)?.dimensions();
+        unsafe {
+            let stream = match ptr_to_opt_mut(avformat_new_stream(self.ctx, ptr::null())) {
+                Some(s) => s,
+                None => return Err(WorkerError::Other {
+                    description: "Could not create cover stream".to_owned()
+                }.into())
+            };
+            let codecpar = (*stream).codecpar;
+            (*codecpar).codec_type = AVMediaType::AVMEDIA_TYPE_VIDEO;
+            (*codecpar).codec_id = match cover.image_type {
+                ImageType::PNG => AVCodecID::AV_CODEC_ID_PNG,
+                ImageType::JPG => AVCodecID::AV_CODEC_ID_MJPEG,
+            };
+            (*codecpar).width = width as c_int;
+            (*codecpar).height = height as c_int;
+            (*stream).disposition |= AV_DISPOSITION_ATTACHED_PIC as c_int;
+            self.cover_stream = Some((*stream).index);
+        }
+        Ok(())
+    }
+
+    fn write_cover(&mut self, cover: &Image) -> Result<()> {
+        let stream_index = match self.cover_stream {
+            Some(i) => i,
+            None => return Ok(())
+        };
+        unsafe {
+            let mut pkt: AVPacket = mem::zeroed();
+            av_init_packet(&mut pkt);
+            pkt.data = cover.data.as_ptr() as *mut u8;
+            pkt.size = cover.data.len() as c_int;
+            pkt.stream_index = stream_index;
+            pkt.flags |= AV_PKT_FLAG_KEY as c_int;
+            check_av_result(av_write_frame(self.ctx, &mut pkt))?;
+        }
+        Ok(())
     }
 
     pub fn write_header(&mut self) -> Result<()> {
@@ -91,7 +224,31 @@ impl NewMediaFile {
         Ok(())
     }
 
-    fn write_frame(&mut self, pkt: &mut AVPacket) -> Result<()> {
+    /// Write tags, chapters and cover art along with the header.
+    pub fn write_header_with_metadata(&mut self, metadata: &OutputMetadata) -> Result<()> {
+        self.set_tags(&metadata.tags)?;
+        self.set_chapters(metadata.chapters, metadata.length)?;
+        if let Some(cover) = metadata.cover {
+            if let Err(e) = self.add_cover_stream(cover) {
+                warn!("Not writing cover art to {}: {}", self.path.display(), e);
+            }
+        }
+        debug!("writing header");
+        self.write_header()?;
+        if let Some(cover) = metadata.cover {
+            self.write_cover(cover)?;
+        }
+        Ok(())
+    }
+
+    /// Time base of the audio stream, only final once the header is written.
+    pub(super) fn audio_time_base(&self) -> AVRational {
+        unsafe {
+            (**(*self.ctx).streams).time_base
+        }
+    }
+
+    pub(super) fn write_frame(&mut self, pkt: &mut AVPacket) -> Result<()> {
         unsafe {
             pkt.stream_index = 0;
             check_av_result(av_write_frame(self.ctx, pkt))?;
@@ -99,9 +256,10 @@ impl NewMediaFile {
         Ok(())
     }
 
-    fn write_trailer(&mut self) -> Result<()> {
+    pub(super) fn write_trailer(&mut self) -> Result<()> {
         unsafe {
             check_av_result(av_write_trailer(self.ctx))?;
+            check_av_result(avio_closep(&mut (*self.ctx).pb))?;
         }
         if self.is_mp3 {
             mllt::mlltify(&self.path)?;
@@ -110,7 +268,51 @@ impl NewMediaFile {
     }
 }
 
-pub fn merge_files(path: &dyn AsRef<Path>, in_files: &[MediaFile]) -> Result<NewMediaFile> {
+impl Drop for NewMediaFile {
+    fn drop(&mut self) {
+        unsafe {
+            // only still open if writing failed
+            avio_closep(&mut (*self.ctx).pb);
+            avformat_free_context(self.ctx);
+        }
+    }
+}
+
+/// Guess the output format from a file name, m4b files are written using the ipod muxer.
+pub(super) fn guess_output_format(file_name: &Path) -> Result<*mut AVOutputFormat> {
+    let c_file_name = CString::new(
+            match file_name.to_str() {
+                Some(s) => s,
+                None => return Err(WorkerError::InvalidUtf8.into())
+            }).unwrap();
+    let ipod_short_name = CString::new("ipod").unwrap();
+    let extension = file_name.extension().and_then(OsStr::to_str);
+    debug!("finding format for {:?}", extension);
+    let short_name = match extension {
+        Some("m4b") => {
+            debug!("short_name ipod");
+            ipod_short_name.as_ptr()
+        },
+        _ => ptr::null()
+    };
+    debug!("decided on short_name {:?}", short_name);
+    unsafe {
+        match ptr_to_opt_mut(av_guess_format(short_name, c_file_name.as_ptr(), ptr::null())) {
+            Some(f) => Ok(f),
+            None => Err(WorkerError::UnkownFormat.into())
+        }
+    }
+}
+
+/// Removes a partially written output file after an error.
+pub(super) fn remove_unfinished(path: &dyn AsRef<Path>) {
+    let remove_result = fs::remove_file(path);
+    if let Err(remove_error) = remove_result {
+        error_log!("additional error while trying to remove unfinished muxed file at {}: {}", path.as_ref().display(), remove_error);
+    }
+}
+
+pub fn merge_files(path: &dyn AsRef<Path>, in_files: &[MediaFile], metadata: &OutputMetadata) -> Result<NewMediaFile> {
     let steps = || -> Result<NewMediaFile> {
         // TODO: check in_files length
         // TODO: check that formats are actually compatible
@@ -123,8 +325,7 @@ pub fn merge_files(path: &dyn AsRef<Path>, in_files: &[MediaFile]) -> Result<New
             let stream = in_files.first().unwrap().get_best_stream(AVMEDIA_TYPE_AUDIO)?;
             NewMediaFile::from_stream(path.as_ref(), stream)?
         };
-        debug!("writing header");
-        out.write_header()?;
+        out.write_header_with_metadata(metadata)?;
 
         let mut previous_files_duration: i64 = 0;
         for f in in_files {
@@ -171,12 +372,74 @@ pub fn merge_files(path: &dyn AsRef<Path>, in_files: &[MediaFile]) -> Result<New
     match steps() {
         Ok(out) => Ok(out),
         Err(error) => {
-            let remove_result = fs::remove_file(path);
-            if let Err(remove_error) = remove_result {
-                error_log!("additional error while trying to remove unfinished muxed file at {}: {}", path.as_ref().display(), remove_error);
-            }
-
+            remove_unfinished(path);
             Err(error)
         },
     }
 }
+
+/// Copy the audio packets of `source` from `start` to `end` seconds into a new file, the format of
+/// which is guessed from `path`. Packets are assigned by their timestamp, so slices that share a
+/// boundary neither overlap nor leave a gap. Timestamps are kept, the slices of a file continue
+/// each other.
+pub fn remux_slice(path: &dyn AsRef<Path>, source: &Path, start: f64, end: f64) -> Result<()> {
+    copy_slice(path, source, start, end, &OutputMetadata::default(), false)
+}
+
+/// Like `remux_slice`, but for a file of its own: timestamps start at zero so players see the
+/// length of the slice, and `metadata` is written along with the header.
+pub fn extract_slice(path: &dyn AsRef<Path>, source: &Path, start: f64, end: f64,
+                     metadata: &OutputMetadata) -> Result<()> {
+    copy_slice(path, source, start, end, metadata, true)
+}
+
+/// The minimum of an i64 is AV_NOPTS_VALUE, packets without a timestamp have it.
+const NO_TIMESTAMP: i64 = i64::min_value();
+
+fn copy_slice(path: &dyn AsRef<Path>, source: &Path, start: f64, end: f64, metadata: &OutputMetadata,
+              rebase: bool) -> Result<()> {
+    let steps = || -> Result<()> {
+        let input = MediaFile::read_file(source)?;
+        let stream = input.get_best_stream(AVMEDIA_TYPE_AUDIO)?;
+        let (index, time_base) = (stream.index, stream.time_base);
+        let mut out = NewMediaFile::from_stream(path.as_ref(), stream)?;
+        out.write_header_with_metadata(metadata)?;
+        if start > 0.0 {
+            input.seek(start)?;
+        }
+
+        let mut offset = None;
+        while let Some(mut pkt) = input.read_packet()? {
+            let timestamp = if pkt.pts == NO_TIMESTAMP { pkt.dts } else { pkt.pts };
+            let time = apply_timebase(timestamp, time_base);
+            if pkt.stream_index == index && time >= end {
+                unsafe { av_packet_unref(&mut pkt) };
+                break;
+            }
+            let written = if pkt.stream_index == index && time >= start {
+                if rebase {
+                    let first = *offset.get_or_insert(if pkt.dts == NO_TIMESTAMP { timestamp } else { pkt.dts });
+                    if pkt.dts != NO_TIMESTAMP {
+                        pkt.dts -= first;
+                    }
+                    if pkt.pts != NO_TIMESTAMP {
+                        pkt.pts -= first;
+                    }
+                }
+                unsafe { av_packet_rescale_ts(&mut pkt, time_base, out.audio_time_base()) };
+                out.write_frame(&mut pkt)
+            } else {
+                Ok(())
+            };
+            unsafe { av_packet_unref(&mut pkt) };
+            written?;
+        }
+
+        out.write_trailer()
+    };
+
+    steps().map_err(|error| {
+        remove_unfinished(path);
+        error
+    })
+}
@@ -0,0 +1,173 @@
+use std::collections::HashMap;
+use std::f64::consts::PI;
+use image::{self, FilterType, GenericImage, RgbImage};
+
+use crate::models::audiobook::Palette;
+use crate::worker::error::{Result, WorkerError};
+
+/// Covers are scaled down to this many pixels per side first, more don't change the result.
+const SAMPLE_SIZE: u32 = 32;
+/// Number of horizontal and vertical blurhash components, 4×3 suits the mostly square covers.
+const BLURHASH_COMPONENTS: (usize, usize) = (4, 3);
+const BASE83: &[u8] = b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz#$%*+,-.:;=?@[]^_{|}~";
+
+/// Pixels of similar colour, averaged.
+struct Swatch {
+    rgb: [f64; 3],
+    population: usize,
+}
+
+impl Swatch {
+    fn saturation_lightness(&self) -> (f64, f64) {
+        let max = self.rgb.iter().cloned().fold(0.0, f64::max) / 255.0;
+        let min = self.rgb.iter().cloned().fold(255.0, f64::min) / 255.0;
+        let lightness = (max + min) / 2.0;
+        let saturation = if max - min < std::f64::EPSILON {
+            0.0
+        } else {
+            (max - min) / (1.0 - (2.0 * lightness - 1.0).abs())
+        };
+        (saturation, lightness)
+    }
+
+    fn hex(&self) -> String {
+        let c: Vec<u8> = self.rgb.iter().map(|v| v.round().max(0.0).min(255.0) as u8).collect();
+        format!("#{:02x}{:02x}{:02x}", c[0], c[1], c[2])
+    }
+}
+
+/// Dominant, vibrant and muted colour and a blurhash of an image. Images without vibrant or
+/// muted colours use the dominant one for those.
+pub fn extract(data: &[u8]) -> Result<Palette> {
+    let mut image = image::load_from_memory(data)?;
+    let (width, height) = image.dimensions();
+    if width == 0 || height == 0 {
+        return Err(WorkerError::Other { description: "The image is empty".to_owned() }.into());
+    }
+    if width > SAMPLE_SIZE || height > SAMPLE_SIZE {
+        image = image.resize_exact(SAMPLE_SIZE, SAMPLE_SIZE, FilterType::Triangle);
+    }
+    let image = image.to_rgb();
+
+    let swatches = swatches(&image);
+    let dominant = &swatches[0];
+    let vibrant = swatches.iter()
+        .filter(|s| {
+            let (saturation, lightness) = s.saturation_lightness();
+            saturation >= 0.35 && lightness >= 0.25 && lightness <= 0.75
+        })
+        .max_by(|a, b| {
+            let score = |s: &Swatch| s.population as f64 * s.saturation_lightness().0;
+            score(a).partial_cmp(&score(b)).unwrap()
+        })
+        .unwrap_or(dominant);
+    let muted = swatches.iter()
+        .find(|s| {
+            let (saturation, lightness) = s.saturation_lightness();
+            saturation < 0.35 && lightness >= 0.2 && lightness <= 0.8
+        })
+        .unwrap_or(dominant);
+
+    Ok(Palette {
+        dominant: dominant.hex(),
+        vibrant: vibrant.hex(),
+        muted: muted.hex(),
+        blurhash: blurhash(&image, BLURHASH_COMPONENTS.0, BLURHASH_COMPONENTS.1),
+    })
+}
+
+/// Group the pixels by their upper four bits per channel, most common group first.
+fn swatches(image: &RgbImage) -> Vec<Swatch> {
+    let mut groups: HashMap<[u8; 3], ([u64; 3], usize)> = HashMap::new();
+    for pixel in image.pixels() {
+        let (r, g, b) = (pixel.data[0], pixel.data[1], pixel.data[2]);
+        let group = groups.entry([r >> 4, g >> 4, b >> 4]).or_insert(([0; 3], 0));
+        (group.0)[0] += u64::from(r);
+        (group.0)[1] += u64::from(g);
+        (group.0)[2] += u64::from(b);
+        group.1 += 1;
+    }
+    let mut swatches: Vec<Swatch> = groups.into_iter().map(|(_, (sum, population))| Swatch {
+        rgb: [
+            sum[0] as f64 / population as f64,
+            sum[1] as f64 / population as f64,
+            sum[2] as f64 / population as f64,
+        ],
+        population,
+    }).collect();
+    // ties are broken by colour so the result doesn't depend on the hash map's order
+    swatches.sort_by(|a, b| b.population.cmp(&a.population)
+        .then_with(|| a.rgb.partial_cmp(&b.rgb).unwrap()));
+    swatches
+}
+
+/// Encode an image as described in https://github.com/woltapp/blurhash/blob/master/Algorithm.md
+fn blurhash(image: &RgbImage, components_x: usize, components_y: usize) -> String {
+    let (width, height) = image.dimensions();
+    let mut factors: Vec<[f64; 3]> = Vec::with_capacity(components_x * components_y);
+    for j in 0..components_y {
+        for i in 0..components_x {
+            let normalisation = if i == 0 && j == 0 { 1.0 } else { 2.0 };
+            let mut factor = [0.0; 3];
+            for (x, y, pixel) in image.enumerate_pixels() {
+                let basis = normalisation
+                    * (PI * i as f64 * f64::from(x) / f64::from(width)).cos()
+                    * (PI * j as f64 * f64::from(y) / f64::from(height)).cos();
+                for (f, value) in factor.iter_mut().zip(pixel.data.iter()) {
+                    *f += basis * srgb_to_linear(*value);
+                }
+            }
+            let scale = 1.0 / f64::from(width * height);
+            factors.push([factor[0] * scale, factor[1] * scale, factor[2] * scale]);
+        }
+    }
+
+    let mut hash = String::new();
+    let size_flag = (components_x - 1) + (components_y - 1) * 9;
+    push_base83(size_flag as u32, 1, &mut hash);
+    let ac = &factors[1..];
+    let maximum = if ac.is_empty() {
+        push_base83(0, 1, &mut hash);
+        1.0
+    } else {
+        let actual = ac.iter().flat_map(|f| f.iter()).fold(0.0, |m: f64, v| m.max(v.abs()));
+        let quantised = (actual * 166.0 - 0.5).floor().max(0.0).min(82.0) as u32;
+        push_base83(quantised, 1, &mut hash);
+        (f64::from(quantised) + 1.0) / 166.0
+    };
+    let dc = factors[0];
+    push_base83((linear_to_srgb(dc[0]) << 16) + (linear_to_srgb(dc[1]) << 8) + linear_to_srgb(dc[2]), 4, &mut hash);
+    for factor in ac {
+        let quantise = |v: f64| {
+            let scaled = v / maximum;
+            (scaled.signum() * scaled.abs().sqrt() * 9.0 + 9.5).floor().max(0.0).min(18.0) as u32
+        };
+        push_base83(quantise(factor[0]) * 19 * 19 + quantise(factor[1]) * 19 + quantise(factor[2]), 2, &mut hash);
+    }
+    hash
+}
+
+fn push_base83(value: u32, length: u32, hash: &mut String) {
+    for i in 1..=length {
+        let digit = (value / 83u32.pow(length - i)) % 83;
+        hash.push(BASE83[digit as usize] as char);
+    }
+}
+
+fn srgb_to_linear(value: u8) -> f64 {
+    let v = f64::from(value) / 255.0;
+    if v <= 0.04045 {
+        v / 12.92
+    } else {
+        ((v + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+fn linear_to_srgb(value: f64) -> u32 {
+    let v = value.max(0.0).min(1.0);
+    if v <= 0.003_130_8 {
+        (v * 12.92 * 255.0 + 0.5) as u32
+    } else {
+        ((1.055 * v.powf(1.0 / 2.4) - 0.055) * 255.0 + 0.5) as u32
+    }
+}
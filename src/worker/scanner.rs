@@ -7,7 +7,7 @@ use std::collections::HashMap;
 use std::env;
 use std::os::unix::prelude::*;
 use std::os::unix::fs;
-use std::fs::{create_dir, rename};
+use std::fs::{create_dir, rename, remove_file};
 use log::error as error_log;
 
 use walkdir::WalkDir;
@@ -26,13 +26,29 @@ use fs2::FileExt;
 use crate::config::Config;
 use crate::helpers::db::Pool;
 use crate::models::library::*;
-use crate::models::audiobook::{Audiobook, Update};
+use crate::models::audiobook::{Audiobook, Update, Genres, COVER_EMBEDDED};
 use crate::models::chapter::Chapter;
+use crate::models::scan_warning::ScanWarning;
+use crate::models::metadata_sidecar::MetadataSidecar;
 use crate::schema::audiobooks;
 use crate::schema::chapters;
 use crate::schema::libraries;
-use crate::worker::mediafile::MediaFile;
+use crate::worker::mediafile::{MediaFile, MediaInfo};
 use crate::worker::muxer;
+use crate::helpers::mllt;
+use crate::worker::hls;
+use crate::worker::chapter_audio;
+use crate::worker::transcoder;
+use crate::worker::chapter_rules::ChapterRules;
+use crate::worker::cue::{self, CueSheet};
+use crate::worker::chapter_sidecar;
+use crate::worker::cover;
+use crate::worker::palette;
+use crate::worker::metadata_sidecar;
+use crate::worker::silence;
+use crate::worker::virtual_file::{self, VirtualFile};
+use crate::worker::tags::{BookTags, SeriesName};
+use crate::worker::mediafile;
 use crate::worker::error::{Result, WorkerError};
 use diesel::BelongingToDsl;
 use crate::worker::util;
@@ -51,8 +67,35 @@ struct MultifileMetadata {
     pub chapters: Vec<Chapter>,
     pub length: f64,
     pub cover: Option<Image>,
+    /// Set when the files are of differing formats and need to be transcoded
+    pub mixed_formats: Option<Vec<OsString>>,
 }
 
+/// Extensions of files that accompany books, see `is_sidecar`.
+const SIDECAR_EXTENSIONS: &[&str] = &["cue", "txt", "json", "opf", "nfo", "jpg", "jpeg", "png"];
+
+/// A single file of a multifile book along with the disc it belongs to.
+struct BookPart {
+    media: MediaFile,
+    info: MediaInfo,
+    disc: Option<u32>,
+}
+
+lazy_static! {
+    /// Matches folder names like `CD1`, `Disc 02` or `Part 3`.
+    static ref DISC_FOLDER_REGEX: Regex = Regex::new(
+        r"(?i)^(?:cd|disc|disk|part|teil|vol(?:ume)?)[\s._-]*(\d+)"
+    ).unwrap();
+}
+
+/// Extensions of files that are considered to be audio when scanning multifile books.
+const AUDIO_EXTENSIONS: &[&str] = &[
+    "mp3", "m4a", "m4b", "mp4", "aac", "ogg", "oga", "opus", "flac", "wav", "wma", "mka",
+];
+
+/// Extensions of the same container, files with these can be remuxed together.
+const MP4_EXTENSIONS: &[&str] = &["m4a", "m4b", "mp4"];
+
 #[derive(Eq, PartialEq)]
 pub enum LockingBehavior {
     Block,
@@ -164,6 +207,7 @@ impl Scanner {
             let path = entry.path();
             let relative_path = entry.path().strip_prefix(&self.library.location).unwrap();
             if relative_path.components().count() == 0 { continue };
+            if is_sidecar(path) { continue };
             if is_audiobook(relative_path, &self.regex) {
                 let r = self.handle_book_at_path(conn, scan_type.clone(), path, relative_path, last_scan);
 
@@ -193,9 +237,24 @@ impl Scanner {
                     .first::<Audiobook>(conn).optional()?;
                 if should_scan(path, last_scan)? || preexisting_book.is_none() {
                     self.process_audiobook(&path, conn)?;
+                } else if let Some(book) = preexisting_book {
+                    // the audio is unchanged, but its chapter list or metadata might not be
+                    self.import_chapter_sidecar(conn, &book, path, last_scan)?;
+                    self.import_metadata_sidecars(conn, &book, path, last_scan)?;
+                    self.import_cover_image(conn, &book, path, last_scan)?;
                 }
             },
-            Scan::Full => self.process_audiobook(&path, conn)?
+            Scan::Full => {
+                self.process_audiobook(&path, conn)?;
+                let existing_book = Audiobook::belonging_to(&self.library)
+                    .filter(location.eq(&relative_path.to_string_lossy()))
+                    .first::<Audiobook>(conn).optional()?;
+                if let Some(book) = existing_book {
+                    self.import_chapter_sidecar(conn, &book, path, None)?;
+                    self.import_metadata_sidecars(conn, &book, path, None)?;
+                    self.import_cover_image(conn, &book, path, None)?;
+                }
+            }
         }
 
         let mut book_result = Audiobook::belonging_to(&self.library)
@@ -206,17 +265,23 @@ impl Scanner {
         // Ensure cached file exists here no need to check if its current, that is ensured
         // above
         if let Ok(mut book) = book_result {
-            if path.is_dir() && !self.data_path_of(&book).exists() {
+            if path.is_dir() && !self.multifile_stored(&book, path)? {
                 debug!("No remuxed version of {}, remuxing!", book.title);
                 match self.multifile_remux(&mut book) {
-                    Ok(_) => info!("Successfully remuxed {}", book.title),
+                    Ok(_) => {
+                        info!("Successfully remuxed {}", book.title);
+                        self.clear_caches(&book);
+                    },
                     Err(e) => info!("Error {:?} while remuxing {}", e, book.title),
                 }
-            } else if !self.data_path_of(&book).exists() {
-                debug!("No remuxed version of {}, linking!", book.title);
-                match self.link_audiobook(&book) {
-                    Ok(_) => info!("Successfully linked {} into collection", book.title),
-                    Err(e) => info!("Error {:?} while linking {}", e, book.title),
+            } else if !path.is_dir() && !self.single_file_stored(&book, path, scan_type)? {
+                debug!("No data file of {} or an outdated one, storing it!", book.title);
+                match self.store_single_file(conn, &book) {
+                    Ok(_) => {
+                        info!("Successfully stored {} in collection", book.title);
+                        self.clear_caches(&book);
+                    },
+                    Err(e) => info!("Error {:?} while storing {}", e, book.title),
                 }
             }
         }
@@ -232,6 +297,20 @@ impl Scanner {
     }
 
 
+    /// Remove the HLS segments and chapters cut from the data file of a book, it was replaced or
+    /// the book is gone.
+    fn clear_caches(&self, book: &Audiobook) {
+        let caches = [
+            hls::cache_directory(&self.config.data_directory, &book.id),
+            chapter_audio::cache_directory(&self.config.data_directory, &book.id),
+        ];
+        for cache in caches.iter().filter(|c| c.exists()) {
+            if let Err(e) = std::fs::remove_dir_all(cache) {
+                warn!("Could not remove the cache {:?} of {}: {}", cache, book.title, e);
+            }
+        }
+    }
+
     /// Try to recover those books that were marked as deleted.
     /// Checks the file paths of books in the database and recovers them if hashes match
     fn recover_deleted(&self, conn: &SqliteConnection) -> Result<usize> {
@@ -283,7 +362,7 @@ impl Scanner {
                 debug!("deleted: {}", del);
                 match del {
                     0 => warn!("Could not delete audiobook, is something wrong with the DB?"),
-                    1 => {},
+                    1 => self.clear_caches(&book),
                     x => warn!("Deleted multiple audiobooks with same UUID, database integrity might be compromised."),
                 }
             }
@@ -292,8 +371,58 @@ impl Scanner {
     }
 
 
-    /// Save cover art to directory
-    fn save_coverart(&self, book: &Audiobook, image: &Image) -> Result<()> {
+    /// Pick the cover of a book according to the library's `cover_preference`, together with
+    /// the `cover_source` to store.
+    fn choose_cover(&self, book_path: &Path, embedded: Option<Image>) -> Option<(Image, String)> {
+        let folder = cover::find_for(book_path).and_then(|image_path| {
+            match cover::read_image(&image_path) {
+                Ok(image) => {
+                    let source = image_path.strip_prefix(&self.library.location)
+                        .unwrap_or(&image_path)
+                        .to_string_lossy()
+                        .into_owned();
+                    Some((image, source))
+                },
+                Err(e) => {
+                    warn!("Ignoring cover image {:?}: {}", image_path, e);
+                    None
+                }
+            }
+        });
+        let embedded = embedded.map(|image| (image, COVER_EMBEDDED.to_owned()));
+        match self.library.cover_preference {
+            CoverPreference::Embedded => embedded.or(folder),
+            CoverPreference::Folder => folder.or(embedded),
+        }
+    }
+
+    /// Pick the cover of a single file book again if its cover image changed since `since`.
+    /// Multifile books are rebuilt when their cover image changes, as it is part of their hash.
+    fn import_cover_image(&self, conn: &SqliteConnection, book: &Audiobook, path: &Path,
+                          since: Option<NaiveDateTime>) -> Result<()> {
+        if path.is_dir() {
+            return Ok(());
+        }
+        let image_path = match cover::find_for(path) {
+            Some(p) => p,
+            None => return Ok(())
+        };
+        if !should_scan(&image_path, since)? {
+            return Ok(());
+        }
+        let embedded = MediaFile::read_file(path)?.get_coverart()?;
+        if let Some((image, source)) = self.choose_cover(path, embedded) {
+            self.save_coverart(conn, &mut book.clone(), &image)?;
+            diesel::update(audiobooks::table.filter(audiobooks::dsl::id.eq(&book.id)))
+                .set(audiobooks::dsl::cover_source.eq(&source))
+                .execute(conn)?;
+            info!("Using cover of {} from {}", book.title, source);
+        }
+        Ok(())
+    }
+
+    /// Save cover art to directory and store the palette of its colours with the book.
+    fn save_coverart(&self, conn: &SqliteConnection, book: &mut Audiobook, image: &Image) -> Result<()> {
         let mut dest = PathBuf::from(format!("{}/img", self.config.data_directory));
         if let Err(e) = create_dir(dest.clone()) {
             match e.kind() {
@@ -303,6 +432,16 @@ impl Scanner {
         };
         dest.push(&book.id.hyphenated().to_string());
         image.save(&dest)?;
+        book.palette = match palette::extract(&image.data) {
+            Ok(p) => Some(p),
+            Err(e) => {
+                warn!("Can't extract the colours of the cover of {}: {}", book.title, e);
+                None
+            }
+        };
+        diesel::update(audiobooks::table.filter(audiobooks::dsl::id.eq(&book.id)))
+            .set(audiobooks::dsl::palette.eq(&book.palette))
+            .execute(conn)?;
         Ok(())
     }
 
@@ -329,41 +468,64 @@ impl Scanner {
         });
 
         let metadata = file.get_mediainfo();
+        let mut tags = BookTags::from_metadata(&metadata.metadata);
+        merge_metadata_sidecars(path.as_ref(), &mut tags);
         let cover_file = MediaFile::read_file(path.as_ref())?;
-        let default_book = Audiobook {
+        let named = path.as_ref().file_stem().and_then(|s| SeriesName::parse(&s.to_string_lossy()));
+        let title = match (&tags.title, &named) {
+            (&Some(ref title), _) => title.clone(),
+            (&None, &Some(ref named)) => named.title.clone(),
+            _ => metadata.title,
+        };
+        let mut default_book = Audiobook {
             id: Uuid::new_v4(),
-            title: metadata.title,
-            artist: metadata.metadata.get("artist").cloned(),
+            title,
+            artist: tags.author.clone(),
             length: metadata.length,
             location: relative_path.to_owned(),
             library_id: self.library.id,
             hash,
             file_extension: file_extension.unwrap_or_else(|| "".to_owned()),
             deleted: false,
+            narrator: None,
+            series: None,
+            series_index: None,
+            description: None,
+            publisher: None,
+            year: None,
+            genres: Genres::default(),
+            language: None,
+            isbn: None,
+            asin: None,
+            cover_source: None,
+            palette: None,
         };
-
-        let chapters = file.get_chapters();
-        let maybe_image = file.get_coverart()?;
+        tags.apply_to(&mut default_book, named.as_ref());
+
+        let (chapters, generated) = match read_chapter_sidecar(path.as_ref()) {
+            Some(chapters) => (chapters, false),
+            None => {
+                let chapters = self.single_file_chapters(path.as_ref(), &file);
+                let files = [path.as_ref().to_owned()];
+                match self.generated_chapters(chapters.len(), &files, default_book.length) {
+                    Some(generated) => (generated, true),
+                    None => (chapters, false)
+                }
+            }
+        };
+        let cover = self.choose_cover(path.as_ref(), file.get_coverart()?);
+        default_book.cover_source = cover.as_ref().map(|&(_, ref source)| source.clone());
 
         let inserted = conn.exclusive_transaction(|| -> Result<(Audiobook, usize)> {
             debug!("Start transaction inserting single audiobook.");
-            let book = Audiobook::ensure_exists_in(
+            let mut book = Audiobook::ensure_exists_in(
                 &relative_path, &self.library, &default_book, conn
             )?;
-            book.delete_all_chapters(conn);
-            if let Some(image) = maybe_image {
-                self.save_coverart(&book, &image);
+            book.delete_all_chapters(conn)?;
+            if let Some((ref image, _)) = cover {
+                self.save_coverart(conn, &mut book, image)?;
             };
-            self.link_audiobook(&book)?;
-            let new_chapters: Vec<Chapter> = chapters.iter().enumerate().map(|(i, chapter)| {
-                Chapter {
-                    id: Uuid::new_v4(),
-                    audiobook_id: book.id,
-                    start_time: chapter.start,
-                    title: chapter.title.clone(),
-                    number: i as i64
-                }
-            }).collect();
+            let new_chapters = model_chapters(&book, &chapters, generated);
             debug!("End transaction inserting single audiobook.");
             Ok((book, diesel::replace_into(chapters::table)
                 .values(&new_chapters).execute(&*conn)?))
@@ -371,12 +533,188 @@ impl Scanner {
         match inserted {
             Ok((b, num_chapters)) => {
                 info!("Successfully saved book: {} with {} chapters.", b.title, num_chapters);
-                Ok(())
+                // outside of the transaction as copying a book takes a while
+                self.store_single_file(conn, &b)
             },
             Err(e) => Err(e)
         }
     }
 
+    /// Replace the chapters of an existing book with those from its chapter sidecar, if the sidecar
+    /// changed since `since`. This catches edited chapter lists of books whose audio is unchanged.
+    fn import_chapter_sidecar(&self, conn: &SqliteConnection, book: &Audiobook, path: &Path,
+                              since: Option<NaiveDateTime>) -> Result<()> {
+        let sidecar_path = match chapter_sidecar::find_for(path) {
+            Some(p) => p,
+            None => return Ok(())
+        };
+        if !should_scan(&sidecar_path, since)? {
+            return Ok(());
+        }
+        let chapters = chapter_sidecar::read_file(&sidecar_path)?;
+        let new_chapters = model_chapters(book, &chapters, false);
+        conn.exclusive_transaction(|| -> Result<()> {
+            book.delete_all_chapters(conn)?;
+            diesel::insert_into(chapters::table).values(&new_chapters).execute(conn)?;
+            Ok(())
+        })?;
+        info!("Imported {} chapters for {} from {:?}", new_chapters.len(), book.title, sidecar_path);
+        Ok(())
+    }
+
+    /// Update the descriptive columns of a single file book whose metadata sidecars changed since
+    /// `since`, or were added or removed since they were last read. Multifile books don't need
+    /// this, their sidecars are part of the directory hash so any change rebuilds the whole book.
+    fn import_metadata_sidecars(&self, conn: &SqliteConnection, book: &Audiobook, path: &Path,
+                                since: Option<NaiveDateTime>) -> Result<()> {
+        if path.is_dir() {
+            return Ok(());
+        }
+        let sidecars = metadata_sidecar::find_for(path);
+        let mut file_names: Vec<String> = sidecars.iter()
+            .filter_map(|p| p.file_name())
+            .map(|n| n.to_string_lossy().into_owned())
+            .collect();
+        file_names.sort();
+        let mut changed = file_names != MetadataSidecar::file_names_of(book, conn)?;
+        for sidecar_path in &sidecars {
+            changed |= should_scan(sidecar_path, since)?;
+        }
+        if !changed {
+            return Ok(());
+        }
+        let file = MediaFile::read_file(path)?;
+        let metadata = file.get_mediainfo();
+        let mut tags = BookTags::from_metadata(&metadata.metadata);
+        merge_metadata_sidecars(path, &mut tags);
+        let named = path.file_stem().and_then(|s| SeriesName::parse(&s.to_string_lossy()));
+        let mut updated = book.clone();
+        updated.title = match (&tags.title, &named) {
+            (&Some(ref title), _) => title.clone(),
+            (&None, &Some(ref named)) => named.title.clone(),
+            _ => metadata.title,
+        };
+        updated.artist = tags.author.clone();
+        tags.apply_to(&mut updated, named.as_ref());
+        conn.exclusive_transaction(|| -> Result<()> {
+            diesel::update(audiobooks::table.filter(audiobooks::dsl::id.eq(&book.id))).set(&updated).execute(conn)?;
+            MetadataSidecar::replace_for(book, &file_names, conn)?;
+            Ok(())
+        })?;
+        info!("Updated metadata of {} from its sidecars", updated.title);
+        Ok(())
+    }
+
+    /// Chapters of a single file book. These come from the container, or from a CUE sheet next to
+    /// the file if the container has none or CUE sheets are preferred.
+    fn single_file_chapters(&self, path: &Path, file: &MediaFile) -> Vec<mediafile::Chapter> {
+        let embedded = file.get_chapters();
+        if !embedded.is_empty() && !self.config.scan.prefer_cue_sheets {
+            return embedded;
+        }
+        let cue_path = match cue::find_for(path) {
+            Some(p) => p,
+            None => return embedded
+        };
+        let file_name = path.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default();
+        match CueSheet::read_file(&cue_path) {
+            Ok(sheet) => {
+                let cue_chapters = sheet.chapters_for(&file_name);
+                if cue_chapters.is_empty() {
+                    embedded
+                } else {
+                    info!("Using {} chapters from {:?}", cue_chapters.len(), cue_path);
+                    cue_chapters
+                }
+            },
+            Err(e) => {
+                warn!("Ignoring unreadable CUE sheet {:?}: {}", cue_path, e);
+                embedded
+            }
+        }
+    }
+
+    /// Chapters found by silence detection, for books with at most one chapter of their own.
+    /// `None` if generating chapters is disabled, unnecessary or failed.
+    fn generated_chapters(&self, existing: usize, files: &[PathBuf], length: f64) -> Option<Vec<mediafile::Chapter>> {
+        let settings = &self.config.scan.generated_chapters;
+        let interval = settings.interval * 60.0;
+        if !settings.enabled || existing > 1 || length < interval {
+            return None;
+        }
+        info!("Looking for silences to generate chapters in {:?}", files);
+        match silence::find_silences(files, settings.threshold, settings.min_silence) {
+            Ok(silences) => Some(silence::chapters_at_silences(&silences, length, interval)),
+            Err(e) => {
+                warn!("Could not generate chapters for {:?}: {}", files, e);
+                None
+            }
+        }
+    }
+
+    /// Whether the data file of a single file book is in place. A copy has to be newer than the
+    /// book, links made before we checked for VBR MP3s are looked at again by full scans.
+    fn single_file_stored(&self, book: &Audiobook, path: &Path, scan_type: Scan) -> Result<bool> {
+        let dest = self.data_path_of(book);
+        match std::fs::symlink_metadata(&dest) {
+            Err(_) => Ok(false),
+            Ok(ref meta) if meta.file_type().is_symlink() => match scan_type {
+                Scan::Full => Ok(!self.needs_seekable_copy(book, path)),
+                Scan::Incremental => Ok(true),
+            },
+            Ok(meta) => Ok(meta.modified()? >= std::fs::metadata(path)?.modified()?),
+        }
+    }
+
+    /// Put a single file book into the data directory. VBR MP3s get a copy with a seek table,
+    /// the file in the library is never changed. All other books are linked.
+    fn store_single_file(&self, conn: &SqliteConnection, book: &Audiobook) -> Result<()> {
+        let source = Path::new(&self.library.location).join(&book.location);
+        if self.needs_seekable_copy(book, &source) {
+            return self.write_seekable_copy(conn, book, &source);
+        }
+        let dest = self.data_path_of(book);
+        if let Ok(meta) = std::fs::symlink_metadata(&dest) {
+            // a copy made while the book was a VBR MP3
+            if !meta.file_type().is_symlink() {
+                remove_file(&dest)?;
+            }
+        }
+        self.link_audiobook(book)
+    }
+
+    /// Whether a book is an MP3 with variable bitrate. Players can only seek precisely in those
+    /// if they have a seek table, and their Xing header often doesn't match the audio.
+    fn needs_seekable_copy(&self, book: &Audiobook, path: &Path) -> bool {
+        if !book.file_extension.eq_ignore_ascii_case("mp3") {
+            return false;
+        }
+        match mllt::is_vbr(path) {
+            Ok(vbr) => vbr,
+            Err(e) => {
+                warn!("Can't tell if {:?} has a variable bitrate, linking it: {}", path, e);
+                false
+            }
+        }
+    }
+
+    /// Remux a VBR MP3 into the data directory. The muxer writes a fresh Xing header and adds an
+    /// MLLT seek table, the tags, chapters and cover of the book are written as well.
+    fn write_seekable_copy(&self, conn: &SqliteConnection, book: &Audiobook, source: &Path) -> Result<()> {
+        let chapters = Chapter::belonging_to(book)
+            .order(chapters::dsl::start_time.asc())
+            .load::<Chapter>(conn)?;
+        let cover = cover::read_image(&cover::saved_path(&self.config.data_directory, &book.id)).ok();
+        let metadata = muxer::OutputMetadata::for_book(book, &chapters, book.length, cover.as_ref());
+        let dest = self.data_path_of(book);
+        let temporary = dest.with_extension(format!("{}.mp3", Uuid::new_v4().hyphenated()));
+        info!("Writing a seekable copy of {} to {:?}", book.title, dest);
+        muxer::merge_files(&temporary, &[MediaFile::read_file(source)?], &metadata)?;
+        // this replaces a link to the library as well
+        rename(&temporary, &dest)?;
+        Ok(())
+    }
+
     /// Audiobooks that are not remuxed are linked into our data directory so we have one canonical
     /// source of data.
     fn link_audiobook(&self, book: &Audiobook) -> Result<()> {
@@ -392,77 +730,213 @@ impl Scanner {
     fn multifile_remux(&self, mut book: &mut Audiobook) -> Result<()> {
         let collection = self.multifile_extract_chapters(&mut book)?;
         let target_path = self.data_path_of(&book);
-        muxer::merge_files(
-            &target_path,
-            &collection.media_files
+        let written = self.store_collection(&target_path, &book, &collection)?;
+        self.place_data_file(&written, &book)
+    }
+
+    /// Whether multifile books in this format are served from their files instead of being
+    /// remuxed, see `MultifileStorage`.
+    fn serves_virtually(&self, extension: &str, mixed_formats: bool) -> bool {
+        self.library.multifile_storage == MultifileStorage::Virtual
+            && !mixed_formats
+            && virtual_file::can_concatenate(extension)
+    }
+
+    /// Whether a multifile book is in the data directory the way the library stores it. Books
+    /// stored the other way are stored again when the library's setting changed.
+    fn multifile_stored(&self, book: &Audiobook, path: &Path) -> Result<bool> {
+        let merged = self.data_path_of(book);
+        // only look at the files if it matters
+        let is_virtual = self.library.multifile_storage == MultifileStorage::Virtual
+            && self.serves_virtually(&book.file_extension, has_mixed_formats(&audio_filetypes(&path)?));
+        if is_virtual {
+            Ok(virtual_file::manifest_path(&merged).exists())
+        } else {
+            Ok(merged.exists())
+        }
+    }
+
+    /// Store a multifile book at `target_path`, or a manifest of its files next to it if the
+    /// library serves books from their files. Returns the path written.
+    fn store_collection(&self, target_path: &Path, book: &Audiobook,
+                        collection: &MultifileMetadata) -> Result<PathBuf> {
+        if self.serves_virtually(&book.file_extension, collection.mixed_formats.is_some()) {
+            let files: Vec<PathBuf> = collection.media_files.iter().map(|m| m.path.clone()).collect();
+            match VirtualFile::for_files(&files) {
+                Ok(parts) => {
+                    let manifest = virtual_file::manifest_path(target_path);
+                    debug!("writing manifest of files to {:?}", manifest);
+                    parts.write(&manifest)?;
+                    return Ok(manifest);
+                },
+                Err(e) => warn!("Can't serve {} from its files, remuxing it instead: {}", book.title, e),
+            }
+        }
+        self.mux_collection(&target_path, book, collection)?;
+        Ok(target_path.to_owned())
+    }
+
+    /// Move a stored book to its data file and remove what was stored for it the other way.
+    fn place_data_file(&self, written: &Path, book: &Audiobook) -> Result<()> {
+        let merged = self.data_path_of(book);
+        let manifest = virtual_file::manifest_path(&merged);
+        let (target, stale) = if virtual_file::is_manifest(written) {
+            (manifest, merged)
+        } else {
+            (merged, manifest)
+        };
+        if written != target {
+            debug!("Moving {:?} to {:?}.", written, target);
+            rename(written, &target)?;
+        }
+        if stale.exists() {
+            remove_file(&stale)?;
+        }
+        Ok(())
+    }
+
+    /// Write the files of a multifile book into a single file at `target_path`.
+    /// Books of a single format are remuxed, mixed formats have to be transcoded.
+    fn mux_collection(&self, target_path: &dyn AsRef<Path>, book: &Audiobook,
+                      collection: &MultifileMetadata) -> Result<()> {
+        let metadata = muxer::OutputMetadata::for_book(
+            &book, &collection.chapters, collection.length, collection.cover.as_ref()
+        );
+        if collection.mixed_formats.is_some() {
+            debug!("transcoding files into {:?}", target_path.as_ref());
+            transcoder::transcode_files(
+                target_path,
+                &collection.media_files,
+                &self.config.scan.mixed_format_target,
+                &metadata
+            )?;
+        } else {
+            debug!("muxing files into {:?}", target_path.as_ref());
+            muxer::merge_files(
+                target_path,
+                &collection.media_files,
+                &metadata
             )?;
+        }
         Ok(())
     }
 
 
     fn multifile_extract_chapters(&self, book: &mut Audiobook) -> Result<MultifileMetadata> {
         let book_path = Path::new(&self.library.location).join(book.location.clone());
-        let walker = WalkDir::new(book_path)
+        let filetypes = audio_filetypes(&book_path)?;
+        let mixed_formats = if has_mixed_formats(&filetypes) { Some(filetypes) } else { None };
+        let walker = WalkDir::new(&book_path)
             .follow_links(true)
             .sort_by(
                 |s, o| s.path().to_string_lossy().humane_cmp(&o.path().to_string_lossy())
             );
 
+        let rules = ChapterRules::for_library(&self.library)?;
+        let mut parts: Vec<BookPart> = Vec::new();
+        for entry in walker {
+            let file = entry?;
+            if file.path().is_dir() { continue };
+            match (file.path().extension(), &mixed_formats) {
+                (Some(ext), &Some(ref formats)) => {
+                    let ext = ext.to_string_lossy().to_lowercase();
+                    if !formats.iter().any(|f| f.to_string_lossy() == ext) { continue }
+                },
+                (Some(ext), &None) => {
+                    let ext = ext.to_string_lossy().to_lowercase();
+                    if container_of(&ext) != container_of(&book.file_extension.to_lowercase()) { continue }
+                },
+                (None, _) => { continue }
+            };
+            let media = MediaFile::read_file(file.path())?;
+            let info = media.get_mediainfo();
+            let relative_dir = file.path().parent()
+                .and_then(|p| p.strip_prefix(&book_path).ok())
+                .unwrap_or_else(|| Path::new(""));
+            let disc = disc_number(relative_dir, &info.metadata);
+            parts.push(BookPart { media, info, disc });
+        }
+        // The sort is stable so files of the same disc stay in humane order. Files without a
+        // disc, like bonus tracks next to the disc folders, come after the discs.
+        parts.sort_by_key(|part| part.disc.unwrap_or(u32::max_value()));
+        let multiple_discs = parts.windows(2).any(|w| w[0].disc != w[1].disc);
+
         let mut all_chapters: Vec<Chapter> = Vec::new();
         let mut mediafiles = Vec::new();
         let mut start_time = 0.0;
         let mut chapter_index = 0;
-        let mut cover: Option<Image> = None;
-
-        for entry in walker {
-            match entry {
-                Ok(file) => {
-                    if file.path().is_dir() { continue };
-                    match file.path().extension() {
-                        Some(ext) => if (ext.to_string_lossy()) != book.file_extension { continue },
-                        None => { continue }
-                    };
-                    let media = match MediaFile::read_file(file.path()) {
-                        Ok(f) => {
-                            let info = f.get_mediainfo();
-                            if chapter_index == 0 {
-                                use self::audiobooks::dsl::*;
-                                if let Some(new_title) = info.metadata.get("album") {
-                                    book.title = new_title.to_owned();
-                                }
-                                if let Some(new_artist) = info.metadata.get("artist") {
-                                    book.artist = Some(new_artist.to_owned());
-                                }
-                                let m = MediaFile::read_file(file.path()).unwrap();
-                                cover = m.get_coverart()?;
-                            };
-                            if Some(&info.title) != all_chapters.last().and_then(|c| c.title.as_ref() ) {
-                                let new_chapter = Chapter {
-                                    id: Uuid::new_v4(),
-                                    title: Some(info.title),
-                                    start_time,
-                                    audiobook_id: book.id,
-                                    number: chapter_index
-                                };
-                                chapter_index += 1;
-                                all_chapters.push(new_chapter);
-                            }
-                            start_time += info.length;
-                            f
-                        }
-                        Err(e) => return Err(e)
-                    };
-                    mediafiles.push(media)
+        let mut embedded_cover: Option<Image> = None;
+        let mut previous_disc = None;
+        let mut previous_key = None;
+
+        for part in parts {
+            let BookPart { media, info, disc } = part;
+            if chapter_index == 0 {
+                let mut tags = BookTags::from_metadata(&info.metadata);
+                // the album names a multifile book, the titles name its files
+                tags.title = tags.album.take();
+                merge_metadata_sidecars(&book_path, &mut tags);
+                let named = book_path.file_name().and_then(|n| SeriesName::parse(&n.to_string_lossy()));
+                match (&tags.title, &named) {
+                    (&Some(ref title), _) => book.title = title.clone(),
+                    (&None, &Some(ref named)) => book.title = named.title.clone(),
+                    (&None, &None) => {},
+                }
+                if let Some(ref new_artist) = tags.author {
+                    book.artist = Some(new_artist.clone());
+                }
+                tags.apply_to(book, named.as_ref());
+                let m = MediaFile::read_file(&media.path)?;
+                embedded_cover = m.get_coverart()?;
+            };
+            let file_title = rules.title(&info, &media.path);
+            let merge_key = rules.merge_key(&file_title, &media.path);
+            let title = match disc {
+                Some(number) if multiple_discs && self.config.scan.prefix_disc_chapters => {
+                    format!("Disc {} – {}", number, file_title)
                 },
-                Err(e) => return Err(e.into())
+                _ => file_title
             };
+            let continues_chapter = chapter_index > 0 && previous_disc == disc
+                && merge_key.is_some() && merge_key == previous_key;
+            if !continues_chapter {
+                let new_chapter = Chapter {
+                    id: Uuid::new_v4(),
+                    title: Some(title),
+                    start_time,
+                    audiobook_id: book.id,
+                    number: chapter_index,
+                    generated: false,
+                };
+                chapter_index += 1;
+                all_chapters.push(new_chapter);
+            }
+            previous_disc = disc;
+            previous_key = merge_key;
+            start_time += info.length;
+            mediafiles.push(media)
         };
 
+        let cover = self.choose_cover(&book_path, embedded_cover).map(|(image, source)| {
+            book.cover_source = Some(source);
+            image
+        });
+
+        if let Some(chapters) = read_chapter_sidecar(&book_path) {
+            all_chapters = model_chapters(book, &chapters, false);
+        } else {
+            let files: Vec<PathBuf> = mediafiles.iter().map(|m| m.path.clone()).collect();
+            if let Some(generated) = self.generated_chapters(all_chapters.len(), &files, start_time) {
+                all_chapters = model_chapters(book, &generated, true);
+            }
+        }
+
         Ok(MultifileMetadata {
             media_files: mediafiles,
             chapters: all_chapters,
             length: start_time,
             cover: cover,
+            mixed_formats,
         })
     }
 
@@ -490,9 +964,14 @@ impl Scanner {
             return Ok(());
         };
 
-        let filetype = match probable_audio_filetype(&path)? {
-            Some(e) => e,
-            None => return Err(WorkerError::NoValidFileExtensions.into())
+        let filetypes = audio_filetypes(&path)?;
+        let filetype = if has_mixed_formats(&filetypes) {
+            OsString::from(&self.config.scan.mixed_format_target.extension)
+        } else {
+            match probable_audio_filetype(&path)? {
+                Some(e) => e,
+                None => return Err(WorkerError::NoValidFileExtensions.into())
+            }
         };
 
         debug!("decided on file type {:?}", filetype);
@@ -511,7 +990,19 @@ impl Scanner {
             artist: None,
             hash,
             file_extension: filetype.to_owned().into_string().unwrap(),
-            deleted: false
+            deleted: false,
+            narrator: None,
+            series: None,
+            series_index: None,
+            description: None,
+            publisher: None,
+            year: None,
+            genres: Genres::default(),
+            language: None,
+            isbn: None,
+            asin: None,
+            cover_source: None,
+            palette: None,
         };
 
         let temp_target_path = self.build_target_path(
@@ -519,11 +1010,7 @@ impl Scanner {
         );
 
         let collection = self.multifile_extract_chapters(&mut default_book)?;
-        debug!("muxing files into {:?}", temp_target_path);
-        muxer::merge_files(
-            &temp_target_path,
-            &collection.media_files
-        )?;
+        let written = self.store_collection(Path::new(&temp_target_path), &default_book, &collection)?;
 
 
         let inserted = conn.exclusive_transaction(||  -> Result<Audiobook> {
@@ -532,8 +1019,20 @@ impl Scanner {
                 &relative_path, &self.library, &default_book, conn
             )?;
 
+            let mut warnings = Vec::new();
+            if let Some(ref formats) = collection.mixed_formats {
+                let format_list: Vec<_> = formats.iter().map(|f| f.to_string_lossy()).collect();
+                let message = format!(
+                    "Book consists of files with different formats ({}), they were transcoded to {}.",
+                    format_list.join(", "), self.config.scan.mixed_format_target.extension
+                );
+                warn!("{}: {}", book.title, message);
+                warnings.push(ScanWarning::new(&book, message));
+            }
+            ScanWarning::replace_for(&book, &warnings, conn)?;
+
             if let Some(img) = collection.cover {
-                self.save_coverart(&book, &img);
+                self.save_coverart(conn, &mut book, &img)?;
             }
 
             book.length = collection.length;
@@ -546,11 +1045,7 @@ impl Scanner {
                 Audiobook::belonging_to(&self.library).filter(audiobooks::dsl::id.eq(&book.id))
             ).set(&book).execute(conn)?;
 
-            let target_path = self.build_target_path(
-                &self.config.data_directory, &book.id, &filetype
-            );
-            debug!("Moving {} to {}.", temp_target_path, target_path);
-            rename(temp_target_path, target_path)?;
+            self.place_data_file(&written, &book)?;
             debug!("End transaction inserting multifile audiobook.");
             Ok(book)
         });
@@ -585,6 +1080,56 @@ impl Scanner {
     }
 }
 
+/// Chapters of a chapter sidecar next to the book, these take precedence over all other sources.
+fn read_chapter_sidecar(path: &Path) -> Option<Vec<mediafile::Chapter>> {
+    let sidecar_path = chapter_sidecar::find_for(path)?;
+    match chapter_sidecar::read_file(&sidecar_path) {
+        Ok(chapters) => {
+            info!("Using {} chapters from {:?}", chapters.len(), sidecar_path);
+            Some(chapters)
+        },
+        Err(e) => {
+            warn!("Ignoring unreadable chapter file {:?}: {}", sidecar_path, e);
+            None
+        }
+    }
+}
+
+/// Merge the metadata sidecars of a book over its tags, later sidecars take precedence.
+fn merge_metadata_sidecars(path: &Path, tags: &mut BookTags) {
+    for sidecar_path in metadata_sidecar::find_for(path) {
+        match metadata_sidecar::read_file(&sidecar_path) {
+            Ok(sidecar) => {
+                info!("Using metadata from {:?}", sidecar_path);
+                metadata_sidecar::merge(tags, sidecar);
+            },
+            Err(e) => warn!("Ignoring unreadable metadata file {:?}: {}", sidecar_path, e)
+        }
+    }
+}
+
+/// Database rows for chapters read from a file, numbered in order.
+fn model_chapters(book: &Audiobook, chapters: &[mediafile::Chapter], generated: bool) -> Vec<Chapter> {
+    chapters.iter().enumerate().map(|(i, chapter)| {
+        Chapter {
+            id: Uuid::new_v4(),
+            audiobook_id: book.id,
+            start_time: chapter.start,
+            title: chapter.title.clone(),
+            number: i as i64,
+            generated,
+        }
+    }).collect()
+}
+
+/// Files describing a book that live next to it, these are never books themselves.
+fn is_sidecar(path: &Path) -> bool {
+    match path.extension() {
+        Some(ext) => SIDECAR_EXTENSIONS.iter().any(|s| ext.to_string_lossy().eq_ignore_ascii_case(s)),
+        None => false
+    }
+}
+
 fn is_audiobook(path: &Path, regex: &Regex) -> bool {
     regex.is_match(path.to_str().unwrap())
 }
@@ -621,8 +1166,9 @@ pub(super) fn probable_audio_filetype(path: &dyn AsRef<Path>) -> Result<Option<O
         .follow_links(true)
         .into_iter()
         .filter_map(|opt| {
-            match opt.map(|wd| wd.path().extension().map(|el| el.to_owned())) {
-                Ok(Some(ext)) => Some(ext),
+            match opt.map(|wd| wd.path().to_owned()) {
+                // cover images and other sidecars could outnumber the audio files
+                Ok(ref path) if !is_sidecar(path) => path.extension().map(|el| el.to_owned()),
                 _ => None
             }
         });
@@ -635,6 +1181,54 @@ pub(super) fn probable_audio_filetype(path: &dyn AsRef<Path>) -> Result<Option<O
     Ok(filetypes.pop().map(|el| el.0))
 }
 
+/// Disc number of a file in a multifile book. Subfolders named like `CD2` or `Part 2` take
+/// precedence as they reflect the structure on disk, the disc tag is used for flat directories.
+pub(super) fn disc_number(relative_dir: &Path, tags: &HashMap<String, String>) -> Option<u32> {
+    let from_folder = relative_dir.components().rev().filter_map(|c| {
+        let name = c.as_os_str().to_string_lossy();
+        DISC_FOLDER_REGEX.captures(&name).and_then(|cap| cap[1].parse().ok())
+    }).next();
+    from_folder.or_else(|| {
+        tags.get("disc")
+            .or_else(|| tags.get("discnumber"))
+            .and_then(|d| d.split('/').next())
+            .and_then(|d| d.trim().parse().ok())
+    })
+}
+
+/// Find all distinct extensions of audio files in a directory, sorted by name.
+pub(super) fn audio_filetypes(path: &dyn AsRef<Path>) -> Result<Vec<OsString>> {
+    let mut filetypes: Vec<OsString> = Vec::new();
+    for entry in WalkDir::new(path.as_ref()).follow_links(true) {
+        let entry = entry?;
+        if entry.file_type().is_dir() { continue };
+        if let Some(ext) = entry.path().extension() {
+            let ext = OsString::from(ext.to_string_lossy().to_lowercase());
+            let is_audio = AUDIO_EXTENSIONS.iter().any(|a| ext == OsStr::new(a));
+            if is_audio && !filetypes.contains(&ext) {
+                filetypes.push(ext);
+            }
+        }
+    }
+    filetypes.sort();
+    Ok(filetypes)
+}
+
+/// Whether files with these extensions can't be remuxed into one file. Extensions of the same
+/// container, like `m4a` and `m4b`, don't make a book mixed.
+pub(super) fn has_mixed_formats(filetypes: &[OsString]) -> bool {
+    let mut containers = filetypes.iter().map(|ext| container_of(&ext.to_string_lossy()).to_owned());
+    match containers.next() {
+        Some(first) => containers.any(|c| c != first),
+        None => false
+    }
+}
+
+/// The container files with a lowercase extension are in, the extension itself for most formats.
+fn container_of(extension: &str) -> &str {
+    if MP4_EXTENSIONS.contains(&extension) { "mp4" } else { extension }
+}
+
 /// Determines whether a scan of a path is necessary based on file change data
 fn should_scan(path: &Path, last_scan: Option<NaiveDateTime>) -> Result<bool> {
     match most_recent_change(&path)? {
@@ -9,7 +9,7 @@ use walkdir::WalkDir;
 use crate::worker::util;
 use crate::helpers::db::init_test_db_pool;
 use crate::helpers::db::Pool;
-use crate::models::library::Library;
+use crate::models::library::{Library, ChapterStrategy, CoverPreference, MultifileStorage};
 use crate::models::audiobook::Audiobook;
 use crate::worker::scanner::{Scanner, LockingBehavior};
 use crate::helpers::uuid::Uuid;
@@ -94,7 +94,7 @@ speculate! {
         util::shut_up_ffmpeg();
 
         use crate::models::audiobook::{Audiobook, Update};
-        use crate::models::library::Library;
+        use crate::models::library::{Library, ChapterStrategy, CoverPreference, MultifileStorage};
         use crate::schema::libraries;
         use crate::worker::scanner;
         let library = Library{
@@ -102,6 +102,10 @@ speculate! {
             location: "".to_owned(),
             is_audiobook_regex: "^[^/]+$".to_owned(),
             last_scan: None,
+            chapter_strategy: ChapterStrategy::default(),
+            chapter_regex: None,
+            cover_preference: CoverPreference::default(),
+            multifile_storage: MultifileStorage::default(),
         };
         diesel::insert_into(libraries::table)
             .values(&library)
@@ -282,6 +286,76 @@ speculate! {
             assert_eq!(book.id, book2.id);
         }
 
+        it "chapter_sidecar_changed" {
+            use crate::models::chapter::Chapter;
+            use crate::schema::chapters::dsl::number;
+            println!("============Step 1!============");
+            let mut base = String::from("integration-tests/chapter_sidecar_changed/01");
+            set_date(&base, &NaiveDate::from_ymd(1990, 1, 1));
+            scanner.library.location = base.clone();
+            scanner.incremental_scan(LockingBehavior::Dont);
+            let book = all_books(&scanner, &pool).pop().unwrap();
+            let chapters = Chapter::belonging_to(&book).order(number.asc())
+                .load::<Chapter>(&*(pool.get().unwrap())).unwrap();
+            assert_eq!(2, chapters.len());
+            assert_eq!(chapters[1].title, Some("The Poems".to_owned()));
+
+            println!("============Step 2!============");
+            // only the chapter list changes, the audio keeps its old time stamp
+            base = String::from("integration-tests/chapter_sidecar_changed/02");
+            set_date(&(base.clone() + "/book.mp3"), &NaiveDate::from_ymd(1990, 1, 1));
+            set_date(&(base.clone() + "/book.chapters.txt"), &NaiveDate::from_ymd(2050, 1, 1));
+            scanner.library.location = base.clone();
+            scanner.incremental_scan(LockingBehavior::Dont);
+            assert_eq!(1, count_books(&scanner, &pool));
+            let chapters = Chapter::belonging_to(&book).order(number.asc())
+                .load::<Chapter>(&*(pool.get().unwrap())).unwrap();
+            assert_eq!(3, chapters.len());
+            assert_eq!(chapters[2].title, Some("Second Poem".to_owned()));
+            assert_eq!(chapters[2].start_time, 150.5);
+        }
+
+        it "metadata_sidecar_changed" {
+            println!("============Step 1!============");
+            let mut base = String::from("integration-tests/metadata_sidecar_changed/01");
+            set_date(&base, &NaiveDate::from_ymd(1990, 1, 1));
+            scanner.library.location = base.clone();
+            scanner.incremental_scan(LockingBehavior::Dont);
+            let book = all_books(&scanner, &pool).pop().unwrap();
+            assert_eq!(book.title, "Poems");
+            assert_eq!(book.narrator, None);
+
+            println!("============Step 2!============");
+            // only the sidecars change, the audio keeps its old time stamp
+            base = String::from("integration-tests/metadata_sidecar_changed/02");
+            set_date(&(base.clone() + "/book.mp3"), &NaiveDate::from_ymd(1990, 1, 1));
+            set_date(&(base.clone() + "/book.metadata.json"), &NaiveDate::from_ymd(2050, 1, 1));
+            set_date(&(base.clone() + "/book.opf"), &NaiveDate::from_ymd(2050, 1, 1));
+            scanner.library.location = base.clone();
+            scanner.incremental_scan(LockingBehavior::Dont);
+            assert_eq!(1, count_books(&scanner, &pool));
+            let book = all_books(&scanner, &pool).pop().unwrap();
+            // metadata.json takes precedence over the OPF file
+            assert_eq!(book.title, "Collected Poems");
+            assert_eq!(book.narrator, Some("Gert Westphal".to_owned()));
+            assert_eq!(book.series, Some("Bulgarian Poetry".to_owned()));
+            assert_eq!(book.series_index, Some(2.0));
+            assert_eq!(book.publisher, Some("Hemus".to_owned()));
+
+            println!("============Step 3!============");
+            // removing the sidecars brings back the values of the tags
+            base = String::from("integration-tests/metadata_sidecar_changed/03");
+            set_date(&(base.clone() + "/book.mp3"), &NaiveDate::from_ymd(1990, 1, 1));
+            scanner.library.location = base.clone();
+            scanner.incremental_scan(LockingBehavior::Dont);
+            assert_eq!(1, count_books(&scanner, &pool));
+            let book = all_books(&scanner, &pool).pop().unwrap();
+            assert_ne!(book.title, "Collected Poems");
+            assert_eq!(book.narrator, None);
+            assert_eq!(book.series, None);
+            assert_eq!(book.publisher, None);
+        }
+
         it "content_changed_multifile" {
             use crate::schema::audiobooks::dsl::deleted;
             println!("============Step 1!============");
@@ -348,6 +422,77 @@ speculate! {
             assert!(data_cover_file(&book).exists());
         }
 
+        it "cover_images" {
+            let base = String::from("integration-tests/cover_images");
+            scanner.library.location = base.clone();
+            scanner.incremental_scan(LockingBehavior::Dont);
+            let books = all_books(&scanner, &pool);
+            let cover_of = |location: &str| books.iter()
+                .find(|b| b.location == location).unwrap()
+                .cover_source.clone();
+            // the multifile book has an embedded cover, which is preferred by default
+            assert_eq!(cover_of("book"), Some("embedded".to_owned()));
+            assert_eq!(cover_of("single.mp3"), Some("single.png".to_owned()));
+            for book in &books {
+                assert!(data_cover_file(book).exists());
+                let palette = book.palette.as_ref().unwrap();
+                assert!(palette.dominant.starts_with('#'));
+                assert_eq!(palette.blurhash.len(), 28);
+            }
+        }
+
+        it "cover_images_preferred" {
+            use std::io::Read;
+            let base = String::from("integration-tests/cover_images");
+            scanner.library.location = base.clone();
+            scanner.library.cover_preference = CoverPreference::Folder;
+            scanner.incremental_scan(LockingBehavior::Dont);
+            let books = all_books(&scanner, &pool);
+            let book = books.iter().find(|b| b.location == "book").unwrap();
+            assert_eq!(book.cover_source, Some("book/cover.png".to_owned()));
+            let mut saved = Vec::new();
+            File::open(data_cover_file(book)).unwrap().read_to_end(&mut saved).unwrap();
+            assert!(saved.starts_with(b"\x89PNG"));
+        }
+
+        it "virtual_multifile" {
+            use crate::worker::virtual_file::{self, VirtualFile};
+            let base = String::from("integration-tests/virtual_multifile");
+            scanner.library.location = base.clone();
+            scanner.library.multifile_storage = MultifileStorage::Virtual;
+            scanner.incremental_scan(LockingBehavior::Dont);
+            let book = all_books(&scanner, &pool).first().unwrap().clone();
+            let manifest = virtual_file::manifest_path(&data_file(&book));
+            assert!(!data_file(&book).exists());
+            let parts = VirtualFile::read(&manifest).unwrap();
+            assert!(parts.parts.iter().all(|p| p.path.starts_with(&base)));
+            assert_eq!(book.data_file("data"), manifest);
+
+            // the book is remuxed once the library stops serving books from their files
+            scanner.library.multifile_storage = MultifileStorage::Remux;
+            scanner.incremental_scan(LockingBehavior::Dont);
+            assert!(data_file(&book).exists());
+            assert!(!manifest.exists());
+        }
+
+        it "vbr_copy" {
+            let base = String::from("integration-tests/vbr_copy");
+            let original = std::fs::read(format!("{}/vbr.mp3", base)).unwrap();
+            scanner.library.location = base.clone();
+            scanner.incremental_scan(LockingBehavior::Dont);
+            let books = all_books(&scanner, &pool);
+            let vbr = books.iter().find(|b| b.location == "vbr.mp3").unwrap();
+            let cbr = books.iter().find(|b| b.location == "cbr.mp3").unwrap();
+
+            // only the VBR book gets a copy, with a seek table and a Xing header
+            assert!(std::fs::symlink_metadata(data_file(cbr)).unwrap().file_type().is_symlink());
+            assert!(!std::fs::symlink_metadata(data_file(vbr)).unwrap().file_type().is_symlink());
+            let copy = std::fs::read(data_file(vbr)).unwrap();
+            assert!(copy.windows(4).any(|w| w == b"MLLT"));
+            assert!(copy.windows(4).any(|w| w == b"Xing"));
+            assert_eq!(std::fs::read(format!("{}/vbr.mp3", base)).unwrap(), original);
+        }
+
         test "multifile_add_file" {
             let mut base1 = data_path!("01");
             set_date(&base1, &NaiveDate::from_ymd(2008, 1, 1));
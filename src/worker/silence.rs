@@ -0,0 +1,158 @@
+use crate::ffmpeg::*;
+
+use std::collections::HashMap;
+use std::os::raw::c_int;
+use std::path::PathBuf;
+use std::ptr;
+use std::slice;
+
+use super::mediafile::{Chapter, MediaFile};
+use super::transcoder::{self, Decoder};
+use super::util::*;
+use crate::worker::error::*;
+
+/// A stretch of silence, in seconds from the start of the book.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Silence {
+    pub start: f64,
+    pub end: f64,
+}
+
+impl Silence {
+    pub fn middle(&self) -> f64 {
+        (self.start + self.end) / 2.0
+    }
+}
+
+/// Collects silences from a stream of mono samples, like ffmpeg's `silencedetect` filter.
+struct SilenceDetector {
+    threshold: f32,
+    min_length: f64,
+    sample_rate: c_int,
+    offset: f64,
+    samples_seen: u64,
+    silent_since: Option<u64>,
+    silences: Vec<Silence>,
+}
+
+impl SilenceDetector {
+    fn push(&mut self, samples: &[f32]) {
+        for sample in samples {
+            if sample.abs() < self.threshold {
+                if self.silent_since.is_none() {
+                    self.silent_since = Some(self.samples_seen);
+                }
+            } else {
+                self.close_silence();
+            }
+            self.samples_seen += 1;
+        }
+    }
+
+    fn close_silence(&mut self) {
+        if let Some(since) = self.silent_since.take() {
+            let rate = f64::from(self.sample_rate);
+            let start = since as f64 / rate;
+            let end = self.samples_seen as f64 / rate;
+            if end - start >= self.min_length {
+                self.silences.push(Silence {
+                    start: self.offset + start,
+                    end: self.offset + end,
+                });
+            }
+        }
+    }
+}
+
+/// Find silences in a book made of `files`, which are played one after another.
+/// `threshold_db` is the loudness below which audio counts as silent, `min_length` in seconds.
+pub fn find_silences(files: &[PathBuf], threshold_db: f64, min_length: f64) -> Result<Vec<Silence>> {
+    let threshold = 10f64.powf(threshold_db / 20.0) as f32;
+    let mut silences = Vec::new();
+    let mut offset = 0.0;
+    for path in files {
+        let file = MediaFile::read_file(path)?;
+        let length = file.get_mediainfo().length;
+        silences.extend(silences_in_file(&file, threshold, min_length, offset)?);
+        offset += length;
+    }
+    Ok(silences)
+}
+
+fn silences_in_file(file: &MediaFile, threshold: f32, min_length: f64, offset: f64) -> Result<Vec<Silence>> {
+    debug!("detecting silences in {:?}", file.path);
+    let decoder = Decoder::open(file)?;
+    unsafe {
+        let sample_rate = (*decoder.ctx).sample_rate;
+        let mut detector = SilenceDetector {
+            threshold,
+            min_length,
+            sample_rate,
+            offset,
+            samples_seen: 0,
+            silent_since: None,
+            silences: Vec::new(),
+        };
+        let mut swr = swr_alloc_set_opts(
+            ptr::null_mut(),
+            av_get_default_channel_layout(1),
+            AVSampleFormat::AV_SAMPLE_FMT_FLT,
+            sample_rate,
+            (*decoder.ctx).channel_layout as i64,
+            (*decoder.ctx).sample_fmt,
+            sample_rate,
+            0,
+            ptr::null_mut()
+        );
+        let result = check_av_result(swr_init(swr))
+            .and_then(|_| transcoder::decode_frames(file, &decoder, |frame| {
+                feed_detector(swr, frame, &mut detector)
+            }))
+            .and_then(|_| feed_detector(swr, ptr::null_mut(), &mut detector));
+        swr_free(&mut swr);
+        result?;
+        detector.close_silence();
+        Ok(detector.silences)
+    }
+}
+
+/// Downmix a decoded frame to mono floats and pass them on to the detector.
+unsafe fn feed_detector(swr: *mut SwrContext, frame: *mut AVFrame, detector: &mut SilenceDetector) -> Result<()> {
+    let mut mono = av_frame_alloc();
+    (*mono).channel_layout = av_get_default_channel_layout(1) as u64;
+    (*mono).sample_rate = detector.sample_rate;
+    (*mono).format = AVSampleFormat::AV_SAMPLE_FMT_FLT as c_int;
+    let result = check_av_result(swr_convert_frame(swr, mono, frame)).map(|_| {
+        if (*mono).nb_samples > 0 {
+            detector.push(slice::from_raw_parts((*mono).data[0] as *const f32, (*mono).nb_samples as usize));
+        }
+    });
+    av_frame_free(&mut mono);
+    result
+}
+
+/// Chapters roughly every `interval` seconds, each starting in the middle of the silence closest
+/// to where it should start. Where no silence is close enough the chapter starts right on time.
+pub fn chapters_at_silences(silences: &[Silence], length: f64, interval: f64) -> Vec<Chapter> {
+    let mut starts = vec![0.0];
+    let mut target = interval;
+    // no chapter shortly before the end, the last one gets up to one and a half intervals instead
+    while interval > 0.0 && target < length - interval / 2.0 {
+        let previous = starts[starts.len() - 1];
+        let nearest = silences.iter()
+            .map(Silence::middle)
+            .filter(|&middle| middle > previous + interval / 2.0 && middle < length)
+            .min_by(|a, b| (a - target).abs().partial_cmp(&(b - target).abs()).unwrap());
+        let start = match nearest {
+            Some(middle) if (middle - target).abs() <= interval / 2.0 => middle,
+            _ => target
+        };
+        starts.push(start);
+        target = start + interval;
+    }
+    starts.into_iter().enumerate().map(|(i, start)| Chapter {
+        title: Some(format!("Chapter {}", i + 1)),
+        metadata: HashMap::new(),
+        start,
+    }).collect()
+}
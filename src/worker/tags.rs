@@ -0,0 +1,142 @@
+use std::collections::HashMap;
+use regex::Regex;
+
+use crate::models::audiobook::{Audiobook, Genres};
+
+// ffmpeg maps iTunes atoms and ID3 frames onto common keys, e.g. `©nam` and `TIT2` both become
+// `title`. Each field lists the keys to try, most specific first.
+const TITLE_KEYS: &[&str] = &["title"];
+const ALBUM_KEYS: &[&str] = &["album"];
+const AUTHOR_KEYS: &[&str] = &["artist", "album_artist", "author"];
+/// The narrator is stored as composer (`©wrt`, `TCOM`) by most tools. Some write a freeform
+/// `NARRATOR` tag or an `©nrt` atom, which ffmpeg passes on with a mangled copyright sign.
+const NARRATOR_KEYS: &[&str] = &["narrator", "narratedby", "©nrt", "\u{fffd}nrt", "composer"];
+/// `ldes` holds the full description, `desc` is limited to 255 characters.
+const DESCRIPTION_KEYS: &[&str] = &["synopsis", "description", "comment"];
+const DATE_KEYS: &[&str] = &["date", "year", "originaldate"];
+const GENRE_KEYS: &[&str] = &["genre"];
+/// Mp3tag and iTunes store series as movement name and number
+const SERIES_KEYS: &[&str] = &["series", "movementname", "mvnm"];
+const SERIES_INDEX_KEYS: &[&str] = &["series-part", "series_part", "seriespart", "series_index", "movement", "mvin"];
+const PUBLISHER_KEYS: &[&str] = &["publisher", "label", "©pub", "\u{fffd}pub"];
+const LANGUAGE_KEYS: &[&str] = &["language", "lang"];
+const ISBN_KEYS: &[&str] = &["isbn"];
+const ASIN_KEYS: &[&str] = &["asin", "audible_asin"];
+
+lazy_static! {
+    /// Book names like `Discworld 01 - The Colour of Magic` or `The Expanse - Book 3 - Abaddon's Gate`
+    static ref SERIES_NAME_REGEX: Regex = Regex::new(
+        r"(?i)^(?P<series>.+?)\s*(?:[-–,]\s*)?(?:#|(?:book|band|vol(?:ume)?|part|teil|folge)\.?\s*)?(?P<index>\d+(?:\.\d+)?)\s+[-–]\s+(?P<title>.+)$"
+    ).unwrap();
+}
+
+/// Tags describing a book, normalized across container formats.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct BookTags {
+    pub title: Option<String>,
+    pub album: Option<String>,
+    pub author: Option<String>,
+    pub narrator: Option<String>,
+    pub description: Option<String>,
+    pub year: Option<i32>,
+    pub genre: Option<String>,
+    pub series: Option<String>,
+    pub series_index: Option<f64>,
+    pub publisher: Option<String>,
+    pub language: Option<String>,
+    pub isbn: Option<String>,
+    pub asin: Option<String>,
+}
+
+/// Series information taken from the name of a book's file or folder.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SeriesName {
+    pub series: String,
+    pub index: f64,
+    pub title: String,
+}
+
+impl SeriesName {
+    pub fn parse(name: &str) -> Option<Self> {
+        let cap = SERIES_NAME_REGEX.captures(name.trim())?;
+        let series = cap["series"].trim_end_matches(|c: char| c.is_whitespace() || c == '-' || c == ',').to_owned();
+        // names like `01 - Intro` have no series
+        if !series.chars().any(char::is_alphabetic) {
+            return None;
+        }
+        Some(SeriesName {
+            series,
+            index: cap["index"].parse().ok()?,
+            title: cap["title"].trim().to_owned(),
+        })
+    }
+}
+
+impl BookTags {
+    pub fn from_metadata(metadata: &HashMap<String, String>) -> Self {
+        let lowercase: HashMap<String, &str> = metadata.iter()
+            .map(|(k, v)| (k.to_lowercase(), v.trim()))
+            .filter(|&(_, v)| !v.is_empty())
+            .collect();
+        let first = |keys: &[&str]| -> Option<String> {
+            keys.iter().filter_map(|k| lowercase.get(*k)).next().map(|v| (*v).to_owned())
+        };
+        BookTags {
+            title: first(TITLE_KEYS),
+            album: first(ALBUM_KEYS),
+            author: first(AUTHOR_KEYS),
+            narrator: first(NARRATOR_KEYS),
+            description: first(DESCRIPTION_KEYS),
+            year: first(DATE_KEYS).and_then(|date| parse_year(&date)),
+            genre: first(GENRE_KEYS),
+            series: first(SERIES_KEYS),
+            series_index: first(SERIES_INDEX_KEYS).and_then(|index| parse_index(&index)),
+            publisher: first(PUBLISHER_KEYS),
+            language: first(LANGUAGE_KEYS),
+            isbn: first(ISBN_KEYS),
+            asin: first(ASIN_KEYS),
+        }
+    }
+
+    /// Fill in the descriptive columns of a book. Series information missing from the tags is
+    /// taken from the name of the book, if it follows the `Series 03 - Title` convention.
+    pub fn apply_to(&self, book: &mut Audiobook, named: Option<&SeriesName>) {
+        book.narrator = self.narrator.clone();
+        book.description = self.description.clone();
+        book.publisher = self.publisher.clone();
+        book.year = self.year;
+        book.genres = self.genre.as_ref().map(|g| Genres::from_tag(g)).unwrap_or_default();
+        book.language = self.language.clone();
+        book.isbn = self.isbn.clone();
+        book.asin = self.asin.clone();
+        match (&self.series, named) {
+            (&Some(ref series), _) => {
+                book.series = Some(series.clone());
+                book.series_index = self.series_index;
+            },
+            (&None, Some(named)) => {
+                book.series = Some(named.series.clone());
+                book.series_index = Some(named.index);
+            },
+            (&None, None) => {
+                book.series = None;
+                book.series_index = None;
+            }
+        }
+    }
+}
+
+/// Series positions are written as `3`, `3.5` or `3/7`.
+pub(crate) fn parse_index(index: &str) -> Option<f64> {
+    index.split('/').next().and_then(|i| i.trim().parse().ok())
+}
+
+/// Dates come as `2004`, `2004-05-01` or full timestamps, all start with the year.
+pub(crate) fn parse_year(date: &str) -> Option<i32> {
+    let digits: String = date.chars().take_while(|c| c.is_ascii_digit()).collect();
+    if digits.len() == 4 {
+        digits.parse().ok()
+    } else {
+        None
+    }
+}
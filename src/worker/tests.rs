@@ -26,7 +26,7 @@ speculate! {
 
     describe "scanner_tests" {
         before {
-            use crate::models::library::Library;
+            use crate::models::library::{Library, ChapterStrategy, CoverPreference, MultifileStorage};
             use crate::schema::libraries;
             use crate::worker::scanner;
             let library = Library {
@@ -34,6 +34,10 @@ speculate! {
                 location: "test-data".to_owned(),
                 is_audiobook_regex: "^[^/]+$".to_owned(),
                 last_scan: None,
+                chapter_strategy: ChapterStrategy::default(),
+                chapter_regex: None,
+                cover_preference: CoverPreference::default(),
+                multifile_storage: MultifileStorage::default(),
             };
             diesel::insert_into(libraries::table)
                 .values(&library)
@@ -58,6 +62,106 @@ speculate! {
             assert_eq!(1, Audiobook::belonging_to(&library).count().first::<i64>(&*conn).unwrap());
         }
 
+        it "uses cue sheets for chapters" {
+            use crate::models::audiobook::Audiobook;
+            use crate::models::chapter::Chapter;
+            use crate::schema::chapters::dsl::number;
+            test_scanner.create_audiobook(&*conn, &Path::new("test-data/4.mp3")).unwrap();
+            let book = Audiobook::belonging_to(&library).first::<Audiobook>(&*conn).unwrap();
+            let chapters = Chapter::belonging_to(&book).order(number.asc()).load::<Chapter>(&*conn).unwrap();
+            assert_eq!(chapters.len(), 3);
+            assert_eq!(chapters[1].title, Some("Middle Part".to_owned()));
+            assert_eq!(chapters[1].start_time, 2.0);
+        }
+
+        it "generates chapters at silences for books without chapters" {
+            use crate::models::audiobook::Audiobook;
+            use crate::models::chapter::Chapter;
+            use crate::schema::chapters::dsl::number;
+            let mut silence_scanner = test_scanner;
+            silence_scanner.config.scan.generated_chapters.enabled = true;
+            silence_scanner.config.scan.generated_chapters.interval = 0.1;
+            silence_scanner.create_audiobook(&*conn, &Path::new("test-data/3.mp3")).unwrap();
+            let book = Audiobook::belonging_to(&library).first::<Audiobook>(&*conn).unwrap();
+            let chapters = Chapter::belonging_to(&book).order(number.asc()).load::<Chapter>(&*conn).unwrap();
+            assert!(chapters.len() > 1);
+            assert!(chapters.iter().all(|c| c.generated));
+            assert_eq!(chapters[0].start_time, 0.0);
+            assert!(chapters.windows(2).all(|w| w[0].start_time < w[1].start_time));
+        }
+
+        it "reads iTunes tags and text track chapters of m4b files" {
+            use crate::models::audiobook::Audiobook;
+            use crate::models::chapter::Chapter;
+            use crate::schema::chapters::dsl::number;
+            test_scanner.create_audiobook(&*conn, &Path::new("test-data/itunes.m4b")).unwrap();
+            let book = Audiobook::belonging_to(&library).first::<Audiobook>(&*conn).unwrap();
+            assert_eq!(book.title, "[Bulgarian]Stihotvorenia");
+            assert_eq!(book.artist, Some("Mara Belcheva".to_owned()));
+            let chapters = Chapter::belonging_to(&book).order(number.asc()).load::<Chapter>(&*conn).unwrap();
+            assert_eq!(chapters.len(), 4);
+            assert_eq!(chapters[0].start_time, 0.0);
+            assert_eq!(chapters[0].title, Some("1 - Svarshi se igrata...".to_owned()));
+        }
+
+        it "stores descriptive tags of books" {
+            use crate::models::audiobook::Audiobook;
+            test_scanner.create_audiobook(&*conn, &Path::new("test-data/itunes.m4b")).unwrap();
+            let book = Audiobook::belonging_to(&library).first::<Audiobook>(&*conn).unwrap();
+            assert_eq!(book.narrator, Some("Gert Westphal".to_owned()));
+            assert_eq!(book.year, Some(1929));
+            assert_eq!(book.genres.0, vec!["Audiobook".to_owned()]);
+            assert_eq!(book.series, None);
+        }
+
+        it "orders multi disc audiobooks by disc folder" {
+            use crate::models::audiobook::Audiobook;
+            use crate::models::chapter::Chapter;
+            use crate::schema::chapters::dsl::number;
+            let mut disc_scanner = test_scanner;
+            disc_scanner.config.scan.prefix_disc_chapters = true;
+            disc_scanner.create_multifile_audiobook(&*conn, &Path::new("test-data/multidisc")).unwrap();
+            let book = Audiobook::belonging_to(&library).first::<Audiobook>(&*conn).unwrap();
+            let chapters = Chapter::belonging_to(&book).order(number.asc()).load::<Chapter>(&*conn).unwrap();
+            let titles: Vec<String> = chapters.into_iter().map(|c| c.title.unwrap()).collect();
+            assert_eq!(titles.len(), 3);
+            assert!(titles[0].starts_with("Disc 1 – 3 - "));
+            assert_eq!(titles[1], "Disc 2 – 1 - Svarshi se igrata...");
+            assert!(titles[2].starts_with("Disc 2 – 2 - "));
+        }
+
+        it "puts files without a disc after the discs" {
+            use crate::models::audiobook::Audiobook;
+            use crate::models::chapter::Chapter;
+            use crate::schema::chapters::dsl::number;
+            let mut disc_scanner = test_scanner;
+            disc_scanner.config.scan.prefix_disc_chapters = true;
+            disc_scanner.create_multifile_audiobook(&*conn, &Path::new("test-data/multidisc_bonus")).unwrap();
+            let book = Audiobook::belonging_to(&library).first::<Audiobook>(&*conn).unwrap();
+            let chapters = Chapter::belonging_to(&book).order(number.asc()).load::<Chapter>(&*conn).unwrap();
+            let titles: Vec<String> = chapters.into_iter().map(|c| c.title.unwrap()).collect();
+            assert_eq!(titles.len(), 4);
+            assert!(titles[0].starts_with("Disc 1 – 3 - "));
+            assert_eq!(titles[1], "Disc 2 – 1 - Svarshi se igrata...");
+            assert!(titles[2].starts_with("Disc 2 – 2 - "));
+            assert!(!titles[3].starts_with("Disc "));
+        }
+
+        it "transcodes multi file audiobooks with mixed formats" {
+            use crate::models::audiobook::Audiobook;
+            use crate::models::chapter::Chapter;
+            use crate::models::scan_warning::ScanWarning;
+            test_scanner.create_multifile_audiobook(&*conn, &Path::new("test-data/mixed")).unwrap();
+            let book = Audiobook::belonging_to(&library).first::<Audiobook>(&*conn).unwrap();
+            assert_eq!(book.file_extension, "m4b");
+            assert_eq!(2, Chapter::belonging_to(&book).count().first::<i64>(&*conn).unwrap());
+            assert_eq!(1, ScanWarning::belonging_to(&book).count().first::<i64>(&*conn).unwrap());
+            let transcoded = MediaFile::read_file(
+                Path::new(&format!("data/{}.m4b", book.id.hyphenated()))
+            ).unwrap();
+            assert!((transcoded.get_mediainfo().length - book.length).abs() < 1.0);
+        }
+
     }
 
     before {
@@ -131,7 +235,43 @@ speculate! {
         it "can remux files" {
             let mut tmp_dir = get_tempdir();
             tmp_dir.push(Path::new("muxed.mp3"));
-            muxer::merge_files(&tmp_dir, &files).unwrap();
+            muxer::merge_files(&tmp_dir, &files, &muxer::OutputMetadata::default()).unwrap();
+        }
+
+        it "writes chapters, tags and cover when remuxing" {
+            use crate::models::chapter::Chapter;
+            let mut tmp_dir = get_tempdir();
+            tmp_dir.push(Path::new("muxed_metadata.mp3"));
+            let book_id = Uuid::new_v4();
+            let chapters: Vec<Chapter> = vec![0.0, 10.0, 20.0].into_iter().enumerate().map(|(i, start)| {
+                Chapter {
+                    id: Uuid::new_v4(),
+                    title: Some(format!("Chapter {}", i + 1)),
+                    audiobook_id: book_id,
+                    start_time: start,
+                    number: i as i64,
+                    generated: false,
+                }
+            }).collect();
+            let cover = MediaFile::read_file(Path::new("test-data/1.mp3")).unwrap().get_coverart().unwrap();
+            let mut metadata = muxer::OutputMetadata {
+                chapters: &chapters,
+                length: 30.0,
+                cover: cover.as_ref(),
+                ..Default::default()
+            };
+            metadata.tags.insert("album".to_owned(), "Muxed Book".to_owned());
+            metadata.tags.insert("artist".to_owned(), "Some Author".to_owned());
+            muxer::merge_files(&tmp_dir, &files, &metadata).unwrap();
+
+            let muxed = MediaFile::read_file(&tmp_dir).unwrap();
+            let muxed_chapters = muxed.get_chapters();
+            assert_eq!(muxed_chapters.len(), 3);
+            assert_eq!(muxed_chapters[1].clone().title.unwrap(), "Chapter 2");
+            let info = muxed.get_mediainfo();
+            assert_eq!(info.metadata.get("album").unwrap(), "Muxed Book");
+            assert_eq!(info.metadata.get("artist").unwrap(), "Some Author");
+            assert_eq!(muxed.get_coverart().unwrap().unwrap().image_type, ImageType::JPG);
         }
     }
 }
@@ -158,6 +298,394 @@ fn common_extension() {
     let ft = probable_audio_filetype(&"test-data/all");
     assert_eq!(ft.unwrap().unwrap(), OsString::from("mp3")) }
 
+#[test]
+fn mixed_extensions() {
+    use crate::worker::scanner::audio_filetypes;
+    let fts = audio_filetypes(&"test-data/mixed").unwrap();
+    assert_eq!(fts, vec![OsString::from("m4a"), OsString::from("mp3")]);
+    assert_eq!(audio_filetypes(&"test-data/all").unwrap(), vec![OsString::from("mp3")]);
+}
+
+#[test]
+fn mixed_formats() {
+    use crate::worker::scanner::has_mixed_formats;
+    let formats = |exts: &[&str]| exts.iter().map(OsString::from).collect::<Vec<_>>();
+    assert!(has_mixed_formats(&formats(&["m4a", "mp3"])));
+    assert!(!has_mixed_formats(&formats(&["m4a", "m4b", "mp4"])));
+    assert!(!has_mixed_formats(&formats(&["mp3"])));
+    assert!(!has_mixed_formats(&[]));
+}
+
+#[test]
+fn disc_numbers() {
+    use std::collections::HashMap;
+    use crate::worker::scanner::disc_number;
+    let no_tags = HashMap::new();
+    assert_eq!(disc_number(Path::new("CD2"), &no_tags), Some(2));
+    assert_eq!(disc_number(Path::new("Disc 03"), &no_tags), Some(3));
+    assert_eq!(disc_number(Path::new("Part_4/extras"), &no_tags), Some(4));
+    assert_eq!(disc_number(Path::new("bonus"), &no_tags), None);
+    let mut tags = HashMap::new();
+    tags.insert("disc".to_owned(), "5/6".to_owned());
+    assert_eq!(disc_number(Path::new(""), &tags), Some(5));
+    assert_eq!(disc_number(Path::new("CD1"), &tags), Some(1));
+}
+
+#[test]
+fn chapter_rules() {
+    use std::collections::HashMap;
+    use crate::models::library::ChapterStrategy;
+    use crate::worker::chapter_rules::ChapterRules;
+    use crate::worker::mediafile::MediaInfo;
+    let info = MediaInfo {
+        length: 1.0,
+        chapters: Vec::new(),
+        title: "Track 01".to_owned(),
+        metadata: HashMap::new(),
+    };
+    let path = Path::new("book/03-02 The Return.mp3");
+
+    let per_file = ChapterRules::new(ChapterStrategy::PerFile, None).unwrap();
+    assert_eq!(per_file.title(&info, path), "Track 01");
+    assert_eq!(per_file.merge_key("Track 01", path), None);
+
+    let titles = ChapterRules::new(ChapterStrategy::MergeTitles, None).unwrap();
+    assert_eq!(titles.merge_key("Track 01", path), Some("Track 01".to_owned()));
+
+    let regex = ChapterRules::new(ChapterStrategy::MergeRegex, None).unwrap();
+    assert_eq!(regex.merge_key("Track 01", path), Some("03".to_owned()));
+    let grouped = ChapterRules::new(ChapterStrategy::MergeRegex, Some(r"^\d+-(\d+)")).unwrap();
+    assert_eq!(grouped.merge_key("Track 01", path), Some("02".to_owned()));
+
+    let filename = ChapterRules::new(ChapterStrategy::Filename, None).unwrap();
+    assert_eq!(filename.title(&info, path), "The Return");
+    assert_eq!(filename.merge_key("The Return", path), None);
+}
+
+#[test]
+fn parses_cue_sheets() {
+    use crate::worker::cue::CueSheet;
+    let sheet = CueSheet::parse(r#"
+PERFORMER "Some Author"
+TITLE "The Book"
+FILE "book part 1.mp3" MP3
+  TRACK 01 AUDIO
+    TITLE "First"
+    INDEX 01 00:00:00
+  TRACK 02 AUDIO
+    TITLE "Second"
+    INDEX 00 01:02:00
+    INDEX 01 01:02:15
+FILE "book part 2.mp3" MP3
+  TRACK 03 AUDIO
+    TITLE "Third"
+    INDEX 00 00:10:00
+"#).unwrap();
+    assert_eq!(sheet.title, Some("The Book".to_owned()));
+    assert_eq!(sheet.performer, Some("Some Author".to_owned()));
+    assert_eq!(sheet.tracks.len(), 3);
+    assert!((sheet.tracks[1].start - 62.2).abs() < 1e-9);
+    assert_eq!(sheet.tracks[2].start, 10.0);
+    assert_eq!(sheet.tracks[2].file, Some("book part 2.mp3".to_owned()));
+    assert_eq!(sheet.chapters_for("book part 1.mp3").len(), 2);
+    assert_eq!(sheet.chapters_for("book part 2.mp3")[0].title, Some("Third".to_owned()));
+    assert!(sheet.chapters_for("other.mp3").is_empty());
+}
+
+#[test]
+fn rejects_invalid_cue_sheets() {
+    use crate::worker::cue::CueSheet;
+    assert!(CueSheet::parse("TRACK one AUDIO").is_err());
+    assert!(CueSheet::parse("TRACK 01 AUDIO\nINDEX 01 1:2").is_err());
+}
+
+#[test]
+fn parses_chapter_text_files() {
+    use crate::worker::chapter_sidecar;
+    let simple = chapter_sidecar::parse_text("# comment\n01:00 Second\n00:00:00.000 First\n1:02:03.5 Third\n").unwrap();
+    let titles: Vec<_> = simple.iter().map(|c| c.title.clone().unwrap()).collect();
+    assert_eq!(titles, vec!["First", "Second", "Third"]);
+    assert_eq!(simple[2].start, 3723.5);
+
+    let ogm = chapter_sidecar::parse_text(
+        "CHAPTER01=00:00:00.000\nCHAPTER01NAME=Intro\nCHAPTER02=00:10:00.000\nCHAPTER02NAME=Outro\n"
+    ).unwrap();
+    assert_eq!(ogm.len(), 2);
+    assert_eq!(ogm[1].title, Some("Outro".to_owned()));
+    assert_eq!(ogm[1].start, 600.0);
+
+    assert!(chapter_sidecar::parse_text("").is_err());
+    assert!(chapter_sidecar::parse_text("Intro at the start").is_err());
+    assert!(chapter_sidecar::parse_text("CHAPTER01NAME=Intro").is_err());
+    assert!(chapter_sidecar::parse_text("CHAPTER01=NaN\nCHAPTER01NAME=Intro").is_err());
+    assert!(chapter_sidecar::parse_text("CHAPTER01=inf\nCHAPTER01NAME=Intro").is_err());
+}
+
+#[test]
+fn parses_chapter_json_files() {
+    use crate::worker::chapter_sidecar;
+    let list = chapter_sidecar::parse_json(
+        r#"[{"title": "Intro", "start": 0}, {"title": "Part One", "start": "00:01:30.250"}]"#
+    ).unwrap();
+    assert_eq!(list.len(), 2);
+    assert_eq!(list[1].start, 90.25);
+
+    let audible = chapter_sidecar::parse_json(
+        r#"{"chapters": [{"title": "Opening Credits", "start_offset_ms": 0, "length_ms": 20000},
+                         {"title": "Chapter 1", "start_offset_ms": 20000, "length_ms": 500000}]}"#
+    ).unwrap();
+    assert_eq!(audible[1].title, Some("Chapter 1".to_owned()));
+    assert_eq!(audible[1].start, 20.0);
+
+    assert!(chapter_sidecar::parse_json(r#"{"title": "No chapters"}"#).is_err());
+    assert!(chapter_sidecar::parse_json(r#"[{"title": "No start"}]"#).is_err());
+    assert!(chapter_sidecar::parse_json(r#"[{"title": "Broken", "start": "NaN"}]"#).is_err());
+    assert!(chapter_sidecar::parse_json(r#"[{"title": "Before", "start_offset_ms": -1000}]"#).is_err());
+}
+
+#[test]
+fn parses_metadata_json() {
+    use crate::worker::metadata_sidecar;
+    let tags = metadata_sidecar::parse_json(r#"{
+        "title": "Abaddon's Gate",
+        "authors": ["James S. A. Corey"],
+        "narrators": ["Jefferson Mays"],
+        "series": ["The Expanse #3"],
+        "genres": ["Science Fiction", "Space Opera"],
+        "publishedYear": "2013",
+        "publisher": null,
+        "asin": "B00CNMY2MU"
+    }"#).unwrap();
+    assert_eq!(tags.title, Some("Abaddon's Gate".to_owned()));
+    assert_eq!(tags.author, Some("James S. A. Corey".to_owned()));
+    assert_eq!(tags.narrator, Some("Jefferson Mays".to_owned()));
+    assert_eq!(tags.series, Some("The Expanse".to_owned()));
+    assert_eq!(tags.series_index, Some(3.0));
+    assert_eq!(tags.genre, Some("Science Fiction;Space Opera".to_owned()));
+    assert_eq!(tags.year, Some(2013));
+    assert_eq!(tags.publisher, None);
+    assert_eq!(tags.asin, Some("B00CNMY2MU".to_owned()));
+
+    let objects = metadata_sidecar::parse_json(
+        r#"{"authors": [{"name": "A"}, {"name": "B"}], "series": [{"name": "S", "sequence": "1.5"}]}"#
+    ).unwrap();
+    assert_eq!(objects.author, Some("A, B".to_owned()));
+    assert_eq!(objects.series, Some("S".to_owned()));
+    assert_eq!(objects.series_index, Some(1.5));
+
+    assert!(metadata_sidecar::parse_json("[]").is_err());
+}
+
+#[test]
+fn parses_opf_files() {
+    use crate::worker::metadata_sidecar;
+    let tags = metadata_sidecar::parse_opf(r#"<?xml version="1.0" encoding="utf-8"?>
+<package xmlns="http://www.idpf.org/2007/opf" version="2.0">
+  <metadata xmlns:dc="http://purl.org/dc/elements/1.1/" xmlns:opf="http://www.idpf.org/2007/opf">
+    <dc:title>Guards! Guards!</dc:title>
+    <dc:creator opf:role="aut" opf:file-as="Pratchett, Terry">Terry Pratchett</dc:creator>
+    <dc:creator opf:role="nrt">Nigel Planer</dc:creator>
+    <dc:description>&lt;p&gt;The city of Ankh-Morpork &amp;amp; its dragon.&lt;/p&gt;</dc:description>
+    <dc:publisher>Isis</dc:publisher>
+    <dc:date>1989-11-01T00:00:00+00:00</dc:date>
+    <dc:language>eng</dc:language>
+    <dc:subject>Fantasy</dc:subject>
+    <dc:subject>Humour</dc:subject>
+    <dc:identifier opf:scheme="ISBN">9780753109489</dc:identifier>
+    <meta name="calibre:series" content="Discworld"/>
+    <meta name="calibre:series_index" content="8.0"/>
+  </metadata>
+</package>"#).unwrap();
+    assert_eq!(tags.title, Some("Guards! Guards!".to_owned()));
+    assert_eq!(tags.author, Some("Terry Pratchett".to_owned()));
+    assert_eq!(tags.narrator, Some("Nigel Planer".to_owned()));
+    assert_eq!(tags.description, Some("The city of Ankh-Morpork & its dragon.".to_owned()));
+    assert_eq!(tags.publisher, Some("Isis".to_owned()));
+    assert_eq!(tags.year, Some(1989));
+    assert_eq!(tags.language, Some("eng".to_owned()));
+    assert_eq!(tags.genre, Some("Fantasy;Humour".to_owned()));
+    assert_eq!(tags.isbn, Some("9780753109489".to_owned()));
+    assert_eq!(tags.series, Some("Discworld".to_owned()));
+    assert_eq!(tags.series_index, Some(8.0));
+
+    assert!(metadata_sidecar::parse_opf("<html></html>").is_err());
+}
+
+#[test]
+fn parses_nfo_files() {
+    use crate::worker::metadata_sidecar;
+    let tags = metadata_sidecar::parse_nfo("
+        ==== General Information ====
+        Title...................: The Colour of Magic
+        Author..................: Terry Pratchett
+        Read By.................: Tony Robinson
+        Release Date............: 05/1995
+        Series..................: Discworld, Book 1
+        Source: http://example.com
+    ");
+    assert_eq!(tags.title, Some("The Colour of Magic".to_owned()));
+    assert_eq!(tags.author, Some("Terry Pratchett".to_owned()));
+    assert_eq!(tags.narrator, Some("Tony Robinson".to_owned()));
+    assert_eq!(tags.year, Some(1995));
+    assert_eq!(tags.series, Some("Discworld".to_owned()));
+    assert_eq!(tags.series_index, Some(1.0));
+    assert_eq!(tags.description, None);
+}
+
+#[test]
+fn merges_metadata_sidecars_over_tags() {
+    use crate::worker::metadata_sidecar;
+    use crate::worker::tags::BookTags;
+    let mut tags = BookTags {
+        title: Some("tag title".to_owned()),
+        author: Some("tag author".to_owned()),
+        series: Some("tag series".to_owned()),
+        series_index: Some(4.0),
+        ..BookTags::default()
+    };
+    metadata_sidecar::merge(&mut tags, BookTags {
+        title: Some("sidecar title".to_owned()),
+        series: Some("sidecar series".to_owned()),
+        ..BookTags::default()
+    });
+    assert_eq!(tags.title, Some("sidecar title".to_owned()));
+    assert_eq!(tags.author, Some("tag author".to_owned()));
+    assert_eq!(tags.series, Some("sidecar series".to_owned()));
+    // the index belonged to the series of the tags
+    assert_eq!(tags.series_index, None);
+}
+
+#[test]
+fn text_track_chapters_match_chapter_track() {
+    util::shut_up_ffmpeg();
+    // itunes.m4b is all.m4b with the chapter reference of its text track removed
+    let referenced = MediaFile::read_file(Path::new("test-data/all.m4b")).unwrap().get_chapters();
+    let text_track = MediaFile::read_file(Path::new("test-data/itunes.m4b")).unwrap().get_chapters();
+    assert_eq!(referenced.len(), text_track.len());
+    for (a, b) in referenced.iter().zip(text_track.iter()) {
+        assert_eq!(a.title, b.title);
+        assert!((a.start - b.start).abs() < 0.01);
+    }
+}
+
+#[test]
+fn maps_itunes_tags() {
+    use crate::worker::tags::BookTags;
+    util::shut_up_ffmpeg();
+    let info = MediaFile::read_file(Path::new("test-data/itunes.m4b")).unwrap().get_mediainfo();
+    let tags = BookTags::from_metadata(&info.metadata);
+    assert_eq!(tags.title, Some("[Bulgarian]Stihotvorenia".to_owned()));
+    assert_eq!(tags.album, Some("Stihotvorenia".to_owned()));
+    assert_eq!(tags.author, Some("Mara Belcheva".to_owned()));
+    assert_eq!(tags.narrator, Some("Gert Westphal".to_owned()));
+    assert_eq!(tags.description, Some("A collection of poems by Mara Belcheva, read aloud in full.".to_owned()));
+    assert_eq!(tags.year, Some(1929));
+    assert_eq!(tags.genre, Some("Audiobook".to_owned()));
+}
+
+#[test]
+fn normalizes_tag_keys() {
+    use crate::worker::tags::BookTags;
+    use std::collections::HashMap;
+    let mut metadata = HashMap::new();
+    metadata.insert("ARTIST".to_owned(), " ".to_owned());
+    metadata.insert("album_artist".to_owned(), "Someone".to_owned());
+    metadata.insert("NARRATOR".to_owned(), "Reader".to_owned());
+    metadata.insert("composer".to_owned(), "Composer".to_owned());
+    metadata.insert("date".to_owned(), "2004-05-01".to_owned());
+    let tags = BookTags::from_metadata(&metadata);
+    assert_eq!(tags.author, Some("Someone".to_owned()));
+    assert_eq!(tags.narrator, Some("Reader".to_owned()));
+    assert_eq!(tags.year, Some(2004));
+    assert_eq!(tags.title, None);
+}
+
+#[test]
+fn parses_series_from_names() {
+    use crate::worker::tags::SeriesName;
+    let discworld = SeriesName::parse("Discworld 01 - The Colour of Magic").unwrap();
+    assert_eq!(discworld.series, "Discworld");
+    assert_eq!(discworld.index, 1.0);
+    assert_eq!(discworld.title, "The Colour of Magic");
+    let expanse = SeriesName::parse("The Expanse - Book 3.5 - The Churn").unwrap();
+    assert_eq!(expanse.series, "The Expanse");
+    assert_eq!(expanse.index, 3.5);
+    assert_eq!(expanse.title, "The Churn");
+    assert_eq!(SeriesName::parse("01 - Intro"), None);
+    assert_eq!(SeriesName::parse("Der Zauberberg"), None);
+}
+
+#[test]
+fn splits_genre_tags() {
+    use crate::models::audiobook::Genres;
+    assert_eq!(Genres::from_tag("Fantasy; Science Fiction/fantasy, Humor").0,
+               vec!["Fantasy", "Science Fiction", "Humor"]);
+    assert!(Genres::from_tag(" ; ").0.is_empty());
+}
+
+#[test]
+fn finds_silences() {
+    use crate::worker::silence;
+    util::shut_up_ffmpeg();
+    let path = PathBuf::from("test-data/4.mp3");
+    let length = MediaFile::read_file(&path).unwrap().get_mediainfo().length;
+    // nothing is louder than +6 dB, so the whole file is one long silence
+    let everything = silence::find_silences(&[path.clone(), path], 6.0, 1.0).unwrap();
+    assert_eq!(everything.len(), 2);
+    assert_eq!(everything[0].start, 0.0);
+    assert!((everything[0].end - length).abs() < 1.0);
+    assert!((everything[1].start - length).abs() < 1e-9);
+}
+
+#[test]
+fn generates_chapters_at_silences() {
+    use crate::worker::silence::{self, Silence};
+    let silences = vec![
+        Silence { start: 50.0, end: 52.0 },
+        Silence { start: 95.0, end: 97.0 },
+        Silence { start: 108.0, end: 110.0 },
+        Silence { start: 400.0, end: 402.0 },
+    ];
+    let starts: Vec<f64> = silence::chapters_at_silences(&silences, 500.0, 100.0)
+        .into_iter().map(|c| c.start).collect();
+    // 96 and 401 are close to their targets, 200 and 300 have no silence nearby
+    assert_eq!(starts, vec![0.0, 96.0, 196.0, 296.0, 401.0]);
+    let short = silence::chapters_at_silences(&silences, 120.0, 100.0);
+    assert_eq!(short.len(), 1);
+    assert_eq!(short[0].title, Some("Chapter 1".to_owned()));
+}
+
+#[test]
+fn extracts_cover_palettes() {
+    use crate::worker::palette;
+    use image::{DynamicImage, ImageOutputFormat, Rgb, RgbImage};
+    let image = DynamicImage::ImageRgb8(RgbImage::from_pixel(16, 16, Rgb { data: [200, 30, 30] }));
+    let mut png = Vec::new();
+    image.write_to(&mut png, ImageOutputFormat::PNG).unwrap();
+    let palette = palette::extract(&png).unwrap();
+    assert_eq!(palette.dominant, "#c81e1e");
+    assert_eq!(palette.vibrant, "#c81e1e");
+    // nothing muted about a single bright colour
+    assert_eq!(palette.muted, "#c81e1e");
+    assert_eq!(palette.blurhash, "LBM^z||wfQ|w|wo1fQo1fQfQfQfQ");
+
+    assert!(palette::extract(b"not an image").is_err());
+}
+
+#[test]
+fn finds_cover_images() {
+    use crate::worker::cover;
+    let book = Path::new("integration-tests/cover_images/book");
+    assert_eq!(cover::find_for(book), Some(book.join("cover.png")));
+    let single = Path::new("integration-tests/cover_images/single.mp3");
+    assert_eq!(cover::find_for(single), Some(PathBuf::from("integration-tests/cover_images/single.png")));
+    assert_eq!(cover::find_for(Path::new("test-data/1.mp3")), None);
+
+    let image = cover::read_image(&book.join("cover.png")).unwrap();
+    assert_eq!(image.image_type, ImageType::PNG);
+    assert!(cover::read_image(Path::new("test-data/4.cue")).is_err());
+}
+
 #[test]
 fn get_thumbnail_jpg() {
     let j = MediaFile::read_file(Path::new("test-data/1.mp3")).unwrap();
@@ -198,6 +726,115 @@ fn checksum_dir() {
     checksum.unwrap();
 }
 
+#[test]
+fn cache_keys() {
+    use super::hashing;
+    // cached files are named by these, they must not change between releases
+    assert_eq!(hashing::cache_key(&[&b"a"[..], &b"bc"[..]]), "3fafa1cf2f19a7c1");
+    assert_ne!(hashing::cache_key(&[&b"ab"[..], &b"c"[..]]), hashing::cache_key(&[&b"a"[..], &b"bc"[..]]));
+}
+
+#[test]
+fn hls_segments() {
+    use crate::models::chapter::Chapter;
+    use crate::worker::hls;
+    let book_id = Uuid::new_v4();
+    let chapters: Vec<Chapter> = vec![(0.0, "Intro"), (4.0, "Main")].into_iter().enumerate().map(|(i, (start, title))| {
+        Chapter {
+            id: Uuid::new_v4(),
+            title: Some(title.to_owned()),
+            audiobook_id: book_id,
+            start_time: start,
+            number: i as i64,
+            generated: false,
+        }
+    }).collect();
+    let segments = hls::segments(&chapters, 25.0);
+    let bounds: Vec<(f64, f64)> = segments.iter().map(|s| (s.start, s.end)).collect();
+    assert_eq!(bounds, vec![(0.0, 4.0), (4.0, 11.0), (11.0, 18.0), (18.0, 25.0)]);
+    assert_eq!(segments[1].title, Some("Main".to_owned()));
+
+    let playlist = hls::playlist(&segments, "?auth=token");
+    assert!(playlist.starts_with("#EXTM3U\n"));
+    assert!(playlist.contains("#EXT-X-TARGETDURATION:7\n"));
+    assert!(playlist.contains("#EXTINF:4.000,Intro\n0.ts?auth=token\n"));
+    assert!(playlist.ends_with("#EXT-X-ENDLIST\n"));
+}
+
+#[test]
+fn remux_slices() {
+    let mut first = get_tempdir();
+    first.push("slice_0.ts");
+    let mut second = get_tempdir();
+    second.push("slice_1.ts");
+    muxer::remux_slice(&first, Path::new("test-data/all.m4b"), 0.0, 1.0).unwrap();
+    muxer::remux_slice(&second, Path::new("test-data/all.m4b"), 1.0, 2.0).unwrap();
+    for slice in &[first, second] {
+        let length = MediaFile::read_file(slice).unwrap().get_mediainfo().length;
+        assert!((length - 1.0).abs() < 0.1, "slice is {} seconds long", length);
+    }
+}
+
+#[test]
+fn replaces_stale_segments() {
+    use crate::worker::hls::{self, Segment};
+    let mut cache = get_tempdir();
+    cache.push("stale_segments");
+    let _ = std::fs::remove_dir_all(&cache);
+    create_dir_all(&cache).unwrap();
+    // cut before a chapter moved from 4 to 3 seconds
+    std::fs::write(cache.join("0-4000.ts"), b"").unwrap();
+    std::fs::write(cache.join("4000-11000.ts"), b"").unwrap();
+    let segment = Segment { start: 0.0, end: 3.0, title: None };
+    let path = hls::segment_file(Path::new("test-data/all.m4b"), &cache, &segment).unwrap();
+    assert_eq!(path, cache.join("0-3000.ts"));
+    assert!(path.exists());
+    assert!(!cache.join("0-4000.ts").exists());
+    assert!(cache.join("4000-11000.ts").exists());
+}
+
+#[test]
+fn virtual_files() {
+    use std::io::Read;
+    use crate::worker::virtual_file::{self, VirtualFile};
+    let files = vec![PathBuf::from("test-data/all/1.mp3"), PathBuf::from("test-data/all/2.mp3")];
+    let parts = VirtualFile::for_files(&files).unwrap();
+    // the tag of the second file is left out
+    let second = parts.parts.iter().find(|p| p.path == files[1]).unwrap();
+    assert!(second.start > 0);
+
+    let mut all = Vec::new();
+    parts.reader(0).read_to_end(&mut all).unwrap();
+    assert_eq!(all.len() as u64, parts.len());
+    let mut rest = Vec::new();
+    parts.reader(parts.len() - 1000).read_to_end(&mut rest).unwrap();
+    assert_eq!(&rest[..], &all[all.len() - 1000..]);
+
+    let mut manifest = get_tempdir();
+    manifest.push("book.mp3.parts");
+    parts.write(&manifest).unwrap();
+    assert!(virtual_file::is_manifest(&manifest));
+    assert_eq!(VirtualFile::read(&manifest).unwrap(), parts);
+    let length = MediaFile::read_file(&manifest).unwrap().get_mediainfo().length;
+    let expected: f64 = files.iter().map(|f| MediaFile::read_file(f).unwrap().get_mediainfo().length).sum();
+    assert!((length - expected).abs() < 1.0, "stream is {} seconds long", length);
+}
+
+#[test]
+fn detects_vbr() {
+    use crate::helpers::mllt::is_vbr;
+    assert!(is_vbr("test-data/vbr.mp3").unwrap());
+    assert!(!is_vbr("test-data/1.mp3").unwrap());
+    assert!(!is_vbr("test-data/no_metadata.mp3").unwrap());
+    // padding before the first frame is skipped
+    let mut padded = get_tempdir();
+    padded.push("padded_vbr.mp3");
+    let mut bytes = vec![0; 1000];
+    bytes.extend(std::fs::read("test-data/vbr.mp3").unwrap());
+    std::fs::write(&padded, bytes).unwrap();
+    assert!(is_vbr(&padded).unwrap());
+}
+
 fn assert_slice_starts_with(bytes: &[u8], start: &[u8]) {
     let mut i = bytes.iter();
     for b in start {
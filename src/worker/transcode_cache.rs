@@ -0,0 +1,219 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::config::{TranscodeProfile, TranscodingConfig};
+use crate::helpers::uuid::Uuid;
+use crate::models::audiobook::Audiobook;
+use crate::models::chapter::Chapter;
+use crate::worker::error::Result;
+use crate::worker::hashing;
+use crate::worker::mediafile::{Image, MediaFile};
+use crate::worker::muxer::OutputMetadata;
+use crate::worker::transcoder;
+
+/// Formats books can be transcoded to for listening over slow connections.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StreamFormat {
+    Opus,
+    Aac,
+    Mp3,
+}
+
+impl StreamFormat {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_lowercase().as_str() {
+            "opus" => Some(StreamFormat::Opus),
+            "aac" => Some(StreamFormat::Aac),
+            "mp3" => Some(StreamFormat::Mp3),
+            _ => None
+        }
+    }
+
+    /// Bitrate in kbit/s used when the client doesn't ask for one, good enough for speech.
+    pub fn default_bitrate(self) -> u32 {
+        match self {
+            StreamFormat::Opus => 32,
+            StreamFormat::Aac | StreamFormat::Mp3 => 64,
+        }
+    }
+
+    pub fn profile(self, bitrate: u32) -> TranscodeProfile {
+        let (extension, codec) = match self {
+            StreamFormat::Opus => ("opus", "libopus"),
+            StreamFormat::Aac => ("m4b", "aac"),
+            StreamFormat::Mp3 => ("mp3", "libmp3lame"),
+        };
+        TranscodeProfile {
+            extension: extension.to_owned(),
+            codec: codec.to_owned(),
+            bitrate: i64::from(bitrate) * 1000,
+        }
+    }
+}
+
+/// State of a transcode that is not in the cache yet.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Transcode {
+    Ready(PathBuf),
+    Running,
+    Failed(String),
+    /// Too many transcodes are waiting already.
+    Busy,
+}
+
+/// How long a failed transcode is reported before it is tried again.
+const RETRY_AFTER: Duration = Duration::from_secs(300);
+
+lazy_static! {
+    static ref JOBS: Mutex<Jobs> = Mutex::new(Jobs::default());
+}
+
+#[derive(Default)]
+struct Jobs {
+    /// Transcodes waiting for a worker, oldest first.
+    queue: VecDeque<Job>,
+    /// Destinations of the waiting and running transcodes.
+    pending: HashSet<PathBuf>,
+    /// Destinations of failed transcodes with the error and when it happened.
+    failed: HashMap<PathBuf, (String, Instant)>,
+    /// Number of threads working off the queue.
+    workers: usize,
+}
+
+struct Job {
+    book: Audiobook,
+    chapters: Vec<Chapter>,
+    cover: Option<Image>,
+    profile: TranscodeProfile,
+    source: PathBuf,
+    dest: PathBuf,
+}
+
+/// Where the transcode of a book is cached. It is named after the hash of the book rather than
+/// its id, so a changed book gets a new transcode and a rescanned one keeps its transcodes. The
+/// hash of the tags, chapters and cover written into it makes edits of the book a new transcode
+/// as well.
+fn cached_path(data_directory: &str, book: &Audiobook, metadata: &OutputMetadata, profile: &TranscodeProfile) -> PathBuf {
+    let mut tags: Vec<String> = metadata.tags.iter().map(|(k, v)| format!("{:?}={:?}", k, v)).collect();
+    tags.sort();
+    let chapters: Vec<String> = metadata.chapters.iter()
+        .map(|c| format!("{}:{:?}", c.start_time.to_bits(), c.title))
+        .collect();
+    let mut values: Vec<&[u8]> = tags.iter().chain(&chapters).map(|v| v.as_bytes()).collect();
+    values.push(metadata.cover.map_or(&[][..], |c| &c.data[..]));
+    let mut path = PathBuf::from(data_directory);
+    path.push("transcodes");
+    path.push(format!("{}-{}{}", hex(&book.hash), hashing::cache_key(&values), profile_suffix(profile)));
+    path
+}
+
+/// The end of the names of all transcodes made with a profile.
+fn profile_suffix(profile: &TranscodeProfile) -> String {
+    format!("-{}-{}.{}", profile.codec, profile.bitrate / 1000, profile.extension)
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// The transcode of a book if it is cached. Otherwise a transcode is queued, it carries the tags,
+/// chapters and cover of the book just like the data file. `config.workers` transcodes run at a
+/// time and at most `config.queue` wait or run, further books are `Busy`.
+pub fn transcode(config: &TranscodingConfig, data_directory: &str, book: Audiobook, chapters: Vec<Chapter>,
+                 cover: Option<Image>, profile: TranscodeProfile) -> Transcode {
+    let dest = {
+        let metadata = OutputMetadata::for_book(&book, &chapters, book.length, cover.as_ref());
+        cached_path(data_directory, &book, &metadata, &profile)
+    };
+    if dest.is_file() {
+        return Transcode::Ready(dest);
+    }
+    let mut jobs = JOBS.lock().unwrap();
+    if jobs.pending.contains(&dest) {
+        return Transcode::Running;
+    }
+    if let Some((message, failed_at)) = jobs.failed.get(&dest).cloned() {
+        if failed_at.elapsed() < RETRY_AFTER {
+            return Transcode::Failed(message);
+        }
+        jobs.failed.remove(&dest);
+    }
+    if jobs.pending.len() >= config.queue.max(1) {
+        return Transcode::Busy;
+    }
+
+    let source = book.data_file(data_directory);
+    jobs.pending.insert(dest.clone());
+    jobs.queue.push_back(Job { book, chapters, cover, profile, source, dest });
+    if jobs.workers < config.workers.max(1) {
+        jobs.workers += 1;
+        thread::spawn(work_off_queue);
+    }
+    Transcode::Running
+}
+
+/// Run queued transcodes until the queue is empty.
+fn work_off_queue() {
+    loop {
+        let job = {
+            let mut jobs = JOBS.lock().unwrap();
+            match jobs.queue.pop_front() {
+                Some(job) => job,
+                None => {
+                    jobs.workers -= 1;
+                    return;
+                }
+            }
+        };
+        info!("Transcoding {} to {:?}", job.book.id.hyphenated(), job.dest);
+        let metadata = OutputMetadata::for_book(&job.book, &job.chapters, job.book.length, job.cover.as_ref());
+        let result = transcode_into(&job.source, &job.dest, &job.profile, &metadata);
+        if result.is_ok() {
+            remove_stale_versions(&job.dest, &job.book, &job.profile);
+        }
+        let mut jobs = JOBS.lock().unwrap();
+        jobs.pending.remove(&job.dest);
+        if let Err(e) = result {
+            warn!("Could not transcode {} to {:?}: {}", job.book.id.hyphenated(), job.dest, e);
+            jobs.failed.insert(job.dest, (e.to_string(), Instant::now()));
+        }
+    }
+}
+
+/// Remove transcodes of the book with the same profile made before it was edited.
+fn remove_stale_versions(dest: &Path, book: &Audiobook, profile: &TranscodeProfile) {
+    let directory = match dest.parent() {
+        Some(d) => d,
+        None => return
+    };
+    let prefix = format!("{}-", hex(&book.hash));
+    let suffix = profile_suffix(profile);
+    let entries = match fs::read_dir(directory) {
+        Ok(entries) => entries,
+        Err(_) => return
+    };
+    for path in entries.filter_map(|e| e.ok()).map(|e| e.path()) {
+        let name = path.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default();
+        if path != dest && name.starts_with(&prefix) && name.ends_with(&suffix) {
+            if let Err(e) = fs::remove_file(&path) {
+                warn!("Could not remove stale transcode {:?}: {}", path, e);
+            }
+        }
+    }
+}
+
+/// Transcode into a temporary file first, so nobody ever reads a half written transcode.
+fn transcode_into(source: &Path, dest: &Path, profile: &TranscodeProfile, metadata: &OutputMetadata) -> Result<()> {
+    if let Some(dir) = dest.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let temporary = dest.with_extension(format!("{}.{}", Uuid::new_v4().hyphenated(), profile.extension));
+    let input = MediaFile::read_file(source)?;
+    transcoder::transcode_files(&temporary, &[input], profile, metadata)?;
+    fs::rename(&temporary, dest)?;
+    Ok(())
+}
@@ -0,0 +1,344 @@
+use crate::ffmpeg::*;
+use crate::ffmpeg::AVMediaType::AVMEDIA_TYPE_AUDIO;
+
+use std::cmp;
+use std::ffi::CString;
+use std::mem;
+use std::ptr;
+use std::os::raw::{c_int, c_void};
+use std::path::Path;
+use libc;
+
+use super::mediafile::MediaFile;
+use super::muxer::{self, NewMediaFile, OutputMetadata};
+use super::util::*;
+use crate::config::TranscodeProfile;
+use crate::worker::error::*;
+
+const AVERROR_EAGAIN: c_int = -libc::EAGAIN;
+
+/// Decodes the best audio stream of a single input file.
+pub(super) struct Decoder {
+    pub(super) ctx: *mut AVCodecContext,
+    stream_index: c_int,
+}
+
+impl Decoder {
+    pub(super) fn open(file: &MediaFile) -> Result<Self> {
+        let stream = file.get_best_stream(AVMEDIA_TYPE_AUDIO)?;
+        unsafe {
+            let codec = match ptr_to_opt(avcodec_find_decoder((*stream.codecpar).codec_id)) {
+                Some(c) => c,
+                None => return Err(WorkerError::Other {
+                    description: format!("No decoder for {:?}", file.path)
+                }.into())
+            };
+            let ctx = avcodec_alloc_context3(codec);
+            let decoder = Self { ctx, stream_index: stream.index };
+            check_av_result(avcodec_parameters_to_context(ctx, stream.codecpar))?;
+            (*ctx).pkt_timebase = stream.time_base;
+            check_av_result(avcodec_open2(ctx, codec, ptr::null_mut()))?;
+            if (*ctx).channel_layout == 0 {
+                (*ctx).channel_layout = av_get_default_channel_layout((*ctx).channels) as u64;
+            }
+            Ok(decoder)
+        }
+    }
+}
+
+impl Drop for Decoder {
+    fn drop(&mut self) {
+        unsafe {
+            avcodec_free_context(&mut self.ctx);
+        }
+    }
+}
+
+/// Encodes raw samples for the output file, buffering them in a fifo so the encoder always gets
+/// full frames.
+struct Encoder {
+    ctx: *mut AVCodecContext,
+    fifo: *mut AVAudioFifo,
+    samples_written: i64,
+}
+
+impl Encoder {
+    fn open(profile: &TranscodeProfile, path: &Path, first_input: &Decoder) -> Result<Self> {
+        let codec_name = CString::new(profile.codec.as_str()).expect("Null byte in codec name.");
+        unsafe {
+            let codec = match ptr_to_opt(avcodec_find_encoder_by_name(codec_name.as_ptr())) {
+                Some(c) => c,
+                None => return Err(WorkerError::Other {
+                    description: format!("No encoder named {}", profile.codec)
+                }.into())
+            };
+            if (*codec).sample_fmts.is_null() {
+                return Err(WorkerError::Other {
+                    description: format!("The encoder {} doesn't list its sample formats", profile.codec)
+                }.into());
+            }
+            let ctx = avcodec_alloc_context3(codec);
+            let channels = cmp::min((*first_input.ctx).channels, 2);
+            (*ctx).channels = channels;
+            (*ctx).channel_layout = av_get_default_channel_layout(channels) as u64;
+            (*ctx).sample_rate = choose_sample_rate(codec, (*first_input.ctx).sample_rate);
+            (*ctx).sample_fmt = *(*codec).sample_fmts;
+            (*ctx).bit_rate = profile.bitrate;
+            (*ctx).time_base = AVRational { num: 1, den: (*ctx).sample_rate };
+            let format = muxer::guess_output_format(path)?;
+            if (*format).flags & AVFMT_GLOBALHEADER as c_int != 0 {
+                (*ctx).flags |= AV_CODEC_FLAG_GLOBAL_HEADER as c_int;
+            }
+            let encoder = Self {
+                ctx,
+                fifo: av_audio_fifo_alloc((*ctx).sample_fmt, channels, 1),
+                samples_written: 0,
+            };
+            if encoder.fifo.is_null() {
+                return Err(WorkerError::Other {
+                    description: "Can't allocate the sample buffer of the encoder".to_owned()
+                }.into());
+            }
+            check_av_result(avcodec_open2(ctx, codec, ptr::null_mut()))?;
+            Ok(encoder)
+        }
+    }
+
+    fn frame_size(&self) -> c_int {
+        unsafe {
+            match (*self.ctx).frame_size {
+                0 => 1024,
+                size => size
+            }
+        }
+    }
+
+    /// Queue converted samples, encoding full frames as soon as they are available.
+    fn push(&mut self, frame: *mut AVFrame, out: &mut NewMediaFile) -> Result<()> {
+        unsafe {
+            check_av_result(av_audio_fifo_write(
+                self.fifo,
+                (*frame).extended_data as *mut *mut c_void,
+                (*frame).nb_samples
+            ))?;
+        }
+        while unsafe { av_audio_fifo_size(self.fifo) } >= self.frame_size() {
+            let frame_size = self.frame_size();
+            self.encode_from_fifo(frame_size, out)?;
+        }
+        Ok(())
+    }
+
+    fn encode_from_fifo(&mut self, samples: c_int, out: &mut NewMediaFile) -> Result<()> {
+        unsafe {
+            let mut frame = av_frame_alloc();
+            (*frame).nb_samples = samples;
+            (*frame).channel_layout = (*self.ctx).channel_layout;
+            (*frame).format = (*self.ctx).sample_fmt as c_int;
+            (*frame).sample_rate = (*self.ctx).sample_rate;
+            let result = check_av_result(av_frame_get_buffer(frame, 0))
+                .and_then(|_| check_av_result(av_audio_fifo_read(
+                    self.fifo, (*frame).extended_data as *mut *mut c_void, samples
+                )))
+                .and_then(|_| {
+                    (*frame).pts = self.samples_written;
+                    self.samples_written += i64::from(samples);
+                    self.encode(frame, out)
+                });
+            av_frame_free(&mut frame);
+            result
+        }
+    }
+
+    /// Send a frame to the encoder and write all resulting packets, a null frame flushes.
+    fn encode(&mut self, frame: *mut AVFrame, out: &mut NewMediaFile) -> Result<()> {
+        unsafe {
+            check_av_result(avcodec_send_frame(self.ctx, frame))?;
+            let mut pkt = av_packet_alloc();
+            let result = loop {
+                match avcodec_receive_packet(self.ctx, pkt) {
+                    AVERROR_EAGAIN | AVERROR_EOF => break Ok(()),
+                    code if code < 0 => break Err(new_media_error(code).into()),
+                    _ => {
+                        av_packet_rescale_ts(pkt, (*self.ctx).time_base, out.audio_time_base());
+                        let written = out.write_frame(&mut *pkt);
+                        av_packet_unref(pkt);
+                        if let Err(e) = written {
+                            break Err(e);
+                        }
+                    }
+                }
+            };
+            av_packet_free(&mut pkt);
+            result
+        }
+    }
+
+    /// Encode whatever is left in the fifo and drain the encoder.
+    fn finish(&mut self, out: &mut NewMediaFile) -> Result<()> {
+        let remaining = unsafe { av_audio_fifo_size(self.fifo) };
+        if remaining > 0 {
+            self.encode_from_fifo(remaining, out)?;
+        }
+        self.encode(ptr::null_mut(), out)
+    }
+}
+
+impl Drop for Encoder {
+    fn drop(&mut self) {
+        unsafe {
+            if !self.fifo.is_null() {
+                av_audio_fifo_free(self.fifo);
+            }
+            avcodec_free_context(&mut self.ctx);
+        }
+    }
+}
+
+/// Pick the input sample rate if the encoder supports it, otherwise the closest supported one.
+unsafe fn choose_sample_rate(codec: *const AVCodec, preferred: c_int) -> c_int {
+    let mut supported = (*codec).supported_samplerates;
+    if supported.is_null() {
+        return preferred;
+    }
+    let mut best = *supported;
+    while *supported != 0 {
+        if (*supported - preferred).abs() < (best - preferred).abs() {
+            best = *supported;
+        }
+        supported = supported.offset(1);
+    }
+    best
+}
+
+/// Convert the samples of one decoded frame to the encoder's format and queue them.
+fn resample(swr: *mut SwrContext, frame: *mut AVFrame, encoder: &mut Encoder, out: &mut NewMediaFile) -> Result<()> {
+    unsafe {
+        let mut converted = av_frame_alloc();
+        (*converted).channel_layout = (*encoder.ctx).channel_layout;
+        (*converted).sample_rate = (*encoder.ctx).sample_rate;
+        (*converted).format = (*encoder.ctx).sample_fmt as c_int;
+        let result = check_av_result(swr_convert_frame(swr, converted, frame))
+            .and_then(|_| {
+                if (*converted).nb_samples > 0 {
+                    encoder.push(converted, out)
+                } else {
+                    Ok(())
+                }
+            });
+        av_frame_free(&mut converted);
+        result
+    }
+}
+
+/// Decode all audio of `file`, handing each frame to `on_frame`.
+pub(super) fn decode_frames<F>(file: &MediaFile, decoder: &Decoder, mut on_frame: F) -> Result<()>
+    where F: FnMut(*mut AVFrame) -> Result<()> {
+    unsafe {
+        let mut frame = av_frame_alloc();
+        let result = (|| -> Result<()> {
+            let mut flushing = false;
+            loop {
+                if !flushing {
+                    match file.read_packet()? {
+                        Some(mut pkt) => {
+                            let sent = if pkt.stream_index == decoder.stream_index {
+                                check_av_result(avcodec_send_packet(decoder.ctx, &pkt))
+                            } else {
+                                Ok(0)
+                            };
+                            av_packet_unref(&mut pkt);
+                            sent?;
+                        },
+                        None => {
+                            check_av_result(avcodec_send_packet(decoder.ctx, ptr::null()))?;
+                            flushing = true;
+                        }
+                    }
+                }
+                loop {
+                    match avcodec_receive_frame(decoder.ctx, frame) {
+                        AVERROR_EAGAIN => break,
+                        AVERROR_EOF => return Ok(()),
+                        code if code < 0 => return Err(new_media_error(code).into()),
+                        _ => {
+                            if (*frame).channel_layout == 0 {
+                                (*frame).channel_layout = (*decoder.ctx).channel_layout;
+                            }
+                            let handled = on_frame(frame);
+                            av_frame_unref(frame);
+                            handled?;
+                        }
+                    }
+                }
+            }
+        })();
+        av_frame_free(&mut frame);
+        result
+    }
+}
+
+/// Decode a single input file, feeding all its samples to the encoder.
+fn transcode_file(file: &MediaFile, encoder: &mut Encoder, out: &mut NewMediaFile) -> Result<()> {
+    info!("transcoding file {:?}", file.path);
+    let decoder = Decoder::open(file)?;
+    unsafe {
+        let mut swr = swr_alloc_set_opts(
+            ptr::null_mut(),
+            (*encoder.ctx).channel_layout as i64,
+            (*encoder.ctx).sample_fmt,
+            (*encoder.ctx).sample_rate,
+            (*decoder.ctx).channel_layout as i64,
+            (*decoder.ctx).sample_fmt,
+            (*decoder.ctx).sample_rate,
+            0,
+            ptr::null_mut()
+        );
+        let result = check_av_result(swr_init(swr))
+            .and_then(|_| decode_frames(file, &decoder, |frame| resample(swr, frame, encoder, out)))
+            // push out whatever the resampler still buffers
+            .and_then(|_| resample(swr, ptr::null_mut(), encoder, out));
+        swr_free(&mut swr);
+        result
+    }
+}
+
+/// Transcode several files, possibly of differing formats, into a single output file.
+/// This is the slow path for multifile books that can't simply be remuxed.
+pub fn transcode_files(path: &dyn AsRef<Path>, in_files: &[MediaFile], profile: &TranscodeProfile,
+                       metadata: &OutputMetadata) -> Result<NewMediaFile> {
+    let steps = || -> Result<NewMediaFile> {
+        let first = match in_files.first() {
+            Some(f) => Decoder::open(f)?,
+            None => return Err(WorkerError::Other{
+                description: "No Mediafiles".to_owned()
+            }.into())
+        };
+        let mut encoder = Encoder::open(profile, path.as_ref(), &first)?;
+        let mut out = unsafe {
+            let mut params = avcodec_parameters_alloc();
+            let created = check_av_result(avcodec_parameters_from_context(params, encoder.ctx))
+                .and_then(|_| NewMediaFile::new(path.as_ref(), &mut *params, (*encoder.ctx).time_base));
+            avcodec_parameters_free(&mut params);
+            created?
+        };
+        out.write_header_with_metadata(metadata)?;
+
+        for f in in_files {
+            transcode_file(f, &mut encoder, &mut out)?;
+        }
+        encoder.finish(&mut out)?;
+
+        debug!("writing trailer");
+        out.write_trailer()?;
+        Ok(out)
+    };
+
+    match steps() {
+        Ok(out) => Ok(out),
+        Err(error) => {
+            muxer::remove_unfinished(path);
+            Err(error)
+        },
+    }
+}
@@ -39,8 +39,9 @@ pub(super) fn dict_to_map(dict_pointer: *mut Dictionary) -> HashMap<String, Stri
         let dict: &Dictionary = &mut *dict_pointer;
         let v = av_dict_vec(dict);
         for i in v.iter() {
-            let key = CStr::from_ptr((*i).key).to_str().unwrap().to_owned();
-            let value = CStr::from_ptr((*i).value).to_str().unwrap().to_owned();
+            // unknown iTunes atoms keep their Latin-1 copyright sign, which isn't valid UTF-8
+            let key = CStr::from_ptr((*i).key).to_string_lossy().into_owned();
+            let value = CStr::from_ptr((*i).value).to_string_lossy().into_owned();
             map.insert(
                 key,
                 value
@@ -0,0 +1,177 @@
+use std::fs::{self, File};
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+use serde_json;
+
+use crate::helpers::mllt::{read_at, id3v2_len, FrameHeader};
+use crate::worker::error::{Result, WorkerError};
+
+/// Extension of the manifest that takes the place of a data file, e.g. `<id>.mp3.parts`.
+pub const EXTENSION: &str = "parts";
+
+/// Formats whose streams stay playable when the files are simply put one after another.
+const CONCATENABLE: [&str; 2] = ["mp3", "aac"];
+
+/// A range of bytes of a file in the library, `end` is exclusive.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Part {
+    pub path: PathBuf,
+    pub start: u64,
+    pub end: u64,
+}
+
+impl Part {
+    pub fn len(&self) -> u64 {
+        self.end - self.start
+    }
+}
+
+/// The files of a multifile book read as one stream without writing a merged copy.
+///
+/// Only the audio of the files is used: the tags at the start of every file but the first and
+/// at the end of every file are left out, as are the Xing/VBRI frames of MP3 files since they
+/// describe a single file and would make players believe the book ends after it.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct VirtualFile {
+    pub parts: Vec<Part>,
+}
+
+/// Whether a book in this format can be served from its files without remuxing.
+pub fn can_concatenate(extension: &str) -> bool {
+    CONCATENABLE.contains(&extension.to_lowercase().as_str())
+}
+
+/// Path of the manifest standing in for a data file.
+pub fn manifest_path(data_file: &Path) -> PathBuf {
+    let mut name = data_file.as_os_str().to_owned();
+    name.push(".");
+    name.push(EXTENSION);
+    PathBuf::from(name)
+}
+
+pub fn is_manifest(path: &Path) -> bool {
+    path.extension().map_or(false, |ext| ext == EXTENSION)
+}
+
+/// Open a file for reading along with its size, manifests are opened as their stream.
+pub fn open_stream(path: &Path) -> Result<(Box<dyn Read>, u64)> {
+    if is_manifest(path) {
+        let parts = VirtualFile::read(path)?;
+        Ok((parts.reader(0), parts.len()))
+    } else {
+        let file = File::open(path)?;
+        let size = file.metadata()?.len();
+        Ok((Box::new(file), size))
+    }
+}
+
+impl VirtualFile {
+    /// Work out which bytes of the files, in this order, make up the stream.
+    pub fn for_files(paths: &[PathBuf]) -> Result<Self> {
+        let mut parts = Vec::new();
+        for (i, path) in paths.iter().enumerate() {
+            if path.to_string_lossy().contains('|') {
+                // FFmpeg's concat protocol separates files by `|` and has no way to escape it
+                return Err(WorkerError::Other {
+                    description: format!("{:?} can't be part of a virtual file", path)
+                }.into());
+            }
+            let mut file = File::open(path)?;
+            let size = file.metadata()?.len();
+            let tag_end = id3v2_len(&mut file, size)?;
+            let audio_start = tag_end + vbr_frame_len(&mut file, tag_end)?;
+            let audio_end = size - id3v1_len(&mut file, size)?;
+            // the tag of the first file stays, so players still show title and cover
+            if i == 0 && tag_end > 0 {
+                parts.push(Part { path: path.clone(), start: 0, end: tag_end });
+            }
+            if audio_end > audio_start {
+                parts.push(Part { path: path.clone(), start: audio_start, end: audio_end });
+            }
+        }
+        Ok(VirtualFile { parts })
+    }
+
+    pub fn read(manifest: &Path) -> Result<Self> {
+        let file = File::open(manifest)?;
+        Ok(serde_json::from_reader(file)?)
+    }
+
+    /// Write the manifest, if it is replaced readers see either the old or the new one.
+    pub fn write(&self, manifest: &Path) -> Result<()> {
+        let mut temporary = manifest.as_os_str().to_owned();
+        temporary.push(".tmp");
+        fs::write(&temporary, serde_json::to_vec(self)?)?;
+        fs::rename(&temporary, manifest)?;
+        Ok(())
+    }
+
+    /// Size of the whole stream.
+    pub fn len(&self) -> u64 {
+        self.parts.iter().map(Part::len).sum()
+    }
+
+    /// Read the stream starting at byte `from`, the files are opened as they are reached.
+    pub fn reader(&self, from: u64) -> Box<dyn Read> {
+        let mut reader: Box<dyn Read> = Box::new(io::empty());
+        let mut skip = from;
+        for part in &self.parts {
+            if skip >= part.len() {
+                skip -= part.len();
+                continue;
+            }
+            reader = Box::new(reader.chain(PartReader {
+                part: part.clone(),
+                offset: skip,
+                file: None,
+            }));
+            skip = 0;
+        }
+        reader
+    }
+
+    /// A URL FFmpeg opens as the stream, using its `concat` and `subfile` protocols.
+    pub fn ffmpeg_url(&self) -> String {
+        let parts: Vec<String> = self.parts.iter()
+            .map(|p| format!("subfile,,start,{},end,{},,:{}", p.start, p.end, p.path.to_string_lossy()))
+            .collect();
+        format!("concat:{}", parts.join("|"))
+    }
+}
+
+/// Reads a part, opening its file on the first read so only one file at a time is open.
+struct PartReader {
+    part: Part,
+    offset: u64,
+    file: Option<io::Take<File>>,
+}
+
+impl Read for PartReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.file.is_none() {
+            let mut file = File::open(&self.part.path)?;
+            file.seek(SeekFrom::Start(self.part.start + self.offset))?;
+            self.file = Some(file.take(self.part.len() - self.offset));
+        }
+        self.file.as_mut().unwrap().read(buf)
+    }
+}
+
+/// Length of the ID3v1 tag at the end of a file, if it has one.
+fn id3v1_len(file: &mut File, size: u64) -> io::Result<u64> {
+    if size < 128 {
+        return Ok(0);
+    }
+    let tag = read_at(file, size - 128, 3)?;
+    Ok(if tag == b"TAG" { 128 } else { 0 })
+}
+
+/// Length of the frame at `offset` if it is a Xing, Info or VBRI frame, these hold no audio.
+fn vbr_frame_len(file: &mut File, offset: u64) -> io::Result<u64> {
+    let frame = read_at(file, offset, 40)?;
+    Ok(match FrameHeader::parse(&frame) {
+        Some(ref header) if header.vbr_tag.is_some() => header.len,
+        _ => 0,
+    })
+}
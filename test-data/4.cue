@@ -0,0 +1,15 @@
+REM GENRE Audiobook
+PERFORMER "Mara Belcheva"
+TITLE "Stihotvorenia"
+FILE "4.mp3" MP3
+  TRACK 01 AUDIO
+    TITLE "Opening"
+    INDEX 01 00:00:00
+  TRACK 02 AUDIO
+    TITLE "Middle Part"
+    PERFORMER "Someone Else"
+    INDEX 00 00:01:50
+    INDEX 01 00:02:00
+  TRACK 03 AUDIO
+    TITLE "Closing"
+    INDEX 01 00:04:37